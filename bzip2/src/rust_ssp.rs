@@ -99,7 +99,7 @@ pub fn rust_ssp(threads: usize, file_action: &str, file_name: &str) {
                 .unwrap();
         }
 
-        let collection = pipeline.collect();
+        let collection = pipeline.collect().unwrap();
 
         // write stage
         for content in collection {
@@ -193,7 +193,7 @@ pub fn rust_ssp(threads: usize, file_action: &str, file_name: &str) {
                 .unwrap();
         }
 
-        let collection = pipeline.collect();
+        let collection = pipeline.collect().unwrap();
 
         // write stage
         for content in collection {
@@ -274,7 +274,7 @@ pub fn rust_ssp_io(threads: usize, file_action: &str, file_name: &str) {
                 .unwrap();
         }
 
-        pipeline.end_and_wait();
+        pipeline.end_and_wait().unwrap();
 
         std::fs::remove_file(file_name).unwrap();
     } else if file_action == "decompress" {
@@ -359,7 +359,7 @@ pub fn rust_ssp_io(threads: usize, file_action: &str, file_name: &str) {
                 .unwrap();
         }
 
-        pipeline.end_and_wait();
+        pipeline.end_and_wait().unwrap();
 
         std::fs::remove_file(file_name).unwrap();
     }
@@ -136,7 +136,7 @@ pub fn rust_spp_eye_tracker(input_video: &String, nthreads: i32) -> opencv::Resu
         pipeline.post(MatData { frame: frame }).unwrap();
     }
 
-    pipeline.end_and_wait();
+    pipeline.end_and_wait().unwrap();
 
     Ok(())
 }
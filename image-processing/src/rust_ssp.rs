@@ -63,7 +63,7 @@ pub fn rust_ssp(dir_name: &str, threads: usize) {
         pipeline.post(image).unwrap();
     }
 
-    let _collection = pipeline.collect();
+    let _collection = pipeline.collect().unwrap();
 
     let system_duration = start.elapsed().expect("Failed to get render time?");
     let in_sec = system_duration.as_secs() as f64 + system_duration.subsec_nanos() as f64 * 1e-9;
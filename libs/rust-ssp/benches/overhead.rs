@@ -0,0 +1,139 @@
+// Per item overhead of the library: pipelines of empty stages, which only
+// pass their items on, against the same chains of threads connected by
+// crossbeam channels. Whatever rust-ssp takes on top of the channels is its
+// queues, the end of the stream and, with an ordered sink, the reordering.
+//
+//     cargo bench --bench overhead
+//
+// Building the pipeline (and spawning the threads of the chain) is left out
+// of the measurements.
+
+#[macro_use]
+extern crate criterion;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::thread::{self, JoinHandle};
+use criterion::{BatchSize, Criterion, ParameterizedBenchmark, Throughput};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use rust_spp::*;
+
+const ITEMS: u64 = 10_000;
+
+#[derive(Clone, Copy)]
+struct Shape {
+    stages: usize,
+    replicas: i32,
+}
+
+//Used by criterion to name the benchmarks
+impl fmt::Debug for Shape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}stages-{}replicas", self.stages, self.replicas)
+    }
+}
+
+fn shapes() -> Vec<Shape> {
+    let mut shapes = vec![];
+    for stages in [1, 2, 4] {
+        for replicas in [1, 2, 4] {
+            shapes.push(Shape { stages, replicas });
+        }
+    }
+    shapes
+}
+
+fn empty_pipeline(shape: Shape, ordered: bool) -> Pipeline<u64, u64> {
+    let mut builder = Pipeline::builder();
+    for _ in 0..shape.stages {
+        builder = builder.stage(parallel!(|item: u64| Some(item), shape.replicas));
+    }
+    if ordered {
+        builder.collect_ordered().build()
+    } else {
+        builder.collect().build()
+    }
+}
+
+fn run_pipeline(pipeline: Pipeline<u64, u64>) -> Vec<u64> {
+    for item in 0..ITEMS {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.collect().unwrap()
+}
+
+//The same stages as threads forwarding from one channel to the next. Items
+//carry their position, so the ordered chain can put them back in order
+struct Chain {
+    input: Sender<(u64, u64)>,
+    output: Receiver<(u64, u64)>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+fn empty_chain(shape: Shape) -> Chain {
+    let (input, mut output) = unbounded::<(u64, u64)>();
+    let mut threads = vec![];
+    for _ in 0..shape.stages {
+        let (sender, receiver) = unbounded();
+        for _ in 0..shape.replicas {
+            let (output, sender) = (output.clone(), sender.clone());
+            threads.push(thread::spawn(move || {
+                for item in output {
+                    sender.send(item).unwrap();
+                }
+            }));
+        }
+        output = receiver;
+    }
+    Chain { input, output, threads }
+}
+
+fn run_chain(chain: Chain, ordered: bool) -> Vec<u64> {
+    let Chain { input, output, threads } = chain;
+    for item in 0..ITEMS {
+        input.send((item, item)).unwrap();
+    }
+    drop(input);
+    let mut collected = Vec::with_capacity(ITEMS as usize);
+    if ordered {
+        let mut pending = BTreeMap::new();
+        for (order, item) in output {
+            pending.insert(order, item);
+            while let Some(item) = pending.remove(&(collected.len() as u64)) {
+                collected.push(item);
+            }
+        }
+    } else {
+        collected.extend(output.into_iter().map(|(_, item)| item));
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    collected
+}
+
+fn sink(c: &mut Criterion, name: &str, ordered: bool) {
+    c.bench(name, ParameterizedBenchmark::new(
+            "rust-ssp",
+            move |b, shape| b.iter_batched(|| empty_pipeline(*shape, ordered), run_pipeline, BatchSize::PerIteration),
+            shapes())
+        .with_function(
+            "crossbeam-channel",
+            move |b, shape| b.iter_batched(|| empty_chain(*shape), |chain| run_chain(chain, ordered), BatchSize::PerIteration))
+        .throughput(|_| Throughput::Elements(ITEMS as u32)));
+}
+
+fn unordered_sink(c: &mut Criterion) {
+    sink(c, "unordered-sink", false);
+}
+
+fn ordered_sink(c: &mut Criterion) {
+    sink(c, "ordered-sink", true);
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = unordered_sink, ordered_sink
+}
+criterion_main!(benches);
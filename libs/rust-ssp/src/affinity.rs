@@ -0,0 +1,196 @@
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use serde::Serialize;
+
+//Public API: Where the replica threads of a pipeline run, see
+//PipelineConfig::with_affinity. Threads are only pinned on Linux; elsewhere
+//every policy leaves them to the OS scheduler. Replicas running as tasks
+//with Executor::Tokio are never pinned.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub enum Affinity {
+    //Left to the OS scheduler
+    #[default]
+    Unpinned,
+    //Replicas fill the cores of one NUMA node before moving to the next,
+    //stage after stage, so neighbouring stages share caches
+    Compact,
+    //Replicas alternate between NUMA nodes, spreading memory bandwidth
+    Scatter,
+    //Replicas cycle through these cores, in stage order
+    Cores(Vec<usize>),
+}
+
+impl Affinity {
+    //Fails when the policy has no cores to pin to on `topology`, e.g. with
+    //Topology::current() on a policy from the command line, which cores
+    //would otherwise panic on while the pipeline is built
+    pub fn check(&self, topology: &Topology) -> Result<(), String> {
+        let order = match self {
+            Affinity::Unpinned => return Ok(()),
+            Affinity::Compact | Affinity::Scatter => topology.nodes.concat(),
+            Affinity::Cores(cores) => {
+                if let Some(core) = cores.iter().find(|core| !topology.contains(**core)) {
+                    return Err(format!("core {} is not available to this process", core));
+                }
+                cores.clone()
+            }
+        };
+        match order.is_empty() {
+            true => Err("no cores to pin the replicas to".to_string()),
+            false => Ok(())
+        }
+    }
+
+    //The cores of `threads` pinned threads, in the order they are started.
+    //Threads beyond the available cores wrap around. Panics when check fails
+    pub fn cores(&self, topology: &Topology, threads: usize) -> Vec<Option<usize>> {
+        if let Err(error) = self.check(topology) {
+            panic!("{}", error);
+        }
+        let order = match self {
+            Affinity::Unpinned => return vec![None; threads],
+            Affinity::Compact => topology.nodes.concat(),
+            Affinity::Scatter => topology.interleaved(),
+            Affinity::Cores(cores) => cores.clone()
+        };
+        (0..threads).map(|thread| Some(order[thread % order.len()])).collect()
+    }
+}
+
+impl fmt::Display for Affinity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Affinity::Unpinned => write!(f, "unpinned"),
+            Affinity::Compact => write!(f, "compact"),
+            Affinity::Scatter => write!(f, "scatter"),
+            Affinity::Cores(cores) => {
+                let cores: Vec<String> = cores.iter().map(|core| core.to_string()).collect();
+                write!(f, "{}", cores.join(","))
+            }
+        }
+    }
+}
+
+//Parses what Display writes: unpinned, compact, scatter or a core list such
+//as 0,2,4-7, so benchmarks can take the policy from the command line. Whether
+//the cores exist is up to Affinity::check
+impl FromStr for Affinity {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Affinity, String> {
+        match policy.trim() {
+            "" | "unpinned" => Ok(Affinity::Unpinned),
+            "compact" => Ok(Affinity::Compact),
+            "scatter" => Ok(Affinity::Scatter),
+            cores => match parse_core_list(cores) {
+                Some(cores) if cores.is_empty() => Err(format!("no cores in affinity policy '{}'", policy)),
+                Some(cores) => Ok(Affinity::Cores(cores)),
+                None => Err(format!("unknown affinity policy '{}'", policy))
+            }
+        }
+    }
+}
+
+//Public API: The cores this process may run on, grouped by NUMA node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topology {
+    pub nodes: Vec<Vec<usize>>,
+}
+
+impl Topology {
+    pub fn new(nodes: Vec<Vec<usize>>) -> Topology {
+        Topology { nodes }
+    }
+
+    //Read from sysfs on Linux, leaving out the cores the process is not
+    //allowed on. A single node with every core everywhere else
+    pub fn current() -> Topology {
+        let allowed = allowed_cores();
+        let nodes: Vec<Vec<usize>> = (0..)
+            .map_while(|node| fs::read_to_string(format!("/sys/devices/system/node/node{}/cpulist", node)).ok())
+            .filter_map(|cores| parse_core_list(cores.trim()))
+            .map(|cores| cores.into_iter().filter(|core| allowed.contains(core)).collect::<Vec<_>>())
+            .filter(|cores| !cores.is_empty())
+            .collect();
+        if nodes.is_empty() {
+            Topology::new(vec![allowed])
+        } else {
+            Topology::new(nodes)
+        }
+    }
+
+    pub fn contains(&self, core: usize) -> bool {
+        self.nodes.iter().any(|cores| cores.contains(&core))
+    }
+
+    //The first core of every node, then the second, and so on
+    fn interleaved(&self) -> Vec<usize> {
+        let longest = self.nodes.iter().map(Vec::len).max().unwrap_or(0);
+        (0..longest)
+            .flat_map(|position| self.nodes.iter().filter_map(move |cores| cores.get(position).copied()))
+            .collect()
+    }
+}
+
+//Public API: Where each replica of a pipeline was placed, for the run
+//metadata. See Pipeline::placement and PipelineMetrics::placement
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct Placement {
+    pub affinity: Affinity,
+    //In stage order
+    pub replicas: Vec<ReplicaPlacement>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReplicaPlacement {
+    pub stage: usize,
+    pub replica: usize,
+    //The core the thread was pinned to. None when unpinned, when the OS
+    //refused the core, or when the replica is a task on the runtime
+    pub core: Option<usize>,
+}
+
+//Internals: Pins the calling thread. Does nothing and returns false when the
+//OS refused, or is not Linux
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(core: usize) -> bool {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) == 0
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_core: usize) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn allowed_cores() -> Vec<usize> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return (0..num_cpus::get()).collect();
+        }
+        (0..libc::CPU_SETSIZE as usize).filter(|core| libc::CPU_ISSET(*core, &set)).collect()
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allowed_cores() -> Vec<usize> {
+    (0..num_cpus::get()).collect()
+}
+
+//The format of sysfs cpulist files: 0-3,8,10-11
+fn parse_core_list(list: &str) -> Option<Vec<usize>> {
+    let mut cores = vec![];
+    for range in list.split(',').map(str::trim).filter(|range| !range.is_empty()) {
+        match range.split_once('-') {
+            Some((first, last)) => cores.extend(first.trim().parse::<usize>().ok()?..=last.trim().parse::<usize>().ok()?),
+            None => cores.push(range.parse().ok()?)
+        }
+    }
+    Some(cores)
+}
@@ -0,0 +1,177 @@
+use crate::blocks::*;
+use crate::metrics::ReplicaProbe;
+use crate::work_storage::*;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Internals: This is the thread-local object of the batch replica
+struct BatchInfo<T, TCollected> {
+    next_step: Arc<Box<dyn PipelineBlock<Vec<T>, TCollected>>>,
+    size: usize,
+    timeout: Duration,
+    items: Vec<T>,
+    orders: Vec<u64>,
+    //When the current batch leaves, even if not full
+    deadline: Option<Instant>,
+    next_order: u64,
+    probe: ReplicaProbe,
+    context: StageContext
+}
+
+impl<T, TCollected> BatchInfo<T, TCollected> {
+    //Takes what the queue gave, None when the deadline passed.
+    //Returns false once the stream ended
+    fn handle(&mut self, dequeued: Option<TimestampedWorkItem<T>>) -> bool {
+        match dequeued {
+            None => self.flush(),
+            Some(TimestampedWorkItem(WorkItem::Value(item), order)) => {
+                if self.items.is_empty() {
+                    self.deadline = Some(Instant::now() + self.timeout);
+                }
+                self.items.push(item);
+                self.orders.push(order);
+                if self.items.len() >= self.size {
+                    self.flush();
+                }
+                self.probe.processed();
+            }
+            Some(TimestampedWorkItem(WorkItem::Dropped, order)) => self.context.item_consumed(order),
+            Some(TimestampedWorkItem(WorkItem::Stop, _)) => {
+                self.flush();
+                self.next_step.process_timestamped(TimestampedWorkItem(WorkItem::Stop, self.next_order));
+                return false;
+            }
+        }
+        true
+    }
+
+    //Batches get new dense orders as they leave, so ordered blocks
+    //downstream do not wait for the orders of the items inside them
+    fn flush(&mut self) {
+        self.deadline = None;
+        if self.items.is_empty() {
+            return;
+        }
+        let batch = std::mem::replace(&mut self.items, Vec::with_capacity(self.size));
+        self.context.item_renumbered(&self.orders, self.next_order);
+        for order in self.orders.drain(..) {
+            self.context.item_consumed(order);
+        }
+        self.next_step.process_timestamped(TimestampedWorkItem(WorkItem::Value(batch), self.next_order));
+        self.next_order += 1;
+    }
+}
+
+/*
+ * Internals: Gathers items into batches of up to `size`. A batch also leaves
+ * once its first item waited for `timeout`, and whatever is left when the
+ * stream ends leaves as a last, smaller batch. A single replica takes the
+ * items in the order they arrive, so batches keep the post order when the
+ * block in front is ordered.
+ */
+pub struct BatchBlock<T, TCollected> {
+    work_queue: Arc<BlockingQueue<T>>,
+    next_step: Arc<Box<dyn PipelineBlock<Vec<T>, TCollected>>>,
+    size: usize,
+    timeout: Duration,
+}
+
+impl<T: Send, TCollected> PipelineBlock<T, TCollected> for BatchBlock<T, TCollected> {
+    fn process(&self, input: WorkItem<T>) -> u64 {
+        self.work_queue.enqueue(input)
+    }
+
+    fn try_process(&self, input: T) -> Result<u64, T> {
+        match self.work_queue.try_enqueue(WorkItem::Value(input)) {
+            Ok(order) => Ok(order),
+            Err(WorkItem::Value(input)) => Err(input),
+            Err(_) => unreachable!("try_enqueue gives back the rejected item")
+        }
+    }
+
+    fn process_timestamped(&self, input: TimestampedWorkItem<T>) {
+        self.work_queue.enqueue_timestamped(input)
+    }
+
+    fn collect(self: Box<Self>) -> Vec<TCollected> {
+        match Arc::try_unwrap(self.next_step) {
+            Ok(result) => result.collect(),
+            Err(_) => {
+                panic!("Could not unwrap Arc in call to collect");
+            }
+        }
+    }
+}
+
+impl<T: Send + 'static, TCollected: 'static> BatchBlock<T, TCollected> {
+    pub fn new(
+        next_step: Box<dyn PipelineBlock<Vec<T>, TCollected>>,
+        size: usize,
+        timeout: Duration,
+        capacity: Option<usize>
+    ) -> BatchBlock<T, TCollected> {
+        assert!(size > 0, "batch size must be greater than zero");
+        //The replica waits for items with a timeout, which only the blocking
+        //queue supports
+        let work_queue = match capacity {
+            None => BlockingQueue::new(),
+            Some(capacity) => BlockingQueue::bounded(capacity)
+        };
+        BatchBlock {
+            work_queue,
+            next_step: Arc::new(next_step),
+            size,
+            timeout,
+        }
+    }
+
+    pub fn monitor_posts(&mut self, context: &StageContext) -> Vec<MonitorLoop> {
+        context.renumbers();
+        context.pipeline.scaler.reserve(1);
+        let queue = self.work_queue.clone();
+        let mut info = BatchInfo {
+            next_step: self.next_step.clone(),
+            size: self.size,
+            timeout: self.timeout,
+            items: Vec::with_capacity(self.size),
+            orders: vec![],
+            deadline: None,
+            next_order: 0,
+            probe: context.probe(),
+            context: context.clone(),
+        };
+
+        let monitor = if context.pipeline.executor.is_async() {
+            MonitorLoop::task(async move {
+                loop {
+                    let dequeued = match info.deadline {
+                        None => Some(queue.dequeue().await),
+                        Some(deadline) => tokio1::time::timeout_at(deadline.into(), queue.dequeue()).await.ok()
+                    };
+                    if dequeued.is_some() {
+                        info.probe.dequeued(|| queue.len());
+                    }
+                    if !info.handle(dequeued) {
+                        break;
+                    }
+                }
+            })
+        } else {
+            MonitorLoop::new(move || {
+                loop {
+                    let dequeued = match info.deadline {
+                        None => Some(queue.wait_and_dequeue()),
+                        Some(deadline) => queue.wait_and_dequeue_until(deadline)
+                    };
+                    if dequeued.is_some() {
+                        info.probe.dequeued(|| queue.len());
+                    }
+                    if !info.handle(dequeued) {
+                        break;
+                    }
+                }
+            })
+        };
+        vec![monitor]
+    }
+}
@@ -1,11 +1,23 @@
-use crate::work_storage::{WorkItem, TimestampedWorkItem};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use tokio1::runtime::Handle;
+use crate::affinity;
+use crate::executor;
+use crate::blocks::StageContext;
+use crate::work_storage::{WorkItem, TimestampedWorkItem, BlockingQueue, StealingQueue, RingQueue, StageQueue};
 
 
 //Base trait for all blocks in the pipeline
 //Used by the internals. Should be able to detal with
-//timestamped items and also perform some automatic timestamping on its own
-pub trait PipelineBlock<TInput, TCollected> {
-    fn process(&self, input: WorkItem<TInput>);
+//timestamped items and also perform some automatic timestamping on its own.
+//Blocks are shared with the replicas of the previous block, hence Send + Sync
+pub trait PipelineBlock<TInput, TCollected>: Send + Sync {
+    //Used by the public API. Returns the order given to the input
+    fn process(&self, input: WorkItem<TInput>) -> u64;
+    //Same, but gives the input back if the block queue is bounded and full
+    fn try_process(&self, input: TInput) -> Result<u64, TInput>;
     fn process_timestamped(&self, input: TimestampedWorkItem<TInput>);
     fn collect(self: Box<Self>) -> Vec<TCollected>;
 }
@@ -18,26 +30,150 @@ pub enum OrderingMode {
 
 pub enum BlockMode {
     Sequential(OrderingMode),
-    Parallel(i32)
+    Parallel(i32),
+    //Parallel replicas whose outputs leave the block in post order
+    ParallelOrdered(i32),
+    //Up to this many replicas, of which the pipeline runs as many as the
+    //load of the block calls for, see PipelineConfig::with_thread_budget.
+    //Sinks run all of them, as with Parallel
+    Adaptive(i32),
+    AdaptiveOrdered(i32)
 }
 
+//Input queue of a block. Bounded queues block the producer (the previous
+//block, or Pipeline::post) while they are full. WorkStealing gives each
+//replica its own deque, which helps fine-grained parallel blocks with many
+//replicas; it is unbounded. Ring is a bounded lock-free ring buffer, which
+//leaves out the locking costs of the other queues when measuring a pipeline,
+//see RingQueue
+#[derive(Debug, Clone, Copy)]
+pub enum QueueMode {
+    Unbounded,
+    Bounded(usize),
+    WorkStealing,
+    Ring(usize)
+}
 
+impl QueueMode {
+    pub fn create_queue<T>(self) -> StageQueue<T> {
+        match self {
+            QueueMode::Unbounded => StageQueue::Shared(BlockingQueue::new()),
+            QueueMode::Bounded(capacity) => StageQueue::Shared(BlockingQueue::bounded(capacity)),
+            QueueMode::WorkStealing => StageQueue::Stealing(StealingQueue::new()),
+            QueueMode::Ring(capacity) => StageQueue::Ring(RingQueue::mpmc(capacity))
+        }
+    }
 
+    //The queue of a block with one replica, fed by a single thread
+    pub fn create_spsc_queue<T>(self) -> StageQueue<T> {
+        match self {
+            QueueMode::Ring(capacity) => StageQueue::Ring(RingQueue::spsc(capacity)),
+            mode => mode.create_queue()
+        }
+    }
+}
+
+
+//A replica of a block: either a loop for its own thread, or a task for the
+//pipeline runtime when it runs on Executor::Tokio
 pub struct MonitorLoop {
-    loop_function: Box<FnOnce() -> () + Send>
+    kind: MonitorKind,
+    //The stage the replica belongs to, None for pipeline threads such as
+    //the scaling controller
+    stage: Option<usize>,
+    //OS thread name, when the replica gets a thread
+    name: String
+}
+
+enum MonitorKind {
+    Thread(Box<dyn FnOnce() + Send>),
+    Task(Pin<Box<dyn Future<Output = ()> + Send>>)
 }
 
 impl MonitorLoop {
 
-    pub fn new<F>(function: F) -> MonitorLoop 
-        where  F: FnOnce() -> (), F: Send + 'static {
+    pub fn new<F>(function: F) -> MonitorLoop
+        where  F: FnOnce(), F: Send + 'static {
+        MonitorLoop {
+            kind: MonitorKind::Thread(Box::new(function)),
+            stage: None,
+            name: "rust-spp".to_string()
+        }
+    }
+
+    pub fn task<F>(future: F) -> MonitorLoop
+        where F: Future<Output = ()>, F: Send + 'static {
         MonitorLoop {
-            loop_function: Box::new(function)
+            kind: MonitorKind::Task(Box::pin(future)),
+            stage: None,
+            name: "rust-spp".to_string()
         }
     }
 
     pub fn run(self) {
-        (self.loop_function)()
+        match self.kind {
+            MonitorKind::Thread(function) => function(),
+            MonitorKind::Task(future) => executor::block_on(&mut None, future)
+        }
+    }
+
+    pub fn stage(&self) -> Option<usize> {
+        self.stage
     }
 
-}
\ No newline at end of file
+    //Set by the builder on the replicas each stage creates
+    pub fn set_stage(&mut self, stage: &StageContext, replica: usize) {
+        self.stage = Some(stage.index);
+        self.name = stage.thread_name(replica);
+    }
+
+    pub fn named(mut self, name: impl Into<String>) -> MonitorLoop {
+        self.name = name.into();
+        self
+    }
+
+    //False for tasks that go to the runtime
+    pub fn needs_thread(&self, runtime: Option<&Handle>) -> bool {
+        !matches!((&self.kind, runtime), (MonitorKind::Task(_), Some(_)))
+    }
+
+    //Tasks go to the runtime when there is one, everything else gets a
+    //thread, pinned to `core` if any. Also gives the core the thread was
+    //pinned to, None when the OS refused it
+    pub fn spawn(self, runtime: Option<&Handle>, core: Option<usize>) -> (MonitorHandle, Option<usize>) {
+        match (self.kind, runtime) {
+            (MonitorKind::Task(future), Some(runtime)) => (MonitorHandle::Task(runtime.spawn(future)), None),
+            (kind, _) => {
+                let name = self.name.clone();
+                let (pinned, placed) = mpsc::channel();
+                let thread = thread::Builder::new().name(self.name).spawn(move || {
+                    let core = core.filter(|core| affinity::pin_current_thread(*core));
+                    let _ = pinned.send(core);
+                    MonitorLoop { kind, stage: None, name }.run()
+                });
+                let thread = thread.expect("could not start a replica thread");
+                let core = placed.recv().expect("replica threads report their core first");
+                (MonitorHandle::Thread(thread), core)
+            }
+        }
+    }
+
+}
+
+pub enum MonitorHandle {
+    Thread(JoinHandle<()>),
+    Task(tokio1::task::JoinHandle<()>)
+}
+
+impl MonitorHandle {
+    //Blocks the calling thread, which must not be a runtime worker
+    pub fn join(self, runtime: Option<&Handle>) {
+        match self {
+            MonitorHandle::Thread(thread) => thread.join().unwrap(),
+            MonitorHandle::Task(task) => runtime
+                .expect("replica tasks need the pipeline runtime")
+                .block_on(task)
+                .unwrap()
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use std::any::Any;
+use std::fmt;
+use std::future::{self, Future};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::Poll;
+use std::thread;
+use std::time::Instant;
+use parking_lot::Mutex;
+use crate::affinity::Affinity;
+use crate::executor::Executor;
+use crate::metrics::{MetricsCollector, Numbering, ReplicaProbe};
+use crate::trace::{EnqueueTracer, ReplicaTracer, TraceCollector};
+use crate::scaling::ReplicaScaler;
+use crate::spp::PipelineConfig;
+
+//Public API: Why an item could not go through the pipeline
+#[derive(Debug, Clone, PartialEq)]
+pub enum PipelineErrorKind {
+    //The stage code panicked, with the panic message
+    Panicked(String),
+    //A fallible stage returned an error, rendered as a string
+    Failed(String),
+}
+
+//Public API: The first failure observed in a pipeline
+#[derive(Debug, Clone, PartialEq)]
+pub struct PipelineError {
+    //Position of the failing stage, starting at 0 for the first one
+    pub stage: usize,
+    //Label of the failing stage, if it was given one
+    pub label: Option<String>,
+    //Order (post timestamp) of the item being processed
+    pub order: u64,
+    pub kind: PipelineErrorKind,
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "stage {}", self.stage)?;
+        if let Some(label) = &self.label {
+            write!(f, " ({})", label)?;
+        }
+        match &self.kind {
+            PipelineErrorKind::Panicked(message) => write!(
+                f, " panicked on item {}: {}", self.order, message),
+            PipelineErrorKind::Failed(message) => write!(
+                f, " failed on item {}: {}", self.order, message),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+//Internals: State shared by all blocks of a pipeline
+pub struct PipelineContext {
+    failed: AtomicBool,
+    cancelled: AtomicBool,
+    error: Mutex<Option<PipelineError>>,
+    pub metrics: Option<MetricsCollector>,
+    pub trace: Option<TraceCollector>,
+    pub executor: Executor,
+    pub scaler: Arc<ReplicaScaler>,
+    pub affinity: Affinity,
+}
+
+impl PipelineContext {
+    pub fn new(config: &PipelineConfig) -> Arc<PipelineContext> {
+        Arc::new(PipelineContext {
+            failed: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+            error: Mutex::new(None),
+            metrics: config.queue_sample_interval().map(MetricsCollector::new),
+            trace: config.is_tracing().then(TraceCollector::new),
+            executor: config.executor(),
+            scaler: Arc::new(ReplicaScaler::new(config.thread_budget())),
+            affinity: config.affinity().clone(),
+        })
+    }
+
+    pub fn has_failed(&self) -> bool {
+        self.failed.load(Ordering::Acquire)
+    }
+
+    //See CancelHandle
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    //After a failure or a cancellation, items are dropped instead of going
+    //through user code, so the queues drain quickly
+    pub fn is_draining(&self) -> bool {
+        self.has_failed() || self.is_cancelled()
+    }
+
+    //Only the first error is kept, later ones are usually a consequence of it
+    pub fn report(&self, error: PipelineError) {
+        let mut current = self.error.lock();
+        if current.is_none() {
+            *current = Some(error);
+            self.failed.store(true, Ordering::Release);
+        }
+    }
+
+    pub fn error(&self) -> Option<PipelineError> {
+        self.error.lock().clone()
+    }
+
+    //Taken right before an item is posted, since an item waiting for room in
+    //the first queue is already in the pipeline for its latency
+    pub fn posting(&self) -> Option<Instant> {
+        self.metrics.as_ref().map(|_| Instant::now())
+    }
+
+    pub fn item_posted(&self, order: u64, posting: Option<Instant>) {
+        if let (Some(metrics), Some(posting)) = (&self.metrics, posting) {
+            metrics.item_posted(order, posting);
+        }
+    }
+
+    //For the thread running Pipeline::run_source
+    pub fn source_probe(&self) -> ReplicaProbe {
+        match &self.metrics {
+            Some(metrics) => metrics.replica_probe(metrics.source()),
+            None => ReplicaProbe::disabled()
+        }
+    }
+}
+
+//Internals: What a block knows about its place in the pipeline
+#[derive(Clone)]
+pub struct StageContext {
+    pub index: usize,
+    pub label: Option<String>,
+    pub pipeline: Arc<PipelineContext>,
+    //The stage before hands every item on from a single thread at a time,
+    //see Stage::forwards_from_one
+    pub fed_by_one: bool,
+}
+
+impl StageContext {
+    pub fn new(index: usize, pipeline: &Arc<PipelineContext>, label: Option<String>) -> StageContext {
+        if let (Some(metrics), Some(label)) = (&pipeline.metrics, &label) {
+            metrics.labelled(index, label);
+        }
+        StageContext {
+            index,
+            label,
+            pipeline: pipeline.clone(),
+            fed_by_one: false,
+        }
+    }
+
+    //The OS thread name of a replica, shown by perf, top -H and panic
+    //messages: the stage label, or stage-<index>, then the replica index
+    pub fn thread_name(&self, replica: usize) -> String {
+        match &self.label {
+            Some(label) => format!("{}-{}", label, replica),
+            None => format!("stage-{}-{}", self.index, replica)
+        }
+    }
+
+    pub fn probe(&self) -> ReplicaProbe {
+        match &self.pipeline.metrics {
+            Some(metrics) => metrics.replica_probe(metrics.stage(self.index)),
+            None => ReplicaProbe::disabled()
+        }
+    }
+
+    //Names the tracks of the replica and of its stage after its thread
+    pub fn tracer(&self, replica: usize) -> ReplicaTracer {
+        match &self.pipeline.trace {
+            Some(trace) => {
+                let stage = self.label.clone().unwrap_or_else(|| format!("stage-{}", self.index));
+                trace.stage(self.index, stage);
+                trace.replica_tracer(self.index, replica, self.thread_name(replica))
+            }
+            None => ReplicaTracer::disabled()
+        }
+    }
+
+    pub fn enqueue_tracer(&self) -> EnqueueTracer {
+        match &self.pipeline.trace {
+            Some(trace) => trace.enqueue_tracer(self.index),
+            None => EnqueueTracer::disabled()
+        }
+    }
+
+    //Called by ordered stages once the stream ended, see
+    //StageMetrics::reorder_high_water
+    pub fn reorder_held(&self, high_water: usize) {
+        if let Some(metrics) = &self.pipeline.metrics {
+            metrics.stage(self.index).reorder_held(high_water);
+        }
+    }
+
+    //Stages that give their outputs new orders (fan-out and batching) call
+    //this while the pipeline is built, then item_renumbered for each output
+    pub fn renumbers(&self) {
+        if let Some(metrics) = &self.pipeline.metrics {
+            metrics.renumbers(Numbering::behind(self.index));
+        }
+    }
+
+    pub fn item_renumbered(&self, from: &[u64], to: u64) {
+        if let Some(metrics) = &self.pipeline.metrics {
+            metrics.item_renumbered(Numbering::behind(self.index), from, to);
+        }
+    }
+
+    //Once such a stage is done with an item it was given, which includes
+    //items it got as Dropped
+    pub fn item_consumed(&self, order: u64) {
+        if let Some(metrics) = &self.pipeline.metrics {
+            metrics.item_consumed(Numbering::behind(self.index), order);
+        }
+    }
+
+    //Runs user code for one item. Panics and errors are reported to the
+    //pipeline and turn the item into None, so the caller drops it and the
+    //stream keeps flowing until Stop. After the first failure, or once the
+    //pipeline is cancelled, items are dropped without running user code.
+    pub fn run<T, F>(&self, order: u64, function: F) -> Option<T>
+        where F: FnOnce() -> Result<T, String> {
+        if self.pipeline.is_draining() {
+            return None;
+        }
+        self.settle(order, panic::catch_unwind(AssertUnwindSafe(function)))
+    }
+
+    //Same as run, for the future of an async node. Panics are caught
+    //whenever the future is polled.
+    pub async fn run_async<T, F>(&self, order: u64, future: F) -> Option<T>
+        where F: Future<Output = Result<T, String>> {
+        if self.pipeline.is_draining() {
+            return None;
+        }
+        let mut future = std::pin::pin!(future);
+        let outcome = future::poll_fn(|cx| {
+            match panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+                Ok(Poll::Pending) => Poll::Pending,
+                Ok(Poll::Ready(result)) => Poll::Ready(Ok(result)),
+                Err(payload) => Poll::Ready(Err(payload)),
+            }
+        }).await;
+        self.settle(order, outcome)
+    }
+
+    fn settle<T>(&self, order: u64, outcome: thread::Result<Result<T, String>>) -> Option<T> {
+        let kind = match outcome {
+            Ok(Ok(result)) => return Some(result),
+            Ok(Err(message)) => PipelineErrorKind::Failed(message),
+            Err(payload) => PipelineErrorKind::Panicked(panic_message(payload)),
+        };
+        self.pipeline.report(PipelineError {
+            stage: self.index,
+            label: self.label.clone(),
+            order,
+            kind,
+        });
+        None
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "non-string panic payload".to_string(),
+        },
+    }
+}
@@ -0,0 +1,247 @@
+use crate::blocks::*;
+use crate::metrics::Numbering;
+use crate::work_storage::*;
+use std::sync::{Arc, OnceLock, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use parking_lot::Mutex;
+
+// Public API: What the last stage of a feedback loop does with an item, see
+// PipelineBuilder::feedback. Returning None drops the item
+pub enum Feedback<TLoop, TOutput> {
+    //Back to the first stage of the loop
+    Again(TLoop),
+    //Out of the loop, on to the stage after it
+    Done(TOutput),
+}
+
+// Internals: Items inside the loop. Every item that entered leaves the body
+// exactly once per round, as a value or as Dropped
+struct LoopCounters {
+    in_flight: usize,
+    //The stream ended in front of the loop
+    ended: bool,
+    //Stop went into the body
+    stopped: bool,
+}
+
+/*
+ * Internals: Shared by the entry and the exit of a feedback loop.
+ *
+ * The body only gets Stop once the stream ended in front of the loop and no
+ * item is left inside it, since any item still in flight may come around
+ * again. Items get new dense orders whenever they go into the body, so
+ * ordered stages inside the loop see every order once. Forwarding happens
+ * under the numbering lock, so the body sees items in the order they got.
+ */
+pub struct LoopState<TLoop, TCollected> {
+    //Held by the entry, which owns the body
+    body: OnceLock<Weak<Box<dyn PipelineBlock<TLoop, TCollected>>>>,
+    next_order: Mutex<u64>,
+    counters: Mutex<LoopCounters>,
+    pipeline: Arc<PipelineContext>,
+    //Numbering of the items in the body, see MetricsCollector::item_requeued
+    numbering: Numbering,
+}
+
+impl<TLoop, TCollected> LoopState<TLoop, TCollected> {
+    //A new item, or one sent around again when `requeued` is its order
+    fn send(&self, body: &dyn PipelineBlock<TLoop, TCollected>, item: TLoop, from: u64, requeued: bool) {
+        let mut next_order = self.next_order.lock();
+        let order = *next_order;
+        *next_order += 1;
+        if let Some(metrics) = &self.pipeline.metrics {
+            if requeued {
+                metrics.item_requeued(self.numbering, from, order);
+            } else {
+                metrics.item_renumbered(self.numbering, &[from], order);
+                metrics.item_consumed(self.numbering, from);
+            }
+        }
+        body.process_timestamped(TimestampedWorkItem(WorkItem::Value(item), order));
+    }
+
+    //Dropped in front of the loop
+    fn dropped(&self, order: u64) {
+        if let Some(metrics) = &self.pipeline.metrics {
+            metrics.item_consumed(self.numbering, order);
+        }
+    }
+
+    fn entered(&self) {
+        self.counters.lock().in_flight += 1;
+    }
+
+    //An item left the loop, or was dropped inside it
+    fn left(&self) {
+        let mut counters = self.counters.lock();
+        counters.in_flight -= 1;
+        self.stop_if_done(&mut counters);
+    }
+
+    fn ended(&self) {
+        let mut counters = self.counters.lock();
+        counters.ended = true;
+        self.stop_if_done(&mut counters);
+    }
+
+    fn stop_if_done(&self, counters: &mut LoopCounters) {
+        if !counters.ended || counters.in_flight > 0 || counters.stopped {
+            return;
+        }
+        counters.stopped = true;
+        if let Some(body) = self.body() {
+            let order = *self.next_order.lock();
+            body.process_timestamped(TimestampedWorkItem(WorkItem::Stop, order));
+        }
+    }
+
+    fn body(&self) -> Option<Arc<Box<dyn PipelineBlock<TLoop, TCollected>>>> {
+        self.body.get().and_then(Weak::upgrade)
+    }
+}
+
+// Internals: In front of the first stage of a feedback loop
+pub struct LoopEntryBlock<TLoop, TCollected> {
+    body: Arc<Box<dyn PipelineBlock<TLoop, TCollected>>>,
+    state: Arc<LoopState<TLoop, TCollected>>,
+    //Orders handed out by process, when the loop is the first stage
+    posted: AtomicU64,
+}
+
+// Internals: Behind the last stage of a feedback loop
+pub struct LoopExitBlock<TLoop, TOutput, TCollected> {
+    next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
+    state: Arc<LoopState<TLoop, TCollected>>,
+    next_order: Mutex<u64>,
+    context: StageContext,
+}
+
+impl<TLoop, TOutput, TCollected> LoopExitBlock<TLoop, TOutput, TCollected> {
+    //The exit of a loop whose body goes from stage `first` to `last`. The
+    //body is created in front of it, then the entry in front of the body
+    pub fn new(
+        next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
+        pipeline: &Arc<PipelineContext>,
+        first: usize,
+        last: usize
+    ) -> LoopExitBlock<TLoop, TOutput, TCollected> {
+        let numbering = Numbering::in_front(first);
+        if let Some(metrics) = &pipeline.metrics {
+            metrics.renumbers(numbering);
+        }
+        let context = StageContext::new(last, pipeline, None);
+        context.renumbers();
+        LoopExitBlock {
+            next_step,
+            state: Arc::new(LoopState {
+                body: OnceLock::new(),
+                next_order: Mutex::new(0),
+                counters: Mutex::new(LoopCounters { in_flight: 0, ended: false, stopped: false }),
+                pipeline: pipeline.clone(),
+                numbering,
+            }),
+            next_order: Mutex::new(0),
+            context,
+        }
+    }
+
+    pub fn state(&self) -> Arc<LoopState<TLoop, TCollected>> {
+        self.state.clone()
+    }
+}
+
+impl<TLoop, TCollected> LoopEntryBlock<TLoop, TCollected> {
+    pub fn new(body: Box<dyn PipelineBlock<TLoop, TCollected>>, state: Arc<LoopState<TLoop, TCollected>>) -> LoopEntryBlock<TLoop, TCollected> {
+        let body = Arc::new(body);
+        let _ = state.body.set(Arc::downgrade(&body));
+        LoopEntryBlock {
+            body,
+            state,
+            posted: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<TLoop, TCollected> PipelineBlock<TLoop, TCollected> for LoopEntryBlock<TLoop, TCollected>
+where
+    TLoop: Send,
+{
+    fn process(&self, input: WorkItem<TLoop>) -> u64 {
+        let order = self.posted.fetch_add(1, Ordering::SeqCst);
+        self.process_timestamped(TimestampedWorkItem(input, order));
+        order
+    }
+
+    //The loop does not bound what goes into it
+    fn try_process(&self, input: TLoop) -> Result<u64, TLoop> {
+        Ok(self.process(WorkItem::Value(input)))
+    }
+
+    fn process_timestamped(&self, input: TimestampedWorkItem<TLoop>) {
+        match input {
+            TimestampedWorkItem(WorkItem::Value(item), order) => {
+                self.state.entered();
+                self.state.send(self.body.as_ref().as_ref(), item, order, false);
+            }
+            TimestampedWorkItem(WorkItem::Dropped, order) => self.state.dropped(order),
+            TimestampedWorkItem(WorkItem::Stop, _) => self.state.ended()
+        }
+    }
+
+    fn collect(self: Box<Self>) -> Vec<TCollected> {
+        match Arc::try_unwrap(self.body) {
+            Ok(body) => body.collect(),
+            Err(_) => {
+                panic!("Could not unwrap Arc in call to collect");
+            }
+        }
+    }
+}
+
+impl<TLoop, TOutput, TCollected> PipelineBlock<Feedback<TLoop, TOutput>, TCollected> for LoopExitBlock<TLoop, TOutput, TCollected>
+where
+    TLoop: Send,
+    TOutput: Send,
+{
+    //Only reached through the last stage of the loop
+    fn process(&self, input: WorkItem<Feedback<TLoop, TOutput>>) -> u64 {
+        self.process_timestamped(TimestampedWorkItem(input, 0));
+        0
+    }
+
+    fn try_process(&self, input: Feedback<TLoop, TOutput>) -> Result<u64, Feedback<TLoop, TOutput>> {
+        Ok(self.process(WorkItem::Value(input)))
+    }
+
+    fn process_timestamped(&self, input: TimestampedWorkItem<Feedback<TLoop, TOutput>>) {
+        match input {
+            TimestampedWorkItem(WorkItem::Value(Feedback::Again(item)), order) => {
+                if let Some(body) = self.state.body() {
+                    self.state.send(body.as_ref().as_ref(), item, order, true);
+                }
+            }
+            TimestampedWorkItem(WorkItem::Value(Feedback::Done(output)), order) => {
+                {
+                    let mut next_order = self.next_order.lock();
+                    self.context.item_renumbered(&[order], *next_order);
+                    self.context.item_consumed(order);
+                    self.next_step.process_timestamped(TimestampedWorkItem(WorkItem::Value(output), *next_order));
+                    *next_order += 1;
+                }
+                self.state.left();
+            }
+            TimestampedWorkItem(WorkItem::Dropped, order) => {
+                self.context.item_consumed(order);
+                self.state.left();
+            }
+            TimestampedWorkItem(WorkItem::Stop, _) => {
+                let order = *self.next_order.lock();
+                self.next_step.process_timestamped(TimestampedWorkItem(WorkItem::Stop, order));
+            }
+        }
+    }
+
+    fn collect(self: Box<Self>) -> Vec<TCollected> {
+        self.next_step.collect()
+    }
+}
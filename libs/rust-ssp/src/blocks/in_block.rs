@@ -2,9 +2,13 @@ use crate::*;
 use crate::blocks::*;
 use work_storage::{WorkItem, TimestampedWorkItem};
 use std::sync::Arc;
-use std::sync::atomic::{Ordering, AtomicUsize};
-use work_storage::{BlockingQueue, BlockingOrderedSet};
+use std::sync::atomic::{Ordering, AtomicBool, AtomicUsize};
+use std::sync::mpsc::{self, Receiver, Sender};
+use work_storage::{StageQueue, BlockingOrderedSet, ReorderBuffer};
 use parking_lot::Mutex;
+use crate::metrics::ReplicaProbe;
+use crate::trace::{EnqueueTracer, ReplicaTracer, TraceEventKind};
+use crate::blocks::inout_block::{SharedReorder, shared_reorder, release_in_order};
 
 //Public API: An output node, receives values and causes side effects
 pub trait In<TInput, TCollected=()> {
@@ -18,62 +22,252 @@ impl <TInput, TCollected, F> In<TInput, TCollected> for F where F: FnMut(TInput)
     }
 }
 
+// Internals: Like transformers, each replica owns its handler on its own thread
+pub type Handler<TInput, TCollected> = Box<dyn In<TInput, TCollected> + Send>;
+pub type HandlerFactory<TInput, TCollected> = Box<dyn FnMut() -> Handler<TInput, TCollected> + Send>;
+
+//Internals: Where the replicas of the last block leave what their handlers
+//return. Kept for collect, unless the outputs are streamed, in which case
+//they are sent out as soon as they are ready, see Pipeline::stream
+pub struct SinkOutput<T> {
+    state: Mutex<SinkState<T>>,
+    streaming: AtomicBool
+}
+
+struct SinkState<T> {
+    collected: Vec<T>,
+    stream: Option<Sender<T>>,
+    //Replicas that did not finish yet. The stream ends with the last one
+    running: usize
+}
+
+impl<T> SinkOutput<T> {
+    pub fn new() -> Arc<SinkOutput<T>> {
+        Arc::new(SinkOutput {
+            state: Mutex::new(SinkState {
+                collected: vec![],
+                stream: None,
+                running: 0
+            }),
+            streaming: AtomicBool::new(false)
+        })
+    }
+
+    fn started(&self, replicas: usize) {
+        self.state.lock().running += replicas;
+    }
+
+    //Only taken once streaming started, so replicas do not lock otherwise
+    fn sender(&self) -> Option<Sender<T>> {
+        if !self.streaming.load(Ordering::Acquire) {
+            return None;
+        }
+        self.state.lock().stream.clone()
+    }
+
+    //Called by each replica when it exits, with what it still holds
+    fn finish(&self, mut collected: Vec<T>) {
+        let mut state = self.state.lock();
+        match &state.stream {
+            Some(stream) => collected.into_iter().for_each(|item| { let _ = stream.send(item); }),
+            None => state.collected.append(&mut collected)
+        }
+        state.running -= 1;
+        if state.running == 0 {
+            state.stream = None;
+        }
+    }
+
+    //Outputs collected so far come first
+    pub fn stream(&self) -> Receiver<T> {
+        let (sender, receiver) = mpsc::channel();
+        let mut state = self.state.lock();
+        assert!(!self.streaming.load(Ordering::Acquire), "the outputs of a pipeline can only be streamed once");
+        state.collected.drain(..).for_each(|item| { let _ = sender.send(item); });
+        if state.running > 0 {
+            state.stream = Some(sender);
+        }
+        self.streaming.store(true, Ordering::Release);
+        receiver
+    }
+
+    //Ordered parallel sinks hand their outputs over one at a time, in order
+    fn push(&self, item: T) {
+        let mut state = self.state.lock();
+        match &state.stream {
+            Some(stream) => { let _ = stream.send(item); }
+            None => state.collected.push(item)
+        }
+    }
+
+    pub fn take(&self) -> Vec<T> {
+        std::mem::take(&mut self.state.lock().collected)
+    }
+}
+
 //Internals: InBlock processing queue for blocks in the pipeline
 pub struct InBlock<TInput, TCollected> {
-    work_queue: Arc<BlockingQueue<TInput>>,
+    work_queue: StageQueue<TInput>,
     ordered_work: Arc<BlockingOrderedSet<TInput>>,
-    collected_items: Arc<Mutex<Vec<TCollected>>>,
-    handler: Box<FnMut() -> Box<dyn In<TInput, TCollected>>>,
+    output: Arc<SinkOutput<TCollected>>,
+    handler: Mutex<HandlerFactory<TInput, TCollected>>,
     ordering: OrderingMode,
-    counter: AtomicUsize
+    replicas: i32,
+    counter: AtomicUsize,
+    //Only for parallel ordered sinks, see InBlockInfo::release
+    reorder: Option<SharedReorder<TCollected>>,
+    enqueues: EnqueueTracer
 }
 
 // Internals: This is a thread-local object for in blocks
 struct InBlockInfo<TInput, TCollected> {
-    handler: Box<dyn In<TInput, TCollected>>
+    handler: Handler<TInput, TCollected>,
+    collected_list: Vec<TCollected>,
+    output: Arc<SinkOutput<TCollected>>,
+    stream: Option<Sender<TCollected>>,
+    probe: ReplicaProbe,
+    tracer: ReplicaTracer,
+    reorder: Option<SharedReorder<TCollected>>,
+    //Sinks run all their replicas until the stream ends
+    replicas: usize,
+    context: StageContext
 }
 
+impl<TInput, TCollected> InBlockInfo<TInput, TCollected> {
+    //Returns false once the stream ended
+    fn handle(&mut self, item: TimestampedWorkItem<TInput>) -> bool {
+        match item {
+            TimestampedWorkItem(WorkItem::Value(val), order) => {
+                self.tracer.record(TraceEventKind::Dequeued, order);
+                self.tracer.record(TraceEventKind::Started, order);
+                let handler = &mut self.handler;
+                let collected = self.context.run(order, || Ok(handler.process(val, order)));
+                self.tracer.record(TraceEventKind::Finished, order);
+                match collected {
+                    Some(_) => self.probe.completed(order),
+                    None => self.probe.dropped(order)
+                }
+                match collected {
+                    Some(collected) if self.reorder.is_none() => self.keep(collected),
+                    Some(collected) => self.release(TimestampedWorkItem(WorkItem::Value(collected), order)),
+                    None => self.release(TimestampedWorkItem(WorkItem::Dropped, order))
+                }
+                self.probe.processed();
+                true
+            },
+            TimestampedWorkItem(WorkItem::Dropped, order) => {
+                self.tracer.record(TraceEventKind::Dequeued, order);
+                self.probe.dropped(order);
+                self.release(TimestampedWorkItem(WorkItem::Dropped, order));
+                true
+            },
+            //Stop stays in the queue for the other replicas
+            TimestampedWorkItem(WorkItem::Stop, _) => false
+        }
+    }
 
-impl <TInput, TCollected> PipelineBlock<TInput, TCollected> for InBlock<TInput, TCollected> {
+    //Each replica collects into its own list and appends it to the
+    //block results when the stream ends, so replicas never contend on it.
+    //Once the outputs are streamed, the list is sent out and so is
+    //everything that follows, in the order the replica produced it
+    fn keep(&mut self, collected: TCollected) {
+        if self.stream.is_none() {
+            self.stream = self.output.sender();
+            if let Some(stream) = &self.stream {
+                self.collected_list.drain(..).for_each(|item| { let _ = stream.send(item); });
+            }
+        }
+        match &self.stream {
+            Some(stream) => { let _ = stream.send(collected); }
+            None => self.collected_list.push(collected)
+        }
+    }
+
+    //Replicas of an ordered parallel sink leave their outputs in a shared
+    //reorder buffer instead, and whichever replica completes the sequence
+    //hands it over to the output. Dropped items and items the handler failed
+    //on go in too, so the sequence has no gaps
+    fn release(&self, item: TimestampedWorkItem<TCollected>) {
+        if let Some(reorder) = &self.reorder {
+            release_in_order(reorder, item, || self.replicas, |released| {
+                if let TimestampedWorkItem(WorkItem::Value(collected), _) = released {
+                    self.output.push(collected);
+                }
+            });
+        }
+    }
+
+    fn finish(&mut self) {
+        self.output.finish(std::mem::take(&mut self.collected_list));
+    }
+}
+
+
+impl <TInput: Send, TCollected: Send> PipelineBlock<TInput, TCollected> for InBlock<TInput, TCollected> {
 
     //used by the public API
-    fn process(&self, input: WorkItem<TInput>) {
+    fn process(&self, input: WorkItem<TInput>) -> u64 {
         match self.ordering {
             //For the unordered case, just enqueue it
             OrderingMode::Unordered => {
-                (*self.work_queue).enqueue(input);
+                let stop = matches!(input, WorkItem::Stop);
+                self.enqueues.enqueue(|| self.work_queue.enqueue(input), |order| (!stop).then_some(*order))
             },
-            //For the ordered case: All InBlocks are single threaded
-            //so we keep a count. Store under an atomic counter
-            //in case we implement a multithreaded outblock
+            //For the ordered case: the monitor expects dense orders starting
+            //at 0, so keep a counter for the items posted to this block
             OrderingMode::Ordered => {
-                let c = self.counter.load(Ordering::SeqCst);
-                (*self.ordered_work).enqueue(TimestampedWorkItem(input, c as u64));
-                self.counter.store(c + 1, Ordering::SeqCst);
+                let stop = matches!(input, WorkItem::Stop);
+                let order = self.counter.fetch_add(1, Ordering::SeqCst) as u64;
+                self.enqueues.enqueue(
+                    || (*self.ordered_work).enqueue(TimestampedWorkItem(input, order)),
+                    |_| (!stop).then_some(order));
+                order
             }
-        };
-        ()
+        }
     }
 
-    //Used internally
-    fn process_timestamped(&self, input: TimestampedWorkItem<TInput>) {
+    fn try_process(&self, input: TInput) -> Result<u64, TInput> {
         match self.ordering {
-            OrderingMode::Unordered => match input {
-                TimestampedWorkItem(work_item, _) => {
-                    (*self.work_queue).enqueue(work_item);
-                }
+            OrderingMode::Unordered => match self.enqueues.enqueue(
+                || self.work_queue.try_enqueue(WorkItem::Value(input)),
+                |enqueued| enqueued.as_ref().ok().copied()) {
+                Ok(order) => Ok(order),
+                Err(WorkItem::Value(input)) => Err(input),
+                Err(_) => unreachable!("try_enqueue gives back the rejected item")
             },
-            OrderingMode::Ordered => (*self.ordered_work).enqueue(input)
+            //The ordered storage is only full when it has a window. An order
+            //that fits stays inside it, so it is only taken once it fits
+            OrderingMode::Ordered => loop {
+                let order = self.counter.load(Ordering::SeqCst);
+                if !self.ordered_work.fits(order as u64) {
+                    return Err(input);
+                }
+                if self.counter.compare_exchange(order, order + 1, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                    self.enqueues.enqueue(
+                        || (*self.ordered_work).enqueue(TimestampedWorkItem(WorkItem::Value(input), order as u64)),
+                        |_| Some(order as u64));
+                    return Ok(order as u64);
+                }
+            }
+        }
+    }
+
+    //Used internally
+    fn process_timestamped(&self, input: TimestampedWorkItem<TInput>) {
+        let order = match input {
+            TimestampedWorkItem(WorkItem::Stop, _) => None,
+            TimestampedWorkItem(_, order) => Some(order)
         };
+        //Items keep the orders they are given, so errors, latencies and
+        //traces of the sink refer to the items the stages before it saw
+        self.enqueues.enqueue(|| match self.ordering {
+            OrderingMode::Unordered => self.work_queue.enqueue_timestamped(input),
+            OrderingMode::Ordered => (*self.ordered_work).enqueue(input)
+        }, |_| order);
     }
 
     fn collect(self: Box<Self>) -> Vec<TCollected> {
-        match Arc::try_unwrap(self.collected_items) {
-            Ok(result) => result.into_inner(),
-            Err(_) => {
-                panic!("Could not unwrap Arc in call to collect");
-            }
-        }
+        self.output.take()
     }
 }
 
@@ -82,96 +276,164 @@ impl <TInput, TCollected> PipelineBlock<TInput, TCollected> for InBlock<TInput,
 impl<TInput: 'static, TCollected: 'static> InBlock<TInput, TCollected>
 where
     TInput: Send,
-    TInput: Sync,
     TCollected: Send,
-    TCollected: Sync,
 {
-    pub fn monitor_posts(&mut self) -> MonitorLoop {
-        match self.ordering {
-            OrderingMode::Ordered => self.monitor_ordered(),
-            OrderingMode::Unordered => self.monitor_unordered()
-        }
-        
+    pub fn monitor_posts(&mut self, context: &StageContext) -> Vec<MonitorLoop> {
+        self.enqueues = context.enqueue_tracer();
+        let monitors: Vec<MonitorLoop> = match self.ordering {
+            OrderingMode::Ordered => vec![self.monitor_ordered(context.clone())],
+            OrderingMode::Unordered => (0..self.replicas as usize).map(|replica| self.monitor_unordered(context.clone(), replica)).collect()
+        };
+        context.pipeline.scaler.reserve(monitors.len());
+        self.output.started(monitors.len());
+        monitors
     }
 
-    fn monitor_unordered(&mut self) -> MonitorLoop {
-        let queue = self.work_queue.clone();
+    fn replica_info(&mut self, context: StageContext, replica: usize) -> InBlockInfo<TInput, TCollected> {
+        InBlockInfo {
+            handler: (self.handler.get_mut())(),
+            collected_list: vec![],
+            output: self.output.clone(),
+            stream: None,
+            probe: context.probe(),
+            tracer: context.tracer(replica),
+            reorder: self.reorder.clone(),
+            replicas: self.replicas as usize,
+            context
+        }
+    }
 
-        let mut info = InBlockInfo {
-            handler: (self.handler)()
-        };
+    fn monitor_unordered(&mut self, context: StageContext, replica: usize) -> MonitorLoop {
+        let mut queue = self.work_queue.consumer();
+        let mut info = self.replica_info(context, replica);
 
-        let arc_collected = self.collected_items.clone();
-
-        MonitorLoop::new(move || {
-            let mut collected_list = arc_collected.lock();
-            loop {
-                let item = queue.wait_and_dequeue();
-                match item {
-                    TimestampedWorkItem(WorkItem::Value(val), order) => {
-                        let collected: TCollected = info.handler.process(val, order);
-                        (*collected_list).push(collected);
-                    },
-                    TimestampedWorkItem(WorkItem::Dropped, order) => {
-                        ()
+        if info.context.pipeline.executor.is_async() {
+            MonitorLoop::task(async move {
+                loop {
+                    let item = queue.dequeue().await;
+                    info.probe.dequeued(|| queue.len());
+                    if !info.handle(item) {
+                        break;
                     }
-                    TimestampedWorkItem(WorkItem::Stop, _) => {
+                }
+                info.finish();
+            })
+        } else {
+            MonitorLoop::new(move || {
+                loop {
+                    let item = queue.wait_and_dequeue();
+                    info.probe.dequeued(|| queue.len());
+                    if !info.handle(item) {
                         break;
                     }
-                };
-            }
-        })
+                }
+                info.finish();
+            })
+        }
     }
 
-    pub fn monitor_ordered(&mut self) -> MonitorLoop {
+    pub fn monitor_ordered(&mut self, context: StageContext) -> MonitorLoop {
         let storage = self.ordered_work.clone();
-        
-        let mut info = InBlockInfo {
-            handler: (self.handler)()
-        };
-        let arc_collected = self.collected_items.clone();
-
-        MonitorLoop::new(move || {
-            let mut next_item = 0;
-            let mut collected_list = arc_collected.lock();
-            loop {
-                let item = storage.wait_and_remove(next_item);
-                match item {
-                    TimestampedWorkItem(WorkItem::Value(val), order) => {
-                        debug_assert!(order == next_item);
-                        next_item += 1;
-                        let collected: TCollected = info.handler.process(val, order);
-                        (*collected_list).push(collected);
-                    }
-                    TimestampedWorkItem(WorkItem::Dropped, order) => {
-                        next_item += 1;
+        let mut info = self.replica_info(context, 0);
+
+        if info.context.pipeline.executor.is_async() {
+            MonitorLoop::task(async move {
+                let mut next_item = 0;
+                loop {
+                    let item = storage.remove(next_item).await;
+                    info.probe.dequeued(|| storage.len());
+                    if !info.handle(item) {
+                        break;
                     }
-                    TimestampedWorkItem(WorkItem::Stop, _) => {
+                    next_item += 1;
+                }
+                info.context.reorder_held(storage.high_water());
+                info.finish();
+            })
+        } else {
+            MonitorLoop::new(move || {
+                let mut next_item = 0;
+                loop {
+                    let item = storage.wait_and_remove(next_item);
+                    info.probe.dequeued(|| storage.len());
+                    if !info.handle(item) {
                         break;
                     }
-                };
-            }
-        })
+                    next_item += 1;
+                }
+                info.context.reorder_held(storage.high_water());
+                info.finish();
+            })
+        }
     }
 
 }
 
 
 impl<TInput, TCollected> InBlock<TInput, TCollected> {
-    pub fn new(behavior: BlockMode, factory: Box<FnMut() -> Box<dyn In<TInput, TCollected>>>) -> InBlock<TInput, TCollected> {
+    pub fn new(
+        behavior: BlockMode,
+        factory: HandlerFactory<TInput, TCollected>,
+        queue: QueueMode,
+        output: Arc<SinkOutput<TCollected>>
+    ) -> InBlock<TInput, TCollected> {
         match behavior {
-            BlockMode::Parallel(_) => unimplemented!("parallel inblocks not implemented"),
-            BlockMode::Sequential(ordering) => InBlock {
-                work_queue: BlockingQueue::new(),
-                handler: factory,
-                ordering: ordering,
-                ordered_work: BlockingOrderedSet::new(),
-                counter: AtomicUsize::new(0),
-                collected_items: Arc::new(Mutex::new(vec![]))
+            //Replicas finish items in any order, ordered ones are put back in
+            //order before they reach the output
+            //Sinks do not scale: adaptive ones keep all of their replicas
+            BlockMode::Parallel(replicas) | BlockMode::Adaptive(replicas) =>
+                InBlock::new_block(factory, OrderingMode::Unordered, replicas, queue, output),
+            BlockMode::ParallelOrdered(1) | BlockMode::AdaptiveOrdered(1) =>
+                InBlock::new_block(factory, OrderingMode::Ordered, 1, queue, output),
+            BlockMode::ParallelOrdered(replicas) | BlockMode::AdaptiveOrdered(replicas) => InBlock {
+                reorder: Some(shared_reorder(ReorderBuffer::new())),
+                ..InBlock::new_block(factory, OrderingMode::Unordered, replicas, queue, output)
             },
+            BlockMode::Sequential(ordering) => InBlock::new_block(factory, ordering, 1, queue, output),
+        }
+    }
+
+    pub fn new_block(
+        factory: HandlerFactory<TInput, TCollected>,
+        ordering: OrderingMode,
+        replicas: i32,
+        queue: QueueMode,
+        output: Arc<SinkOutput<TCollected>>
+    ) -> InBlock<TInput, TCollected> {
+        //Ordered items wait in the ordered storage instead of the queue, so
+        //that is what a bound caps
+        let ordered_work = match (ordering, queue) {
+            (OrderingMode::Ordered, QueueMode::Bounded(capacity)) => BlockingOrderedSet::windowed(capacity),
+            _ => BlockingOrderedSet::new()
+        };
+        InBlock {
+            work_queue: queue.create_queue(),
+            handler: Mutex::new(factory),
+            ordering,
+            replicas,
+            ordered_work,
+            counter: AtomicUsize::new(0),
+            reorder: None,
+            output,
+            enqueues: EnqueueTracer::disabled()
         }
     }
-}
 
-unsafe impl<TInput, TCollected> Send for InBlockInfo<TInput, TCollected> {}
-unsafe impl<TInput, TCollected> Sync for InBlockInfo<TInput, TCollected> {}
+    //Bounds the storage in front of an ordered block, or the reorder buffer
+    //of an ordered parallel one, see InStage::reorder_window
+    pub fn reorder_window(mut self, window: usize) -> InBlock<TInput, TCollected> {
+        match self.reorder {
+            Some(_) => self.reorder = Some(shared_reorder(ReorderBuffer::windowed(window))),
+            None => self.ordered_work = BlockingOrderedSet::windowed(window)
+        }
+        self
+    }
+
+    //See InOutBlock::fed_by_one
+    pub fn fed_by_one(mut self, queue: QueueMode) -> InBlock<TInput, TCollected> {
+        if self.replicas == 1 && matches!(self.work_queue, StageQueue::Ring(_)) {
+            self.work_queue = queue.create_spsc_queue();
+        }
+        self
+    }
+}
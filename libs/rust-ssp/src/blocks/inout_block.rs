@@ -1,9 +1,15 @@
 use crate::blocks::*;
+use crate::executor;
+use crate::scaling::AdaptiveStage;
+use crate::trace::{EnqueueTracer, TraceEventKind};
 use crate::work_storage::*;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use crate::sync::{Condvar, Mutex};
+use crate::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::thread::JoinHandle;
-use std::thread;
+use std::time::Instant;
 
 // Public API: A Input-Output node; transforms some value into another
 pub trait InOut<TInput, TOutput> {
@@ -17,41 +23,328 @@ impl <TInput, TOutput, F> InOut<TInput, TOutput> for F where F: FnMut(TInput) ->
     }
 }
 
+// Public API: A fallible Input-Output node. An error stops the pipeline,
+// and is reported by Pipeline::collect along with the stage and item order
+pub trait TryInOut<TInput, TOutput> {
+    type Error: fmt::Display;
+    fn try_process(&mut self, input: TInput) -> Result<Option<TOutput>, Self::Error>;
+}
+
+
+impl <TInput, TOutput, TError, F> TryInOut<TInput, TOutput> for F
+where
+    F: FnMut(TInput) -> Result<Option<TOutput>, TError>,
+    TError: fmt::Display
+{
+    type Error = TError;
+    fn try_process(&mut self, input: TInput) -> Result<Option<TOutput>, TError> {
+        (*self)(input)
+    }
+}
+
+// Public API: An async Input-Output node, for stages that wait on I/O.
+// The returned future owns what it needs, so it may outlive the call.
+pub trait AsyncInOut<TInput, TOutput> {
+    type Future: Future<Output = Option<TOutput>> + Send + 'static;
+    fn process(&mut self, input: TInput) -> Self::Future;
+}
+
+
+impl <TInput, TOutput, F, TFuture> AsyncInOut<TInput, TOutput> for F
+where
+    F: FnMut(TInput) -> TFuture,
+    TFuture: Future<Output = Option<TOutput>> + Send + 'static
+{
+    type Future = TFuture;
+    fn process(&mut self, input: TInput) -> TFuture {
+        (*self)(input)
+    }
+}
+
+// Public API: A fallible async Input-Output node, see TryInOut
+pub trait TryAsyncInOut<TInput, TOutput> {
+    type Error: fmt::Display;
+    type Future: Future<Output = Result<Option<TOutput>, Self::Error>> + Send + 'static;
+    fn try_process(&mut self, input: TInput) -> Self::Future;
+}
+
+
+impl <TInput, TOutput, TError, F, TFuture> TryAsyncInOut<TInput, TOutput> for F
+where
+    F: FnMut(TInput) -> TFuture,
+    TFuture: Future<Output = Result<Option<TOutput>, TError>> + Send + 'static,
+    TError: fmt::Display
+{
+    type Error = TError;
+    type Future = TFuture;
+    fn try_process(&mut self, input: TInput) -> TFuture {
+        (*self)(input)
+    }
+}
+
+// Internals: What a node gives back for an item, with errors already rendered.
+// Async nodes hand back a future, driven by the executor running the replica
+pub enum Transformed<TOutput> {
+    Ready(Result<Option<TOutput>, String>),
+    Pending(NodeFuture<TOutput>)
+}
+
+pub type NodeFuture<TOutput> = Pin<Box<dyn Future<Output = Result<Option<TOutput>, String>> + Send>>;
+
+// Internals: Every node runs as a Transform.
+pub trait Transform<TInput, TOutput> {
+    fn transform(&mut self, input: TInput) -> Transformed<TOutput>;
+}
+
+// Each replica owns its node on its own thread, so nodes must be Send
+pub type Transformer<TInput, TOutput> = Box<dyn Transform<TInput, TOutput> + Send>;
+pub type TransformerFactory<TInput, TOutput> = Box<dyn FnMut() -> Transformer<TInput, TOutput> + Send>;
+
+// Internals: Adapts an InOut node, used by the stage macros
+pub struct FromInOut<T>(pub T);
+
+impl <TInput, TOutput, T> Transform<TInput, TOutput> for FromInOut<T> where T: InOut<TInput, TOutput> {
+    fn transform(&mut self, input: TInput) -> Transformed<TOutput> {
+        Transformed::Ready(Ok(self.0.process(input)))
+    }
+}
+
+// Internals: Adapts a TryInOut node, used by the stage macros
+pub struct FromTryInOut<T>(pub T);
+
+impl <TInput, TOutput, T> Transform<TInput, TOutput> for FromTryInOut<T> where T: TryInOut<TInput, TOutput> {
+    fn transform(&mut self, input: TInput) -> Transformed<TOutput> {
+        Transformed::Ready(self.0.try_process(input).map_err(|error| error.to_string()))
+    }
+}
+
+// Internals: Adapts an AsyncInOut node, used by the stage macros
+pub struct FromAsyncInOut<T>(pub T);
+
+impl <TInput, TOutput, T> Transform<TInput, TOutput> for FromAsyncInOut<T> where T: AsyncInOut<TInput, TOutput> {
+    fn transform(&mut self, input: TInput) -> Transformed<TOutput> {
+        let future = self.0.process(input);
+        Transformed::Pending(Box::pin(async move { Ok(future.await) }))
+    }
+}
+
+// Internals: Adapts a TryAsyncInOut node, used by the stage macros
+pub struct FromTryAsyncInOut<T>(pub T);
+
+impl <TInput, TOutput, T> Transform<TInput, TOutput> for FromTryAsyncInOut<T> where T: TryAsyncInOut<TInput, TOutput> {
+    fn transform(&mut self, input: TInput) -> Transformed<TOutput> {
+        let future = self.0.try_process(input);
+        Transformed::Pending(Box::pin(async move { future.await.map_err(|error| error.to_string()) }))
+    }
+}
+
+// Internals: Where an item stands after the node was called on it
+enum Step<TOutput> {
+    Done(Option<TOutput>),
+    Await(NodeFuture<TOutput>)
+}
+
+// Internals: Reorder buffer of an ordered block.
+// Replicas holding an item past its window wait for room on the Condvar
+pub(crate) type SharedReorder<T> = Arc<(Mutex<Reordering<T>>, Condvar)>;
+
+pub(crate) struct Reordering<T> {
+    pub(crate) buffer: ReorderBuffer<T>,
+    //Replicas waiting for room. Cleared when they are woken up, so that
+    //those yet to get the lock back do not count
+    waiting: usize,
+    //Set while a replica hands released items on
+    releasing: bool,
+}
+
+pub(crate) fn shared_reorder<T>(buffer: ReorderBuffer<T>) -> SharedReorder<T> {
+    Arc::new((Mutex::new(Reordering { buffer, waiting: 0, releasing: false }), Condvar::new()))
+}
 
 // Internals: This is a thread-local object for inout blocks
 struct InOutBlockInfo<TInput, TOutput, TCollected> {
     next_step: Arc<Box<dyn PipelineBlock<TOutput, TCollected>>>,
-    transformer: Box<dyn InOut<TInput, TOutput>>
+    transformer: Transformer<TInput, TOutput>,
+    reorder: Option<SharedReorder<TOutput>>,
+    alive_threads: Arc<AtomicUsize>,
+    context: StageContext,
+    replica: usize,
+    adaptive: Option<Arc<AdaptiveStage>>
+}
+
+impl<TInput, TOutput, TCollected> InOutBlockInfo<TInput, TOutput, TCollected> {
+    //Replicas an adaptive block no longer needs leave between two items,
+    //see AdaptiveStage::retire
+    //A replica leaving may leave its siblings as the only ones to wait for
+    //room, so they check again, see release_in_order
+    fn retire(&self) -> bool {
+        let retired = self.adaptive.as_ref().is_some_and(|adaptive| adaptive.retire());
+        if let (true, Some(reorder)) = (retired, &self.reorder) {
+            reorder.0.lock().waiting = 0;
+            reorder.1.notify_all();
+        }
+        retired
+    }
+
+    //Service times are only measured for adaptive blocks
+    fn clock(&self) -> Option<Instant> {
+        self.adaptive.as_ref().map(|_| Instant::now())
+    }
+
+    fn served(&self, started: Option<Instant>) {
+        if let (Some(adaptive), Some(started)) = (&self.adaptive, started) {
+            adaptive.served(started);
+        }
+    }
+
+    //Calls the node. Sync nodes are done right away; the future of an async
+    //node is left for the replica to drive
+    fn start(&mut self, input: TInput, order: u64) -> Step<TOutput> {
+        let transformer = &mut self.transformer;
+        match self.context.run(order, || Ok(transformer.transform(input))) {
+            None => Step::Done(None),
+            Some(Transformed::Ready(result)) => Step::Done(self.context.run(order, || result).flatten()),
+            Some(Transformed::Pending(future)) => Step::Await(future)
+        }
+    }
+
+    //Items the node filtered out, or failed on, still go through as Dropped
+    //so that ordered blocks downstream do not wait for them
+    fn emit(&self, output: Option<TOutput>, order: u64) {
+        match output {
+            Some(value) => self.forward(TimestampedWorkItem(WorkItem::Value(value), order)),
+            None => self.forward(TimestampedWorkItem(WorkItem::Dropped, order))
+        }
+    }
+
+    //Stop stays in the queue, so every replica sees it once.
+    //The last replica to leave forwards it: all its siblings
+    //have already forwarded their last item by then.
+    fn stop(&self, order: u64) {
+        if self.alive_threads.fetch_sub(1, Ordering::AcqRel) == 1 {
+            if let Some(reorder) = &self.reorder {
+                self.context.reorder_held(reorder.0.lock().buffer.high_water());
+            }
+            self.forward(TimestampedWorkItem(WorkItem::Stop, order));
+            if let Some(adaptive) = &self.adaptive {
+                adaptive.finished();
+            }
+        }
+    }
+
+    //Sends an item to the next step. In ordered blocks, the item waits in the
+    //reorder buffer and whichever replica completes the sequence forwards it,
+    //see release_in_order
+    fn forward(&self, item: TimestampedWorkItem<TOutput>) {
+        match &self.reorder {
+            None => self.next_step.process_timestamped(item),
+            Some(reorder) => release_in_order(
+                reorder,
+                item,
+                || self.alive_threads.load(Ordering::Acquire),
+                |next| self.next_step.process_timestamped(next))
+        }
+    }
+}
+
+// Internals: Inserts the item of a replica into the reorder buffer of its
+// block and releases whatever it completes.
+// Items past the window of the buffer wait for the ones before them, and
+// so does the replica holding them. The next item to release is always
+// inside the window, but it may still be in the input queue: the last of
+// the `replicas` running never waits, so that someone takes it out.
+// Only one replica at a time hands items on, and it does so without the
+// lock, as the next step may wait for room. The others leave what they
+// complete in the buffer for it. Replicas running as tasks wait through
+// executor::park: otherwise they could take every worker of the runtime
+// from the next step
+pub(crate) fn release_in_order<T>(
+    reorder: &SharedReorder<T>,
+    item: TimestampedWorkItem<T>,
+    replicas: impl Fn() -> usize,
+    mut release: impl FnMut(TimestampedWorkItem<T>)
+) {
+    let (lock, room) = &**reorder;
+    let locked = || match lock.try_lock() {
+        Some(reordering) => reordering,
+        None => executor::park(|| lock.lock())
+    };
+    let mut reordering = locked();
+    while !reordering.buffer.fits(item.1) && reordering.waiting + 1 < replicas() {
+        reordering.waiting += 1;
+        executor::park(|| room.wait(&mut reordering));
+    }
+    reordering.buffer.insert(item);
+    if reordering.releasing {
+        return;
+    }
+    reordering.releasing = true;
+    loop {
+        let released: Vec<_> = std::iter::from_fn(|| reordering.buffer.pop_next()).collect();
+        if released.is_empty() {
+            break;
+        }
+        if reordering.buffer.window().is_some() {
+            reordering.waiting = 0;
+            room.notify_all();
+        }
+        drop(reordering);
+        released.into_iter().for_each(&mut release);
+        reordering = locked();
+    }
+    reordering.releasing = false;
 }
 
 //Internals: Processing queue for inout blocks in the pipeline
 pub struct InOutBlock<TInput, TOutput, TCollected> {
-    work_queue: Arc<BlockingQueue<TInput>>,
+    work_queue: StageQueue<TInput>,
     next_step: Arc<Box<dyn PipelineBlock<TOutput, TCollected>>>,
-    transformer_factory: Box<FnMut() -> Box<dyn InOut<TInput, TOutput>>>,
+    //Shared with the scaler, which creates the replicas of adaptive blocks
+    transformer_factory: Arc<Mutex<TransformerFactory<TInput, TOutput>>>,
     replicas: i32,
-}
-
-impl<TInput, TOutput, TCollected> InOutBlock<TInput, TOutput, TCollected> {
-    pub fn send_stop(&self) {
-        (*self.work_queue).enqueue(WorkItem::Stop);
-    }
+    reorder: Option<SharedReorder<TOutput>>,
+    adaptive: bool,
+    //Set when the replicas are created
+    scaling: Option<Arc<AdaptiveStage>>,
+    enqueues: EnqueueTracer,
 }
 
 impl<TInput: 'static, TCollected: 'static, TOutput: 'static> PipelineBlock<TInput, TCollected> 
 for InOutBlock<TInput, TOutput, TCollected>
 where
     TInput: Send,
-    TInput: Sync,
+    TOutput: Send,
 {
     //used by the public API. Always unordered
-    fn process(&self, input: WorkItem<TInput>) {
-        (*self.work_queue).enqueue(input);
+    fn process(&self, input: WorkItem<TInput>) -> u64 {
+        let stop = matches!(input, WorkItem::Stop);
+        if stop {
+            self.stopping();
+        }
+        self.enqueues.enqueue(|| self.work_queue.enqueue(input), |order| (!stop).then_some(*order))
+    }
+
+    fn try_process(&self, input: TInput) -> Result<u64, TInput> {
+        let enqueued = self.enqueues.enqueue(
+            || self.work_queue.try_enqueue(WorkItem::Value(input)),
+            |enqueued| enqueued.as_ref().ok().copied());
+        match enqueued {
+            Ok(order) => Ok(order),
+            Err(WorkItem::Value(input)) => Err(input),
+            Err(_) => unreachable!("try_enqueue gives back the rejected item")
+        }
     }
 
     //Used internally
     fn process_timestamped(&self, input: TimestampedWorkItem<TInput>) {
-        (*self.work_queue).enqueue_timestamped(input)
+        let order = match input {
+            TimestampedWorkItem(WorkItem::Stop, _) => {
+                self.stopping();
+                None
+            },
+            TimestampedWorkItem(_, order) => Some(order)
+        };
+        self.enqueues.enqueue(|| self.work_queue.enqueue_timestamped(input), |_| order);
     }
 
     fn collect(self: Box<Self>) -> Vec<TCollected> {
@@ -68,105 +361,240 @@ where
 impl<TInput: 'static, TOutput: 'static, TCollected: 'static> InOutBlock<TInput, TOutput, TCollected>
 where
     TInput: Send,
-    TInput: Sync,
+    TOutput: Send,
 {
     pub fn new(
         next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
         transformer: BlockMode,
-        transformer_factory: Box<FnMut() -> Box<dyn InOut<TInput, TOutput>>>
+        transformer_factory: TransformerFactory<TInput, TOutput>,
+        queue: QueueMode
     ) -> InOutBlock<TInput, TOutput, TCollected> {
         match transformer {
             BlockMode::Parallel(replicas) => {
-                InOutBlock::new_block(next_step, transformer_factory, replicas)
+                InOutBlock::new_block(next_step, transformer_factory, replicas, OrderingMode::Unordered, queue)
+            }
+            BlockMode::ParallelOrdered(replicas) => {
+                InOutBlock::new_block(next_step, transformer_factory, replicas, OrderingMode::Ordered, queue)
             }
-            BlockMode::Sequential(_) => InOutBlock::new_block(next_step, transformer_factory, 1),
+            BlockMode::Sequential(ordering) => InOutBlock::new_block(next_step, transformer_factory, 1, ordering, queue),
+            BlockMode::Adaptive(replicas) => InOutBlock {
+                adaptive: true,
+                ..InOutBlock::new_block(next_step, transformer_factory, replicas, OrderingMode::Unordered, queue)
+            },
+            BlockMode::AdaptiveOrdered(replicas) => InOutBlock {
+                adaptive: true,
+                ..InOutBlock::new_block(next_step, transformer_factory, replicas, OrderingMode::Ordered, queue)
+            },
         }
     }
    
     pub fn new_block(
         next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
-        transformer: Box<FnMut() -> Box<dyn InOut<TInput, TOutput>>>,
+        transformer: TransformerFactory<TInput, TOutput>,
         replicas: i32,
+        ordering: OrderingMode,
+        queue: QueueMode
     ) -> InOutBlock<TInput, TOutput, TCollected> {
+        //Even a single replica needs the buffer: unordered stages in front
+        //of it may have mixed the items up
+        let reorder = match ordering {
+            OrderingMode::Ordered => Some(shared_reorder(ReorderBuffer::new())),
+            OrderingMode::Unordered => None
+        };
         InOutBlock {
-            work_queue: BlockingQueue::new(),
+            work_queue: queue.create_queue(),
             next_step: Arc::new(next_step),
-            transformer_factory: transformer,
-            replicas: replicas,
+            transformer_factory: Arc::new(Mutex::new(transformer)),
+            replicas,
+            reorder,
+            adaptive: false,
+            scaling: None,
+            enqueues: EnqueueTracer::disabled(),
         }
     }
 
+    //Gives each replica a queue of its own, see KeyedQueue. Every replica
+    //has keys of its own to serve, so keyed blocks are never adaptive
+    pub fn keyed(mut self, key: KeyFn<TInput>, queue: QueueMode) -> InOutBlock<TInput, TOutput, TCollected> {
+        let capacity = match queue {
+            QueueMode::Bounded(capacity) | QueueMode::Ring(capacity) => Some(capacity),
+            _ => None
+        };
+        self.work_queue = StageQueue::Keyed(KeyedQueue::new(self.replicas as usize, capacity, key));
+        self.adaptive = false;
+        self
+    }
+
+    //Bounds the reorder buffer, see InOutStage::reorder_window. Blocks that
+    //do not reorder are left as they are
+    pub fn reorder_window(mut self, window: usize) -> InOutBlock<TInput, TOutput, TCollected> {
+        if self.reorder.is_some() {
+            self.reorder = Some(shared_reorder(ReorderBuffer::windowed(window)));
+        }
+        self
+    }
+
+    //Called when the stage before forwards from a single thread, see
+    //StageContext::fed_by_one. With one replica, the queue then has a single
+    //producer and a single consumer
+    pub fn fed_by_one(mut self, queue: QueueMode) -> InOutBlock<TInput, TOutput, TCollected> {
+        if self.replicas == 1 && matches!(self.work_queue, StageQueue::Ring(_)) {
+            self.work_queue = queue.create_spsc_queue();
+        }
+        self
+    }
+
+    //Replicas of an adaptive block must not leave once Stop is in its queue,
+    //as each of them has to see it
+    fn stopping(&self) {
+        if let Some(scaling) = &self.scaling {
+            scaling.stop();
+        }
+    }
+
+
+    //Adaptive blocks only give their first replica here: the scaler creates
+    //the others, see ReplicaScaler
+    pub fn monitor_posts(&mut self, context: &StageContext) -> Vec<MonitorLoop> {
+        self.enqueues = context.enqueue_tracer();
+        let scaler = &context.pipeline.scaler;
+        if !self.adaptive {
+            scaler.reserve(self.replicas as usize);
+            let mut replica = self.replica_factory(context, Arc::new(AtomicUsize::new(self.replicas as usize)));
+            return (0..self.replicas as usize).map(|index| replica(None, index)).collect();
+        }
 
-    pub fn monitor_posts(&mut self) -> Vec<MonitorLoop> {
-        let mut monitors: Vec<MonitorLoop> = vec![];
-        let alive_threads = Arc::new(AtomicUsize::new(self.replicas as usize));
+        let running = Arc::new(AtomicUsize::new(1));
+        let mut replica = self.replica_factory(context, running.clone());
+        let stage = context.clone();
+        let queue = self.work_queue.clone();
+        let scaling = scaler.register(
+            self.replicas as usize,
+            running,
+            move || queue.len(),
+            Box::new(move |adaptive, index| {
+                let mut monitor = replica(Some(adaptive.clone()), index);
+                monitor.set_stage(&stage, index);
+                monitor
+            }));
+        self.scaling = Some(scaling.clone());
+        vec![scaling.first_replica()]
+    }
 
-        for _ in 0..self.replicas {
-            let queue = self.work_queue.clone();
-            let alive_threads = alive_threads.clone();
-            
-            let mut info = InOutBlockInfo {
-                next_step: self.next_step.clone(),
-                transformer: (self.transformer_factory)(),
+    //Creates the replicas of the block, which share `alive_threads`
+    fn replica_factory(
+        &self,
+        context: &StageContext,
+        alive_threads: Arc<AtomicUsize>
+    ) -> impl FnMut(Option<Arc<AdaptiveStage>>, usize) -> MonitorLoop + Send + 'static {
+        let next_step = self.next_step.clone();
+        let transformer_factory = self.transformer_factory.clone();
+        let reorder = self.reorder.clone();
+        let work_queue = self.work_queue.clone();
+        let context = context.clone();
+        move |adaptive, replica| {
+            let info = InOutBlockInfo {
+                next_step: next_step.clone(),
+                transformer: (transformer_factory.lock())(),
+                reorder: reorder.clone(),
+                alive_threads: alive_threads.clone(),
+                context: context.clone(),
+                replica,
+                adaptive,
             };
-            
-            let monitor_loop = MonitorLoop::new(move || {
-               
-                loop {
-                    let dequeued = queue.wait_and_dequeue();
-
-                    match dequeued {
-                        TimestampedWorkItem(WorkItem::Value(val), order) => {
-                            let output = info.transformer.process(val);
-
-                            if let Some(val) = output {
-                                info.next_step.process_timestamped(TimestampedWorkItem(
-                                    WorkItem::Value(val),
-                                    order,
-                                ));
-                            } else {
-                                info.next_step.process_timestamped(TimestampedWorkItem(
-                                    WorkItem::Dropped,
-                                    order,
-                                ));
-                            }
-                        },
-                        TimestampedWorkItem(WorkItem::Dropped, order) => {
-                            info.next_step.process_timestamped(TimestampedWorkItem(
-                                WorkItem::Dropped,
-                                order,
-                            ));
-                        },
-                        TimestampedWorkItem(WorkItem::Stop, order) => {
-                            let mut threads = alive_threads.load(Ordering::SeqCst);
-                        
-                            threads -= 1;
-
-                            if threads == 0 {
-                                info.next_step.process_timestamped(TimestampedWorkItem(
-                                    WorkItem::Stop,
-                                    order,
-                                ));
-                            }
-
-                            alive_threads.store(threads, Ordering::SeqCst);
-
-                            //reenqueue the same item
-                            queue.enqueue_timestamped(dequeued);
-
-                            break;
-                        }
+            if context.pipeline.executor.is_async() {
+                InOutBlock::monitor_task(work_queue.consumer(), info)
+            } else {
+                InOutBlock::monitor_thread(work_queue.consumer(), info)
+            }
+        }
+    }
+
+    fn monitor_thread(
+        mut queue: QueueConsumer<TInput>,
+        mut info: InOutBlockInfo<TInput, TOutput, TCollected>
+    ) -> MonitorLoop {
+        MonitorLoop::new(move || {
+            let mut probe = info.context.probe();
+            let mut tracer = info.context.tracer(info.replica);
+            //Only created if the node is async
+            let mut runtime = None;
+
+            loop {
+                if info.retire() {
+                    break;
+                }
+                let dequeued = queue.wait_and_dequeue();
+                probe.dequeued(|| queue.len());
+
+                match dequeued {
+                    TimestampedWorkItem(WorkItem::Value(val), order) => {
+                        tracer.record(TraceEventKind::Dequeued, order);
+                        tracer.record(TraceEventKind::Started, order);
+                        let started = info.clock();
+                        let output = match info.start(val, order) {
+                            Step::Done(output) => output,
+                            Step::Await(future) => executor::block_on(
+                                &mut runtime, info.context.run_async(order, future)).flatten()
+                        };
+                        info.served(started);
+                        probe.processed();
+                        tracer.record(TraceEventKind::Finished, order);
+                        info.emit(output, order);
+                    },
+                    TimestampedWorkItem(WorkItem::Dropped, order) => {
+                        tracer.record(TraceEventKind::Dequeued, order);
+                        info.emit(None, order)
+                    },
+                    TimestampedWorkItem(WorkItem::Stop, order) => {
+                        info.stop(order);
+                        break;
                     }
                 }
-            });
-            monitors.push(monitor_loop);
-        }
+            }
+        })
+    }
 
-        return monitors;
+    fn monitor_task(
+        mut queue: QueueConsumer<TInput>,
+        mut info: InOutBlockInfo<TInput, TOutput, TCollected>
+    ) -> MonitorLoop {
+        MonitorLoop::task(async move {
+            let mut probe = info.context.probe();
+            let mut tracer = info.context.tracer(info.replica);
+
+            loop {
+                if info.retire() {
+                    break;
+                }
+                let dequeued = queue.dequeue().await;
+                probe.dequeued(|| queue.len());
+
+                match dequeued {
+                    TimestampedWorkItem(WorkItem::Value(val), order) => {
+                        tracer.record(TraceEventKind::Dequeued, order);
+                        tracer.record(TraceEventKind::Started, order);
+                        let started = info.clock();
+                        let output = match info.start(val, order) {
+                            Step::Done(output) => output,
+                            Step::Await(future) => info.context.run_async(order, future).await.flatten()
+                        };
+                        info.served(started);
+                        probe.processed();
+                        tracer.record(TraceEventKind::Finished, order);
+                        info.emit(output, order);
+                    },
+                    TimestampedWorkItem(WorkItem::Dropped, order) => {
+                        tracer.record(TraceEventKind::Dequeued, order);
+                        info.emit(None, order)
+                    },
+                    TimestampedWorkItem(WorkItem::Stop, order) => {
+                        info.stop(order);
+                        break;
+                    }
+                }
+            }
+        })
     }
 
 }
-
-/* Assume a MapBlock can be passed to threads, and assume we'll implement parallelism correctly */
-unsafe impl<TInput, TOutput, TCollected> Send for InOutBlockInfo<TInput, TOutput, TCollected> {}
-unsafe impl<TInput, TOutput, TCollected> Sync for InOutBlockInfo<TInput, TOutput, TCollected> {}
@@ -0,0 +1,97 @@
+use crate::blocks::*;
+use crate::work_storage::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Public API: A one-to-many node; turns a value into any number of values,
+// possibly none. Outputs go through the pipeline as separate items
+pub trait InOutMany<TInput, TOutput> {
+    type Output: IntoIterator<Item = TOutput>;
+    fn process(&mut self, input: TInput) -> Self::Output;
+}
+
+
+impl <TInput, TOutput, F, TIter> InOutMany<TInput, TOutput> for F
+where
+    F: FnMut(TInput) -> TIter,
+    TIter: IntoIterator<Item = TOutput>
+{
+    type Output = TIter;
+    fn process(&mut self, input: TInput) -> TIter {
+        (*self)(input)
+    }
+}
+
+// Internals: Adapts an InOutMany node, used by the fan-out stage macros. The
+// outputs of one input leave the replica together, see FlattenBlock
+pub struct FromInOutMany<T>(pub T);
+
+impl <TInput, TOutput, T> Transform<TInput, Vec<TOutput>> for FromInOutMany<T> where T: InOutMany<TInput, TOutput> {
+    fn transform(&mut self, input: TInput) -> Transformed<Vec<TOutput>> {
+        Transformed::Ready(Ok(Some(self.0.process(input).into_iter().collect())))
+    }
+}
+
+/*
+ * Internals: Placed right after the block of a fan-out stage, without
+ * replicas of its own. Orders downstream must stay dense, so every output
+ * gets a new one as it goes through. Behind an ordered block, groups arrive
+ * one at a time in post order, so the outputs are numbered in post order as
+ * well. Behind an unordered block, they are numbered as they come.
+ *
+ * Stop comes last, from the last replica of the block, and gets the order
+ * after the last output.
+ */
+pub struct FlattenBlock<TOutput, TCollected> {
+    next_step: Box<dyn PipelineBlock<TOutput, TCollected>>,
+    next_order: AtomicU64,
+    context: StageContext
+}
+
+impl<TOutput, TCollected> FlattenBlock<TOutput, TCollected> {
+    pub fn new(next_step: Box<dyn PipelineBlock<TOutput, TCollected>>, context: &StageContext) -> FlattenBlock<TOutput, TCollected> {
+        context.renumbers();
+        FlattenBlock {
+            next_step,
+            next_order: AtomicU64::new(0),
+            context: context.clone()
+        }
+    }
+}
+
+impl<TOutput, TCollected> PipelineBlock<Vec<TOutput>, TCollected> for FlattenBlock<TOutput, TCollected>
+where
+    TOutput: Send,
+{
+    //Only reached through the block in front of it
+    fn process(&self, input: WorkItem<Vec<TOutput>>) -> u64 {
+        let order = self.next_order.load(Ordering::SeqCst);
+        self.process_timestamped(TimestampedWorkItem(input, order));
+        order
+    }
+
+    fn try_process(&self, input: Vec<TOutput>) -> Result<u64, Vec<TOutput>> {
+        Ok(self.process(WorkItem::Value(input)))
+    }
+
+    fn process_timestamped(&self, input: TimestampedWorkItem<Vec<TOutput>>) {
+        match input {
+            TimestampedWorkItem(WorkItem::Value(outputs), order) => {
+                for output in outputs {
+                    let renumbered = self.next_order.fetch_add(1, Ordering::SeqCst);
+                    self.context.item_renumbered(&[order], renumbered);
+                    self.next_step.process_timestamped(TimestampedWorkItem(WorkItem::Value(output), renumbered));
+                }
+                self.context.item_consumed(order);
+            }
+            TimestampedWorkItem(WorkItem::Dropped, order) => self.context.item_consumed(order),
+            TimestampedWorkItem(WorkItem::Stop, _) => {
+                let order = self.next_order.load(Ordering::SeqCst);
+                self.next_step.process_timestamped(TimestampedWorkItem(WorkItem::Stop, order));
+            }
+        }
+    }
+
+    fn collect(self: Box<Self>) -> Vec<TCollected> {
+        self.next_step.collect()
+    }
+}
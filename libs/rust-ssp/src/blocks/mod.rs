@@ -1,8 +1,20 @@
-
+pub mod batch_block;
+#[allow(clippy::module_inception)]
 pub mod blocks;
+pub mod context;
+pub mod feedback_block;
 pub mod in_block;
 pub mod inout_block;
+pub mod many_block;
+pub mod split_block;
 
-pub use blocks::{BlockMode, OrderingMode, PipelineBlock, MonitorLoop};
-pub use in_block::{In, InBlock};
-pub use inout_block::{InOut, InOutBlock};
\ No newline at end of file
+pub use blocks::{BlockMode, OrderingMode, QueueMode, PipelineBlock, MonitorLoop, MonitorHandle};
+pub use context::{PipelineContext, StageContext, PipelineError, PipelineErrorKind};
+pub use in_block::{In, InBlock, Handler, HandlerFactory, SinkOutput};
+pub use inout_block::{InOut, TryInOut, AsyncInOut, TryAsyncInOut, InOutBlock};
+pub use inout_block::{Transform, Transformed, Transformer, TransformerFactory, NodeFuture};
+pub use inout_block::{FromInOut, FromTryInOut, FromAsyncInOut, FromTryAsyncInOut};
+pub use many_block::{InOutMany, FromInOutMany, FlattenBlock};
+pub use batch_block::BatchBlock;
+pub use feedback_block::{Feedback, LoopState, LoopEntryBlock, LoopExitBlock};
+pub use split_block::{Route, Branched, Merge, MergeInputBlock, SplitBlock, BranchExitBlock};
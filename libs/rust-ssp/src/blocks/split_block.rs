@@ -0,0 +1,440 @@
+use crate::blocks::*;
+use crate::work_storage::*;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use parking_lot::Mutex;
+
+// Public API: Which branches of a split stage get an item, see split!
+pub struct Route<T> {
+    kind: RouteKind<T>,
+}
+
+enum RouteKind<T> {
+    //Every branch gets a copy
+    Broadcast(fn(&T) -> T),
+    //One branch, picked by the hash of the key, as parallel_keyed! does
+    ByKey(KeyFn<T>),
+}
+
+impl<T> Route<T> {
+    pub fn broadcast() -> Route<T> where T: Clone {
+        Route { kind: RouteKind::Broadcast(T::clone) }
+    }
+
+    pub fn by_key<K, F>(key: F) -> Route<T>
+    where
+        K: Hash,
+        F: Fn(&T) -> K + Send + Sync + 'static {
+        Route { kind: RouteKind::ByKey(key_fn(key)) }
+    }
+
+    //How many branches an item goes to
+    fn outputs(&self, branches: usize) -> usize {
+        match self.kind {
+            RouteKind::Broadcast(_) => branches,
+            RouteKind::ByKey(_) => 1,
+        }
+    }
+}
+
+// Public API: What a branch of a split stage made of an item, as it leaves
+// the split. merge! puts the outputs of an item back together
+pub struct Branched<T> {
+    //Order of the item when it reached the split
+    pub order: u64,
+    pub branch: usize,
+    pub branches: usize,
+    //Outputs the item makes in total, one per branch it went to
+    pub outputs: usize,
+    //None when the branch dropped the item
+    pub item: Option<T>,
+}
+
+// Internals: Numbering of the items that go into one branch. Branches only
+// get some of the items when routing, so each has dense orders of its own
+struct BranchInput<T> {
+    head: Box<dyn PipelineBlock<T, ()>>,
+    next_order: Mutex<u64>,
+    //Order at the split of each item inside the branch
+    origins: Arc<Mutex<HashMap<u64, u64>>>,
+}
+
+impl<T> BranchInput<T> {
+    //Forwarding happens under the lock, so the branch sees items in order
+    fn send(&self, item: WorkItem<T>, origin: u64) {
+        let mut next_order = self.next_order.lock();
+        self.origins.lock().insert(*next_order, origin);
+        self.head.process_timestamped(TimestampedWorkItem(item, *next_order));
+        *next_order += 1;
+    }
+
+    fn stop(&self) {
+        let next_order = self.next_order.lock();
+        self.head.process_timestamped(TimestampedWorkItem(WorkItem::Stop, *next_order));
+    }
+}
+
+/*
+ * Internals: Where the branches of a split come back together, behind all
+ * of them. Every item makes the same number of outputs, so each output gets
+ * the order slot(origin, branch) of the join, which stays dense once all
+ * outputs went through. The stages up to merge! may drop an output, and
+ * MergeInputBlock finds from the order alone which item and branch it was.
+ * Stop goes on once every branch has stopped, after the last output.
+ */
+struct Join<TOutput, TCollected> {
+    next_step: Box<dyn PipelineBlock<Branched<TOutput>, TCollected>>,
+    forwarded: AtomicU64,
+    stopped: Mutex<usize>,
+    branches: usize,
+    outputs: usize,
+    //Outputs still to come of the items the join heard of, for metrics
+    outputs_left: Mutex<HashMap<u64, usize>>,
+    context: StageContext,
+}
+
+impl<TOutput, TCollected> Join<TOutput, TCollected> {
+    fn output(&self, branch: usize, origin: u64, item: Option<TOutput>) {
+        let order = slot(origin, branch, self.outputs);
+        self.context.item_renumbered(&[origin], order);
+        self.output_left(origin);
+        let branched = Branched {
+            order: origin,
+            branch,
+            branches: self.branches,
+            outputs: self.outputs,
+            item,
+        };
+        self.next_step.process_timestamped(TimestampedWorkItem(WorkItem::Value(branched), order));
+        self.forwarded.fetch_add(1, Ordering::SeqCst);
+    }
+
+    //The item is consumed once every branch it went to gave its output
+    fn output_left(&self, origin: u64) {
+        if self.context.pipeline.metrics.is_none() {
+            return;
+        }
+        if self.outputs > 1 {
+            let mut outputs_left = self.outputs_left.lock();
+            let left = outputs_left.entry(origin).or_insert(self.outputs);
+            *left -= 1;
+            if *left > 0 {
+                return;
+            }
+            outputs_left.remove(&origin);
+        }
+        self.context.item_consumed(origin);
+    }
+
+    fn stop(&self) {
+        let mut stopped = self.stopped.lock();
+        *stopped += 1;
+        if *stopped == self.branches {
+            let order = self.forwarded.load(Ordering::SeqCst);
+            self.next_step.process_timestamped(TimestampedWorkItem(WorkItem::Stop, order));
+        }
+    }
+}
+
+//Order behind the join of the output of an item from a branch. Items routed
+//by key make one output only, whichever branch it comes from
+fn slot(origin: u64, branch: usize, outputs: usize) -> u64 {
+    match outputs {
+        1 => origin,
+        outputs => origin * outputs as u64 + branch as u64
+    }
+}
+
+// Internals: Behind the last stage of a branch
+pub struct BranchExitBlock<TOutput, TCollected> {
+    branch: usize,
+    origins: Arc<Mutex<HashMap<u64, u64>>>,
+    join: Arc<Join<TOutput, TCollected>>,
+}
+
+// Internals: In front of the branches of a split stage. The branches end in
+// BranchExitBlocks that share the block behind the split
+pub struct SplitBlock<TInput, TOutput, TCollected> {
+    route: Route<TInput>,
+    branches: Vec<BranchInput<TInput>>,
+    join: Arc<Join<TOutput, TCollected>>,
+    //Orders handed out by process, when the split is the first stage
+    posted: AtomicU64,
+}
+
+impl<TInput, TOutput: Send + 'static, TCollected: Send + 'static> SplitBlock<TInput, TOutput, TCollected> {
+    //`branches` creates each branch in front of its exit
+    pub fn new<F>(
+        route: Route<TInput>,
+        branches: usize,
+        next_step: Box<dyn PipelineBlock<Branched<TOutput>, TCollected>>,
+        context: &StageContext,
+        mut branch: F
+    ) -> SplitBlock<TInput, TOutput, TCollected>
+    where F: FnMut(usize, Box<dyn PipelineBlock<TOutput, ()>>) -> Box<dyn PipelineBlock<TInput, ()>> {
+        context.renumbers();
+        let join = Arc::new(Join {
+            next_step,
+            forwarded: AtomicU64::new(0),
+            stopped: Mutex::new(0),
+            branches,
+            outputs: route.outputs(branches),
+            outputs_left: Mutex::new(HashMap::new()),
+            context: context.clone(),
+        });
+        let branches = (0..branches)
+            .map(|index| {
+                let origins = Arc::new(Mutex::new(HashMap::new()));
+                let exit = BranchExitBlock { branch: index, origins: origins.clone(), join: join.clone() };
+                BranchInput {
+                    head: branch(index, Box::new(exit)),
+                    next_order: Mutex::new(0),
+                    origins,
+                }
+            })
+            .collect();
+        SplitBlock {
+            route,
+            branches,
+            join,
+            posted: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<TInput, TOutput, TCollected> PipelineBlock<TInput, TCollected> for SplitBlock<TInput, TOutput, TCollected>
+where
+    TInput: Send,
+    TOutput: Send,
+{
+    fn process(&self, input: WorkItem<TInput>) -> u64 {
+        let order = self.posted.fetch_add(1, Ordering::SeqCst);
+        self.process_timestamped(TimestampedWorkItem(input, order));
+        order
+    }
+
+    //Bounded queues are up to the stages of the branches
+    fn try_process(&self, input: TInput) -> Result<u64, TInput> {
+        Ok(self.process(WorkItem::Value(input)))
+    }
+
+    //Items dropped in front of the split still go to the branches as
+    //Dropped, so that merge! hears from every branch about every item
+    fn process_timestamped(&self, input: TimestampedWorkItem<TInput>) {
+        match (input, &self.route.kind) {
+            (TimestampedWorkItem(WorkItem::Stop, _), _) => {
+                for branch in &self.branches {
+                    branch.stop();
+                }
+            }
+            (TimestampedWorkItem(WorkItem::Value(item), order), RouteKind::Broadcast(copy)) => {
+                let (last, others) = self.branches.split_last().expect("a split has branches");
+                for branch in others {
+                    branch.send(WorkItem::Value(copy(&item)), order);
+                }
+                last.send(WorkItem::Value(item), order);
+            }
+            (TimestampedWorkItem(WorkItem::Dropped, order), RouteKind::Broadcast(_)) => {
+                for branch in &self.branches {
+                    branch.send(WorkItem::Dropped, order);
+                }
+            }
+            (TimestampedWorkItem(WorkItem::Value(item), order), RouteKind::ByKey(key)) => {
+                let branch = (key(&item) % self.branches.len() as u64) as usize;
+                self.branches[branch].send(WorkItem::Value(item), order);
+            }
+            (TimestampedWorkItem(WorkItem::Dropped, order), RouteKind::ByKey(_)) => {
+                self.branches[0].send(WorkItem::Dropped, order);
+            }
+        }
+    }
+
+    //The exits give their share of the join back as the branches go
+    fn collect(self: Box<Self>) -> Vec<TCollected> {
+        for branch in self.branches {
+            branch.head.collect();
+        }
+        match Arc::try_unwrap(self.join) {
+            Ok(join) => join.next_step.collect(),
+            Err(_) => {
+                panic!("Could not unwrap Arc in call to collect");
+            }
+        }
+    }
+}
+
+impl<TOutput, TCollected> PipelineBlock<TOutput, ()> for BranchExitBlock<TOutput, TCollected>
+where
+    TOutput: Send,
+    TCollected: Send,
+{
+    //Only reached through the last stage of the branch
+    fn process(&self, input: WorkItem<TOutput>) -> u64 {
+        self.process_timestamped(TimestampedWorkItem(input, 0));
+        0
+    }
+
+    fn try_process(&self, input: TOutput) -> Result<u64, TOutput> {
+        Ok(self.process(WorkItem::Value(input)))
+    }
+
+    fn process_timestamped(&self, input: TimestampedWorkItem<TOutput>) {
+        let (item, order) = match input {
+            TimestampedWorkItem(WorkItem::Value(item), order) => (Some(item), order),
+            TimestampedWorkItem(WorkItem::Dropped, order) => (None, order),
+            TimestampedWorkItem(WorkItem::Stop, _) => return self.join.stop()
+        };
+        let origin = self.origins.lock().remove(&order).expect("items leave a branch with the order they got");
+        self.join.output(self.branch, origin, item);
+    }
+
+    fn collect(self: Box<Self>) -> Vec<()> {
+        vec![]
+    }
+}
+
+/*
+ * Internals: In front of the block of merge!. A stage between split! and
+ * merge! that drops an output only hands on Dropped with the order the join
+ * gave it, see slot. Such outputs go on to merge! as empty slots of their
+ * item, so it does not wait for them. How many outputs an item makes is
+ * known from the first output that is not dropped; the orders dropped
+ * before that wait here.
+ */
+pub struct MergeInputBlock<T, TCollected> {
+    next_step: Box<dyn PipelineBlock<Branched<T>, TCollected>>,
+    shape: Mutex<MergeShape>,
+}
+
+struct MergeShape {
+    //Branches and outputs per item, once an output came through
+    known: Option<(usize, usize)>,
+    dropped: Vec<u64>,
+}
+
+impl<T, TCollected> MergeInputBlock<T, TCollected> {
+    pub fn new(next_step: Box<dyn PipelineBlock<Branched<T>, TCollected>>) -> MergeInputBlock<T, TCollected> {
+        MergeInputBlock {
+            next_step,
+            shape: Mutex::new(MergeShape { known: None, dropped: vec![] }),
+        }
+    }
+
+    fn forward_dropped(&self, order: u64, (branches, outputs): (usize, usize)) {
+        let (origin, branch) = match outputs {
+            1 => (order, 0),
+            outputs => (order / outputs as u64, (order % outputs as u64) as usize)
+        };
+        let branched = Branched { order: origin, branch, branches, outputs, item: None };
+        self.next_step.process_timestamped(TimestampedWorkItem(WorkItem::Value(branched), order));
+    }
+}
+
+impl<T, TCollected> PipelineBlock<Branched<T>, TCollected> for MergeInputBlock<T, TCollected>
+where
+    T: Send,
+{
+    //Only reached through the stages of the split in front of it
+    fn process(&self, input: WorkItem<Branched<T>>) -> u64 {
+        self.process_timestamped(TimestampedWorkItem(input, 0));
+        0
+    }
+
+    fn try_process(&self, input: Branched<T>) -> Result<u64, Branched<T>> {
+        Ok(self.process(WorkItem::Value(input)))
+    }
+
+    fn process_timestamped(&self, input: TimestampedWorkItem<Branched<T>>) {
+        match input {
+            TimestampedWorkItem(WorkItem::Value(branched), order) => {
+                let waiting = {
+                    let mut shape = self.shape.lock();
+                    shape.known.get_or_insert((branched.branches, branched.outputs));
+                    std::mem::take(&mut shape.dropped)
+                };
+                for dropped in waiting {
+                    self.forward_dropped(dropped, (branched.branches, branched.outputs));
+                }
+                self.next_step.process_timestamped(TimestampedWorkItem(WorkItem::Value(branched), order));
+            }
+            TimestampedWorkItem(WorkItem::Dropped, order) => {
+                let known = {
+                    let mut shape = self.shape.lock();
+                    if shape.known.is_none() {
+                        shape.dropped.push(order);
+                    }
+                    shape.known
+                };
+                if let Some(known) = known {
+                    self.forward_dropped(order, known);
+                }
+            }
+            //Nothing came through, so merge! has nothing to wait for
+            TimestampedWorkItem(WorkItem::Stop, order) => {
+                let waiting = std::mem::take(&mut self.shape.lock().dropped);
+                for dropped in waiting {
+                    self.next_step.process_timestamped(TimestampedWorkItem(WorkItem::Dropped, dropped));
+                }
+                self.next_step.process_timestamped(TimestampedWorkItem(WorkItem::Stop, order));
+            }
+        }
+    }
+
+    fn collect(self: Box<Self>) -> Vec<TCollected> {
+        self.next_step.collect()
+    }
+}
+
+/*
+ * Public API: The node made by merge!. Waits for every output of an item
+ * and gives them back together, one slot per branch in branch order. Items
+ * leave in the order they reached the split, so merge! runs a single replica
+ * and holds the items that are ahead. Items no branch gave anything back
+ * for are dropped.
+ */
+pub struct Merge<T> {
+    pending: BTreeMap<u64, (Vec<Option<T>>, usize)>,
+    next: u64,
+}
+
+impl<T> Merge<T> {
+    pub fn new() -> Merge<T> {
+        Merge { pending: BTreeMap::new(), next: 0 }
+    }
+}
+
+impl<T> Default for Merge<T> {
+    fn default() -> Self {
+        Merge::new()
+    }
+}
+
+impl<T> InOutMany<Branched<T>, Vec<Option<T>>> for Merge<T> {
+    type Output = Vec<Vec<Option<T>>>;
+
+    fn process(&mut self, input: Branched<T>) -> Vec<Vec<Option<T>>> {
+        let (slots, received) = self.pending.entry(input.order)
+            .or_insert_with(|| ((0..input.branches).map(|_| None).collect(), 0));
+        slots[input.branch] = input.item;
+        *received += 1;
+        if *received == input.outputs && input.order == self.next {
+            let mut merged = vec![];
+            while let Some(entry) = self.pending.first_entry() {
+                let (_, received) = entry.get();
+                if *entry.key() != self.next || *received < input.outputs {
+                    break;
+                }
+                let (slots, _) = entry.remove();
+                self.next += 1;
+                if slots.iter().any(Option::is_some) {
+                    merged.push(slots);
+                }
+            }
+            merged
+        } else {
+            vec![]
+        }
+    }
+}
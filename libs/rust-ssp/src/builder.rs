@@ -0,0 +1,783 @@
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::blocks::*;
+use crate::work_storage::{KeyFn, key_fn};
+use crate::spp::{Pipeline, PipelineConfig};
+
+//Public API: An InOut stage ready to be added to a pipeline. Made by the
+//parallel!, parallel_ordered!, try_parallel! and try_parallel_ordered! macros
+pub struct InOutStage<TInput, TOutput> {
+    pub mode: BlockMode,
+    pub factory: TransformerFactory<TInput, TOutput>,
+    pub queue: QueueMode,
+    pub label: Option<String>,
+    pub key: Option<KeyFn<TInput>>,
+    pub reorder_window: Option<usize>,
+}
+
+impl<TInput, TOutput> InOutStage<TInput, TOutput> {
+    pub fn new(mode: BlockMode, factory: TransformerFactory<TInput, TOutput>) -> InOutStage<TInput, TOutput> {
+        InOutStage { mode, factory, queue: QueueMode::Unbounded, label: None, key: None, reorder_window: None }
+    }
+
+    //Names the stage in thread names, errors and metrics, instead of its
+    //position. See StageContext::thread_name
+    pub fn label(mut self, label: impl Into<String>) -> InOutStage<TInput, TOutput> {
+        self.label = Some(label.into());
+        self
+    }
+
+    //Bounds the input queue of the stage, see QueueMode::Bounded
+    pub fn bounded(mut self, capacity: usize) -> InOutStage<TInput, TOutput> {
+        self.queue = QueueMode::Bounded(capacity);
+        self
+    }
+
+    //Gives each replica its own deque, see QueueMode::WorkStealing
+    pub fn work_stealing(mut self) -> InOutStage<TInput, TOutput> {
+        self.queue = QueueMode::WorkStealing;
+        self
+    }
+
+    //Bounds the input queue with a lock-free ring buffer, see QueueMode::Ring
+    pub fn ring(mut self, capacity: usize) -> InOutStage<TInput, TOutput> {
+        self.queue = QueueMode::Ring(capacity);
+        self
+    }
+
+    //Turns the replica count of a parallel stage into a maximum, see
+    //BlockMode::Adaptive. Sequential stages keep their single replica
+    pub fn adaptive(mut self) -> InOutStage<TInput, TOutput> {
+        self.mode = match self.mode {
+            BlockMode::Parallel(replicas) => BlockMode::Adaptive(replicas),
+            BlockMode::ParallelOrdered(replicas) => BlockMode::AdaptiveOrdered(replicas),
+            mode => mode
+        };
+        self
+    }
+
+    //Sends items with the same key to the same replica, so the node of each
+    //replica can keep state for its keys. Replicas get a queue each, bounded
+    //if the stage is, and all of them stay busy even if the stage was made
+    //adaptive. See KeyedQueue
+    pub fn keyed<K, F>(mut self, key: F) -> InOutStage<TInput, TOutput>
+    where
+        K: Hash,
+        F: Fn(&TInput) -> K + Send + Sync + 'static {
+        self.key = Some(key_fn(key));
+        self
+    }
+
+    //Caps the items an ordered stage with several replicas holds back while
+    //an earlier one is late. Replicas that finish an item further ahead wait
+    //for the late one, and so their input queue fills up. The last replica
+    //not waiting goes past the window instead, as the late item may still
+    //be in the queue when unordered stages in front mixed the items up
+    pub fn reorder_window(mut self, window: usize) -> InOutStage<TInput, TOutput> {
+        self.reorder_window = Some(window);
+        self
+    }
+}
+
+//Public API: A one-to-many stage. Made by the parallel_many! and
+//parallel_many_ordered! macros; each output goes on as an item of its own
+pub struct InOutManyStage<TInput, TOutput> {
+    pub stage: InOutStage<TInput, Vec<TOutput>>,
+}
+
+impl<TInput, TOutput> InOutManyStage<TInput, TOutput> {
+    pub fn new(mode: BlockMode, factory: TransformerFactory<TInput, Vec<TOutput>>) -> InOutManyStage<TInput, TOutput> {
+        InOutManyStage { stage: InOutStage::new(mode, factory) }
+    }
+
+    pub fn bounded(self, capacity: usize) -> InOutManyStage<TInput, TOutput> {
+        InOutManyStage { stage: self.stage.bounded(capacity) }
+    }
+
+    pub fn work_stealing(self) -> InOutManyStage<TInput, TOutput> {
+        InOutManyStage { stage: self.stage.work_stealing() }
+    }
+
+    pub fn ring(self, capacity: usize) -> InOutManyStage<TInput, TOutput> {
+        InOutManyStage { stage: self.stage.ring(capacity) }
+    }
+
+    pub fn adaptive(self) -> InOutManyStage<TInput, TOutput> {
+        InOutManyStage { stage: self.stage.adaptive() }
+    }
+
+    pub fn keyed<K, F>(self, key: F) -> InOutManyStage<TInput, TOutput>
+    where
+        K: Hash,
+        F: Fn(&TInput) -> K + Send + Sync + 'static {
+        InOutManyStage { stage: self.stage.keyed(key) }
+    }
+
+    pub fn reorder_window(self, window: usize) -> InOutManyStage<TInput, TOutput> {
+        InOutManyStage { stage: self.stage.reorder_window(window) }
+    }
+
+    pub fn label(self, label: impl Into<String>) -> InOutManyStage<TInput, TOutput> {
+        InOutManyStage { stage: self.stage.label(label) }
+    }
+}
+
+//Public API: The stage made by merge!, a single replica of Merge behind a
+//MergeInputBlock, so that outputs dropped on the way still reach it
+pub struct MergeStage<T> {
+    pub stage: InOutManyStage<Branched<T>, Vec<Option<T>>>,
+}
+
+impl<T: Send + 'static> MergeStage<T> {
+    pub fn new() -> MergeStage<T> {
+        let factory: TransformerFactory<Branched<T>, Vec<Vec<Option<T>>>> = Box::new(move || Box::new(FromInOutMany(Merge::new())));
+        MergeStage { stage: InOutManyStage::new(BlockMode::Sequential(OrderingMode::Unordered), factory) }
+    }
+
+    pub fn label(self, label: impl Into<String>) -> MergeStage<T> {
+        MergeStage { stage: self.stage.label(label) }
+    }
+}
+
+impl<T: Send + 'static> Default for MergeStage<T> {
+    fn default() -> Self {
+        MergeStage::new()
+    }
+}
+
+//Public API: Gathers items into Vecs of up to `size` items, made by the
+//batch! macro. See BatchBlock for when a batch leaves
+pub struct BatchStage {
+    pub size: usize,
+    pub timeout: Duration,
+    //Bounds the input queue when set. The single replica waits for items
+    //with a timeout, which the other queue modes do not support
+    capacity: Option<usize>,
+    pub label: Option<String>,
+}
+
+impl BatchStage {
+    pub fn new(size: usize, timeout: Duration) -> BatchStage {
+        BatchStage { size, timeout, capacity: None, label: None }
+    }
+
+    pub fn bounded(mut self, capacity: usize) -> BatchStage {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    pub fn label(mut self, label: impl Into<String>) -> BatchStage {
+        self.label = Some(label.into());
+        self
+    }
+}
+
+//Public API: A sub-pipeline of a split stage, made by the split! macro from
+//each list of stages. Only InOut stages, so that each item leaves the
+//branch once, as a value or dropped
+pub struct Branch<TInput, TCurrent> {
+    stages: usize,
+    link: BranchLink<TInput, TCurrent>,
+}
+
+// Internals: Same as Link, for blocks that collect nothing. Branch stages
+// only learn their positions once the split is added to a pipeline
+type BranchLink<TInput, TCurrent> = Box<dyn FnOnce(
+    Box<dyn PipelineBlock<TCurrent, ()>>,
+    usize,
+    &Arc<PipelineContext>,
+    &mut Vec<MonitorLoop>
+) -> Box<dyn PipelineBlock<TInput, ()>>>;
+
+impl<TInput: 'static> Branch<TInput, TInput> {
+    pub fn new() -> Branch<TInput, TInput> {
+        Branch {
+            stages: 0,
+            link: Box::new(|first, _, _, _| first),
+        }
+    }
+}
+
+impl<TInput: 'static> Default for Branch<TInput, TInput> {
+    fn default() -> Self {
+        Branch::new()
+    }
+}
+
+impl<TInput: 'static, TCurrent: Send + 'static> Branch<TInput, TCurrent> {
+    pub fn stage<TNext: Send + 'static>(self, stage: InOutStage<TCurrent, TNext>) -> Branch<TInput, TNext> {
+        let position = self.stages;
+        let link = self.link;
+        Branch {
+            stages: position + 1,
+            link: Box::new(move |next, first, context, monitors| {
+                let block = link_stage(Box::new(|block, _, _| block), stage, first + position, false)(next, context, monitors);
+                link(block, first, context, monitors)
+            }),
+        }
+    }
+}
+
+//Public API: Hands items to several branches, made by the split! macro.
+//The items leaving it say where they come from, and merge! puts the outputs
+//of each item back together. The stages of the branches are numbered one
+//branch after the other, starting at the position of the split
+pub struct SplitStage<TInput, TOutput> {
+    route: Route<TInput>,
+    branches: Vec<Branch<TInput, TOutput>>,
+}
+
+impl<TInput, TOutput> SplitStage<TInput, TOutput> {
+    pub fn new(route: Route<TInput>, branches: Vec<Branch<TInput, TOutput>>) -> SplitStage<TInput, TOutput> {
+        assert!(!branches.is_empty(), "a split needs at least one branch");
+        SplitStage { route, branches }
+    }
+}
+
+//Public API: Anything PipelineBuilder::stage can add in the middle of a
+//pipeline: InOutStage, InOutManyStage, BatchStage, SplitStage and MergeStage
+pub trait Stage<TInput, TOutput> {
+    //None to go by the position of the stage
+    fn label(&self) -> Option<String> {
+        None
+    }
+
+    //Positions the stage takes, more than one for the branches of a split
+    fn stages(&self) -> usize {
+        1
+    }
+
+    //Whether a single thread at a time hands on everything the stage makes,
+    //which lets the next stage use a single-producer queue
+    fn forwards_from_one(&self) -> bool {
+        false
+    }
+
+    //Internals: Creates the block of the stage in front of the next one and
+    //adds its replicas to `monitors`
+    fn create<TCollected: Send + 'static>(
+        self,
+        next: Box<dyn PipelineBlock<TOutput, TCollected>>,
+        context: &StageContext,
+        monitors: &mut Vec<MonitorLoop>
+    ) -> Box<dyn PipelineBlock<TInput, TCollected>>;
+}
+
+impl<TInput: Send + 'static, TOutput: Send + 'static> Stage<TInput, TOutput> for InOutStage<TInput, TOutput> {
+    fn label(&self) -> Option<String> {
+        self.label.clone()
+    }
+
+    //Sequential stages, and parallel ones with a single replica
+    fn forwards_from_one(&self) -> bool {
+        matches!(
+            self.mode,
+            BlockMode::Sequential(_) | BlockMode::Parallel(1) | BlockMode::ParallelOrdered(1) |
+            BlockMode::Adaptive(1) | BlockMode::AdaptiveOrdered(1)
+        )
+    }
+
+    fn create<TCollected: Send + 'static>(
+        self,
+        next: Box<dyn PipelineBlock<TOutput, TCollected>>,
+        context: &StageContext,
+        monitors: &mut Vec<MonitorLoop>
+    ) -> Box<dyn PipelineBlock<TInput, TCollected>> {
+        let mut block = InOutBlock::new(next, self.mode, self.factory, self.queue);
+        if let Some(key) = self.key {
+            block = block.keyed(key, self.queue);
+        }
+        if let Some(window) = self.reorder_window {
+            block = block.reorder_window(window);
+        }
+        if context.fed_by_one {
+            block = block.fed_by_one(self.queue);
+        }
+        monitors.extend(block.monitor_posts(context));
+        Box::new(block)
+    }
+}
+
+impl<TInput: Send + 'static, TOutput: Send + 'static> Stage<TInput, TOutput> for InOutManyStage<TInput, TOutput> {
+    fn label(&self) -> Option<String> {
+        self.stage.label.clone()
+    }
+
+    //The outputs of an item are handed on by the replica that made them
+    fn forwards_from_one(&self) -> bool {
+        self.stage.forwards_from_one()
+    }
+
+    fn create<TCollected: Send + 'static>(
+        self,
+        next: Box<dyn PipelineBlock<TOutput, TCollected>>,
+        context: &StageContext,
+        monitors: &mut Vec<MonitorLoop>
+    ) -> Box<dyn PipelineBlock<TInput, TCollected>> {
+        let flatten = FlattenBlock::new(next, context);
+        self.stage.create(Box::new(flatten), context, monitors)
+    }
+}
+
+impl<T: Send + 'static> Stage<T, Vec<T>> for BatchStage {
+    fn label(&self) -> Option<String> {
+        self.label.clone()
+    }
+
+    fn create<TCollected: Send + 'static>(
+        self,
+        next: Box<dyn PipelineBlock<Vec<T>, TCollected>>,
+        context: &StageContext,
+        monitors: &mut Vec<MonitorLoop>
+    ) -> Box<dyn PipelineBlock<T, TCollected>> {
+        let mut block = BatchBlock::new(next, self.size, self.timeout, self.capacity);
+        monitors.extend(block.monitor_posts(context));
+        Box::new(block)
+    }
+}
+
+impl<TInput: Send + 'static, TOutput: Send + 'static> Stage<TInput, Branched<TOutput>> for SplitStage<TInput, TOutput> {
+    fn stages(&self) -> usize {
+        self.branches.iter().map(|branch| branch.stages).sum::<usize>().max(1)
+    }
+
+    fn create<TCollected: Send + 'static>(
+        self,
+        next: Box<dyn PipelineBlock<Branched<TOutput>, TCollected>>,
+        context: &StageContext,
+        monitors: &mut Vec<MonitorLoop>
+    ) -> Box<dyn PipelineBlock<TInput, TCollected>> {
+        let stages = self.stages();
+        let firsts: Vec<usize> = self.branches.iter()
+            .scan(context.index, |first, branch| {
+                let position = *first;
+                *first += branch.stages;
+                Some(position)
+            })
+            .collect();
+        let mut branches = self.branches.into_iter().map(|branch| branch.link);
+        let last = StageContext::new(context.index + stages - 1, &context.pipeline, None);
+        Box::new(SplitBlock::new(self.route, firsts.len(), next, &last, |index, exit| {
+            let link = branches.next().expect("one link per branch");
+            link(exit, firsts[index], &context.pipeline, monitors)
+        }))
+    }
+}
+
+impl<T: Send + 'static> Stage<Branched<T>, Vec<Option<T>>> for MergeStage<T> {
+    fn label(&self) -> Option<String> {
+        self.stage.stage.label.clone()
+    }
+
+    fn forwards_from_one(&self) -> bool {
+        self.stage.forwards_from_one()
+    }
+
+    fn create<TCollected: Send + 'static>(
+        self,
+        next: Box<dyn PipelineBlock<Vec<Option<T>>, TCollected>>,
+        context: &StageContext,
+        monitors: &mut Vec<MonitorLoop>
+    ) -> Box<dyn PipelineBlock<Branched<T>, TCollected>> {
+        Box::new(MergeInputBlock::new(self.stage.create(next, context, monitors)))
+    }
+}
+
+//Public API: The last stage of a pipeline. Made by the sequential!,
+//sequential_ordered!, parallel_sink!, parallel_sink_ordered!, collect! and
+//collect_ordered! macros
+pub struct InStage<TInput, TCollected> {
+    pub mode: BlockMode,
+    pub factory: HandlerFactory<TInput, TCollected>,
+    pub queue: QueueMode,
+    pub label: Option<String>,
+    pub reorder_window: Option<usize>,
+}
+
+impl<TInput, TCollected> InStage<TInput, TCollected> {
+    pub fn new(mode: BlockMode, factory: HandlerFactory<TInput, TCollected>) -> InStage<TInput, TCollected> {
+        InStage { mode, factory, queue: QueueMode::Unbounded, label: None, reorder_window: None }
+    }
+
+    pub fn label(mut self, label: impl Into<String>) -> InStage<TInput, TCollected> {
+        self.label = Some(label.into());
+        self
+    }
+
+    //An ordered sink with a single replica takes up to `capacity` items
+    //past the one it works on, as with a reorder window of that size
+    pub fn bounded(mut self, capacity: usize) -> InStage<TInput, TCollected> {
+        self.queue = QueueMode::Bounded(capacity);
+        self
+    }
+
+    pub fn work_stealing(mut self) -> InStage<TInput, TCollected> {
+        self.queue = QueueMode::WorkStealing;
+        self
+    }
+
+    pub fn ring(mut self, capacity: usize) -> InStage<TInput, TCollected> {
+        self.queue = QueueMode::Ring(capacity);
+        self
+    }
+
+    //Caps the items an ordered last stage holds while an earlier one is
+    //late. Parallel sinks wait as InOutStage::reorder_window does; in front
+    //of a single replica, the stages in front wait instead, so items must
+    //reach the sink in order, see BlockingOrderedSet
+    pub fn reorder_window(mut self, window: usize) -> InStage<TInput, TCollected> {
+        self.reorder_window = Some(window);
+        self
+    }
+}
+
+// Internals: Blocks are created back to front, since each block needs the
+// next one. A link remembers the stages added so far and creates them once
+// the block that follows is known, returning the first block.
+type Link<TInput, TCurrent, TCollected> = Box<dyn FnOnce(
+    Box<dyn PipelineBlock<TCurrent, TCollected>>,
+    &Arc<PipelineContext>,
+    &mut Vec<MonitorLoop>
+) -> Box<dyn PipelineBlock<TInput, TCollected>>>;
+
+type SealedLink<TInput, TCollected> = Box<dyn FnOnce(
+    &Arc<PipelineContext>,
+    &Arc<SinkOutput<TCollected>>,
+    &mut Vec<MonitorLoop>
+) -> Box<dyn PipelineBlock<TInput, TCollected>>>;
+
+//Public API: Describes a pipeline one stage at a time. TCurrent is the type
+//of the items leaving the last stage added so far. The type stays the same
+//when stages do not change it, so stages can be added in a loop:
+//
+//    let mut builder = Pipeline::builder();
+//    for filter in filters {
+//        builder = builder.stage_parallel(filter, threads);
+//    }
+//    let pipeline = builder.sink_ordered(SaveImage).build();
+pub struct PipelineBuilder<TInput, TCurrent, TCollected> {
+    config: PipelineConfig,
+    stages: usize,
+    //The last stage so far forwards from a single thread. Never true for the
+    //front of the pipeline, since any thread may post
+    fed_by_one: bool,
+    link: Link<TInput, TCurrent, TCollected>,
+}
+
+//Public API: The stages of a feedback loop, see PipelineBuilder::feedback.
+//Only InOut stages, so that each item leaves the body once per round
+pub struct LoopBody<TLoop, TCurrent, TCollected> {
+    stages: usize,
+    link: Link<TLoop, TCurrent, TCollected>,
+}
+
+impl<TLoop: 'static, TCurrent: Send + 'static, TCollected: Send + 'static> LoopBody<TLoop, TCurrent, TCollected> {
+    pub fn stage<TNext: Send + 'static>(self, stage: InOutStage<TCurrent, TNext>) -> LoopBody<TLoop, TNext, TCollected> {
+        LoopBody {
+            stages: self.stages + 1,
+            link: link_stage(self.link, stage, self.stages, false),
+        }
+    }
+}
+
+//Creates `stage` in front of the next block, then the stages before it
+fn link_stage<TInput: 'static, TCurrent: 'static, TNext: Send + 'static, TCollected: Send + 'static, S>(
+    link: Link<TInput, TCurrent, TCollected>,
+    stage: S,
+    index: usize,
+    fed_by_one: bool
+) -> Link<TInput, TNext, TCollected>
+where S: Stage<TCurrent, TNext> + 'static {
+    Box::new(move |next, context, monitors| {
+        let created = monitors.len();
+        let stage_context = StageContext {
+            fed_by_one,
+            ..StageContext::new(index, context, stage.label())
+        };
+        let block = stage.create(next, &stage_context, monitors);
+        //Stages inside a split tag their own replicas
+        let replicas = monitors[created..].iter_mut().filter(|monitor| monitor.stage().is_none());
+        for (replica, monitor) in replicas.enumerate() {
+            monitor.set_stage(&stage_context, replica);
+        }
+        link(block, context, monitors)
+    })
+}
+
+//Public API: A pipeline description that already has its last stage
+pub struct SealedPipelineBuilder<TInput, TCollected> {
+    config: PipelineConfig,
+    link: SealedLink<TInput, TCollected>,
+}
+
+impl<TInput: 'static, TCollected: 'static> PipelineBuilder<TInput, TInput, TCollected> {
+    pub fn new() -> PipelineBuilder<TInput, TInput, TCollected> {
+        PipelineBuilder {
+            config: PipelineConfig::default(),
+            stages: 0,
+            fed_by_one: false,
+            link: Box::new(|first, _, _| first),
+        }
+    }
+}
+
+impl<TInput: 'static, TCollected: 'static> PipelineBuilder<TInput, TInput, TCollected> {
+    //Numbers the stages from `index` instead of 0. Only for
+    //pipeline_propagate!, whose callers number the stages themselves
+    #[doc(hidden)]
+    pub fn first_stage(mut self, index: usize) -> PipelineBuilder<TInput, TInput, TCollected> {
+        self.stages = index;
+        self
+    }
+}
+
+impl<TInput: 'static, TCollected: 'static> Default for PipelineBuilder<TInput, TInput, TCollected> {
+    fn default() -> Self {
+        PipelineBuilder::new()
+    }
+}
+
+impl<TInput: 'static, TCurrent: 'static, TCollected: 'static> PipelineBuilder<TInput, TCurrent, TCollected>
+where
+    TCurrent: Send,
+    TCollected: Send,
+{
+    pub fn config(mut self, config: PipelineConfig) -> PipelineBuilder<TInput, TCurrent, TCollected> {
+        self.config = config;
+        self
+    }
+
+    pub fn stage<TNext: Send + 'static, S>(self, stage: S) -> PipelineBuilder<TInput, TNext, TCollected>
+    where S: Stage<TCurrent, TNext> + 'static {
+        let index = self.stages;
+        PipelineBuilder {
+            config: self.config,
+            stages: index + stage.stages(),
+            fed_by_one: stage.forwards_from_one(),
+            link: link_stage(self.link, stage, index, self.fed_by_one),
+        }
+    }
+
+    //Adds stages whose last one may send items back to the first, e.g. to
+    //refine them until they converge or to retry them. Each stage of the
+    //body takes one item and gives back at most one, and the last one says
+    //where it goes with a Feedback:
+    //
+    //    builder.feedback(|body| body
+    //        .stage(parallel!(Refine, threads))
+    //        .stage(parallel!(|item: Item| Some(if item.converged() {
+    //            Feedback::Done(item.result())
+    //        } else {
+    //            Feedback::Again(item)
+    //        }), 1)))
+    //
+    //The stages after the loop see the items as they leave it, numbered in
+    //that order. Queues inside the loop should stay unbounded: a full one
+    //would keep the last stage from sending items around, which is the
+    //only way it empties
+    pub fn feedback<TNext: Send + 'static, F>(self, body: F) -> PipelineBuilder<TInput, TNext, TCollected>
+    where F: FnOnce(LoopBody<TCurrent, TCurrent, TCollected>) -> LoopBody<TCurrent, Feedback<TCurrent, TNext>, TCollected> {
+        let first = self.stages;
+        let body = body(LoopBody {
+            stages: first,
+            link: Box::new(|first, _, _| first),
+        });
+        assert!(body.stages > first, "a feedback loop needs at least one stage");
+        let last = body.stages - 1;
+        let link = self.link;
+        PipelineBuilder {
+            config: self.config,
+            stages: body.stages,
+            fed_by_one: false,
+            link: Box::new(move |next, context, monitors| {
+                let exit = LoopExitBlock::new(next, context, first, last);
+                let state = exit.state();
+                let stages = (body.link)(Box::new(exit), context, monitors);
+                link(Box::new(LoopEntryBlock::new(stages, state)), context, monitors)
+            }),
+        }
+    }
+
+    pub fn stage_parallel<TNext: Send + 'static, F>(self, node: F, replicas: i32) -> PipelineBuilder<TInput, TNext, TCollected>
+    where F: InOut<TCurrent, TNext> + Clone + Send + 'static {
+        self.stage(InOutStage::new(BlockMode::Parallel(replicas), in_out_factory(node)))
+    }
+
+    pub fn stage_parallel_ordered<TNext: Send + 'static, F>(self, node: F, replicas: i32) -> PipelineBuilder<TInput, TNext, TCollected>
+    where F: InOut<TCurrent, TNext> + Clone + Send + 'static {
+        self.stage(InOutStage::new(BlockMode::ParallelOrdered(replicas), in_out_factory(node)))
+    }
+
+    //Items with the same key go to the same replica, see InOutStage::keyed
+    pub fn stage_parallel_keyed<TNext: Send + 'static, F, K, KF>(self, node: F, replicas: i32, key: KF) -> PipelineBuilder<TInput, TNext, TCollected>
+    where
+        F: InOut<TCurrent, TNext> + Clone + Send + 'static,
+        K: Hash,
+        KF: Fn(&TCurrent) -> K + Send + Sync + 'static {
+        self.stage(InOutStage::new(BlockMode::Parallel(replicas), in_out_factory(node)).keyed(key))
+    }
+
+    //A single replica, which sees the items in the order they arrive
+    pub fn stage_seq<TNext: Send + 'static, F>(self, node: F) -> PipelineBuilder<TInput, TNext, TCollected>
+    where F: InOut<TCurrent, TNext> + Clone + Send + 'static {
+        self.stage(InOutStage::new(BlockMode::Sequential(OrderingMode::Unordered), in_out_factory(node)))
+    }
+
+    pub fn try_stage_parallel<TNext: Send + 'static, F>(self, node: F, replicas: i32) -> PipelineBuilder<TInput, TNext, TCollected>
+    where F: TryInOut<TCurrent, TNext> + Clone + Send + 'static {
+        self.stage(InOutStage::new(BlockMode::Parallel(replicas), try_in_out_factory(node)))
+    }
+
+    pub fn try_stage_parallel_ordered<TNext: Send + 'static, F>(self, node: F, replicas: i32) -> PipelineBuilder<TInput, TNext, TCollected>
+    where F: TryInOut<TCurrent, TNext> + Clone + Send + 'static {
+        self.stage(InOutStage::new(BlockMode::ParallelOrdered(replicas), try_in_out_factory(node)))
+    }
+
+    //The node returns a future, awaited by the replica, see AsyncInOut
+    pub fn async_stage_parallel<TNext: Send + 'static, F>(self, node: F, replicas: i32) -> PipelineBuilder<TInput, TNext, TCollected>
+    where F: AsyncInOut<TCurrent, TNext> + Clone + Send + 'static {
+        self.stage(InOutStage::new(BlockMode::Parallel(replicas), async_in_out_factory(node)))
+    }
+
+    pub fn async_stage_parallel_ordered<TNext: Send + 'static, F>(self, node: F, replicas: i32) -> PipelineBuilder<TInput, TNext, TCollected>
+    where F: AsyncInOut<TCurrent, TNext> + Clone + Send + 'static {
+        self.stage(InOutStage::new(BlockMode::ParallelOrdered(replicas), async_in_out_factory(node)))
+    }
+
+    //Every output of the node goes on as an item of its own, see InOutMany
+    pub fn stage_many<TNext: Send + 'static, F>(self, node: F, replicas: i32) -> PipelineBuilder<TInput, TNext, TCollected>
+    where F: InOutMany<TCurrent, TNext> + Clone + Send + 'static {
+        self.stage(InOutManyStage::new(BlockMode::Parallel(replicas), in_out_many_factory(node)))
+    }
+
+    pub fn stage_many_ordered<TNext: Send + 'static, F>(self, node: F, replicas: i32) -> PipelineBuilder<TInput, TNext, TCollected>
+    where F: InOutMany<TCurrent, TNext> + Clone + Send + 'static {
+        self.stage(InOutManyStage::new(BlockMode::ParallelOrdered(replicas), in_out_many_factory(node)))
+    }
+
+    pub fn batch(self, size: usize, timeout: Duration) -> PipelineBuilder<TInput, Vec<TCurrent>, TCollected> {
+        self.stage(BatchStage::new(size, timeout))
+    }
+
+    pub fn sink_stage(self, stage: InStage<TCurrent, TCollected>) -> SealedPipelineBuilder<TInput, TCollected> {
+        let index = self.stages;
+        let fed_by_one = self.fed_by_one;
+        let link = self.link;
+        SealedPipelineBuilder {
+            config: self.config,
+            link: Box::new(move |context, output, monitors| {
+                let stage_context = StageContext::new(index, context, stage.label);
+                let mut block = InBlock::new(stage.mode, stage.factory, stage.queue, output.clone());
+                if let Some(window) = stage.reorder_window {
+                    block = block.reorder_window(window);
+                }
+                if fed_by_one {
+                    block = block.fed_by_one(stage.queue);
+                }
+                let mut created = block.monitor_posts(&stage_context);
+                for (replica, monitor) in created.iter_mut().enumerate() {
+                    monitor.set_stage(&stage_context, replica);
+                }
+                monitors.extend(created);
+                link(Box::new(block), context, monitors)
+            }),
+        }
+    }
+
+    pub fn sink<F>(self, node: F) -> SealedPipelineBuilder<TInput, TCollected>
+    where F: In<TCurrent, TCollected> + Clone + Send + 'static {
+        self.sink_stage(InStage::new(BlockMode::Sequential(OrderingMode::Unordered), in_factory(node)))
+    }
+
+    pub fn sink_ordered<F>(self, node: F) -> SealedPipelineBuilder<TInput, TCollected>
+    where F: In<TCurrent, TCollected> + Clone + Send + 'static {
+        self.sink_stage(InStage::new(BlockMode::Sequential(OrderingMode::Ordered), in_factory(node)))
+    }
+
+    pub fn sink_parallel<F>(self, node: F, replicas: i32) -> SealedPipelineBuilder<TInput, TCollected>
+    where F: In<TCurrent, TCollected> + Clone + Send + 'static {
+        self.sink_stage(InStage::new(BlockMode::Parallel(replicas), in_factory(node)))
+    }
+
+    pub fn sink_parallel_ordered<F>(self, node: F, replicas: i32) -> SealedPipelineBuilder<TInput, TCollected>
+    where F: In<TCurrent, TCollected> + Clone + Send + 'static {
+        self.sink_stage(InStage::new(BlockMode::ParallelOrdered(replicas), in_factory(node)))
+    }
+}
+
+impl<TInput: 'static, TCurrent: 'static> PipelineBuilder<TInput, TCurrent, TCurrent>
+where
+    TCurrent: Send,
+{
+    //Gathers the items, returned by Pipeline::collect
+    pub fn collect(self) -> SealedPipelineBuilder<TInput, TCurrent> {
+        self.sink(|item: TCurrent| item)
+    }
+
+    pub fn collect_ordered(self) -> SealedPipelineBuilder<TInput, TCurrent> {
+        self.sink_ordered(|item: TCurrent| item)
+    }
+}
+
+impl<TInput: 'static, TCollected: 'static> SealedPipelineBuilder<TInput, TCollected> {
+    //Creates the blocks and starts their threads
+    pub fn build(self) -> Pipeline<TInput, TCollected> {
+        let context = PipelineContext::new(&self.config);
+        let mut monitors = Vec::<MonitorLoop>::new();
+        let output = SinkOutput::new();
+        let block = self.create(&context, &output, &mut monitors);
+
+        let mut pipeline = Pipeline::new(block, monitors, context, output);
+        pipeline.start();
+        pipeline
+    }
+
+    //Creates the blocks without starting them, and adds the replicas that
+    //run them to `monitors`. The outputs of the last stage go to `output`.
+    //The config of the builder is ignored, `context` was made from one
+    pub fn create(
+        self,
+        context: &Arc<PipelineContext>,
+        output: &Arc<SinkOutput<TCollected>>,
+        monitors: &mut Vec<MonitorLoop>
+    ) -> Box<dyn PipelineBlock<TInput, TCollected>> {
+        let block = (self.link)(context, output, monitors);
+        monitors.extend(context.scaler.monitor());
+        block
+    }
+
+    //Builds the pipeline and feeds it from `source`, see Pipeline::run_source
+    pub fn run_source<I>(self, source: I) -> Result<Vec<TCollected>, PipelineError>
+    where
+        I: IntoIterator<Item = TInput>,
+        I::IntoIter: Send + 'static,
+        TInput: Send {
+        self.build().run_source(source)
+    }
+}
+
+fn in_out_factory<TInput, TOutput, F>(node: F) -> TransformerFactory<TInput, TOutput>
+where F: InOut<TInput, TOutput> + Clone + Send + 'static {
+    Box::new(move || Box::new(FromInOut(node.clone())))
+}
+
+fn try_in_out_factory<TInput, TOutput, F>(node: F) -> TransformerFactory<TInput, TOutput>
+where F: TryInOut<TInput, TOutput> + Clone + Send + 'static {
+    Box::new(move || Box::new(FromTryInOut(node.clone())))
+}
+
+fn async_in_out_factory<TInput, TOutput, F>(node: F) -> TransformerFactory<TInput, TOutput>
+where F: AsyncInOut<TInput, TOutput> + Clone + Send + 'static {
+    Box::new(move || Box::new(FromAsyncInOut(node.clone())))
+}
+
+fn in_out_many_factory<TInput, TOutput, F>(node: F) -> TransformerFactory<TInput, Vec<TOutput>>
+where F: InOutMany<TInput, TOutput> + Clone + Send + 'static {
+    Box::new(move || Box::new(FromInOutMany(node.clone())))
+}
+
+fn in_factory<TInput, TCollected, F>(node: F) -> HandlerFactory<TInput, TCollected>
+where F: In<TInput, TCollected> + Clone + Send + 'static {
+    Box::new(move || Box::new(node.clone()))
+}
@@ -0,0 +1,67 @@
+use std::future::Future;
+use tokio1::runtime::{Builder, Handle, Runtime, RuntimeFlavor};
+
+//Public API: Where the replicas of a pipeline run, see PipelineConfig::executor.
+//The same pipeline definition runs on either one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Executor {
+    //One OS thread per replica, blocking on the stage queues
+    #[default]
+    Threads,
+    //One task per replica on a tokio multi-thread runtime owned by the
+    //pipeline. Async stages yield their worker while they wait; sync stage
+    //code runs inline and keeps its worker busy
+    Tokio { worker_threads: usize },
+}
+
+impl Executor {
+    //A tokio runtime with one worker per core
+    pub fn tokio() -> Executor {
+        Executor::Tokio { worker_threads: num_cpus::get() }
+    }
+
+    pub fn is_async(&self) -> bool {
+        matches!(self, Executor::Tokio { .. })
+    }
+
+    //Internals: The runtime the replica tasks are spawned on. Its threads
+    //are not pinned: park hands the worker of a waiting replica over to a
+    //thread of the blocking pool, so no thread stays a given worker
+    pub fn runtime(&self) -> Option<Runtime> {
+        match self {
+            Executor::Threads => None,
+            Executor::Tokio { worker_threads } => Some(Builder::new_multi_thread()
+                .worker_threads((*worker_threads).max(1))
+                .thread_name("rust-spp-worker")
+                .enable_all()
+                .build()
+                .expect("could not start the tokio runtime"))
+        }
+    }
+}
+
+//Internals: Drives a future on the current thread. Used by replicas of async
+//stages running on their own thread. The runtime is created on first use and
+//kept, so tokio timers and I/O work inside the stage code.
+pub fn block_on<F: Future>(runtime: &mut Option<Runtime>, future: F) -> F::Output {
+    runtime.get_or_insert_with(|| Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("could not start the tokio runtime"))
+        .block_on(future)
+}
+
+//Internals: Runs `wait`, which parks the current thread until another one
+//makes progress. A replica running as a task on a multi-thread runtime must
+//not park a worker that the other replicas may need, so the runtime moves
+//its other tasks away first
+pub fn park<R, F: FnOnce() -> R>(wait: F) -> R {
+    let in_runtime = Handle::try_current()
+        .map(|handle| handle.runtime_flavor() == RuntimeFlavor::MultiThread)
+        .unwrap_or(false);
+    if in_runtime {
+        tokio1::task::block_in_place(wait)
+    } else {
+        wait()
+    }
+}
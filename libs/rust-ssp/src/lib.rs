@@ -1,9 +1,52 @@
+//! Structured stream parallelism for Rust.
+//!
+//! Items and stage nodes move between the threads of a pipeline, so they
+//! must be `Send`. Payloads that are not thread-safe are rejected at compile
+//! time, and need a wrapper that explicitly implements `Send`:
+//!
+//! ```compile_fail
+//! use rust_spp::*;
+//! use std::rc::Rc;
+//!
+//! let pipeline = pipeline![
+//!     parallel!(|item: Rc<u32>| Some(*item), 2),
+//!     collect!()
+//! ];
+//! pipeline.post(Rc::new(1)).unwrap();
+//! ```
+//!
+//! ```
+//! use rust_spp::*;
+//! use std::sync::Arc;
+//!
+//! let pipeline = pipeline![
+//!     parallel!(|item: Arc<u32>| Some(*item), 2),
+//!     collect!()
+//! ];
+//! pipeline.post(Arc::new(1)).unwrap();
+//! assert_eq!(pipeline.collect().unwrap(), vec![1]);
+//! ```
+
+pub mod affinity;
 pub mod blocks;
+pub mod builder;
+pub mod executor;
+pub mod metrics;
+pub mod scaling;
+pub mod stream;
+mod sync;
+pub mod trace;
 pub mod work_storage;
 #[macro_use]
 pub mod spp;
 
 
 pub use spp::*;
+pub use affinity::*;
 pub use blocks::*;
+pub use builder::*;
+pub use executor::Executor;
+pub use metrics::*;
+pub use stream::*;
+pub use trace::*;
 pub use work_storage::*;
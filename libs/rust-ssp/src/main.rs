@@ -1,3 +1,6 @@
+//The image processing driver predates the lints the library is checked with
+#![allow(unused, non_snake_case, clippy::needless_return)]
+
 use clap::{Arg, App};
 
 mod image_processing;
@@ -0,0 +1,598 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+use serde::{Serialize, Serializer};
+use crate::affinity::Placement;
+
+//Public API: Runtime statistics of a pipeline, see PipelineConfig::metrics
+#[derive(Debug, Clone, Serialize)]
+pub struct PipelineMetrics {
+    #[serde(serialize_with = "as_secs")]
+    pub elapsed: Duration,
+    pub stages: Vec<StageMetrics>,
+    //Only for pipelines fed by Pipeline::run_source. Busy is the time spent
+    //producing items, idle the time waiting for the first stage to take them
+    pub source: Option<ReplicaMetrics>,
+    //None when no item reached the last stage
+    pub latency: Option<LatencyMetrics>,
+    //Items whose entry time is kept for their latency: those still in the
+    //pipeline. 0 once it ended
+    pub items_in_flight: usize,
+    //The affinity policy and the core of each replica
+    pub placement: Placement,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StageMetrics {
+    pub stage: usize,
+    //Given with .label() on the stage, or label = "..." in its macro
+    pub label: Option<String>,
+    //Items that went through the stage code, summed over all replicas
+    pub items: u64,
+    pub replicas: Vec<ReplicaMetrics>,
+    pub max_queue_len: usize,
+    pub queue_samples: Vec<QueueSample>,
+    //Most items held at once by the reorder buffer of an ordered stage,
+    //waiting for earlier ones. 0 for stages that do not reorder
+    pub reorder_high_water: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplicaMetrics {
+    pub items: u64,
+    //Time spent running the stage code
+    #[serde(serialize_with = "as_secs")]
+    pub busy: Duration,
+    //Time spent waiting for items (or for the next stage to accept them)
+    #[serde(serialize_with = "as_secs")]
+    pub idle: Duration,
+}
+
+//Length of a stage input queue, taken when a replica dequeues from it
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueSample {
+    #[serde(serialize_with = "as_secs")]
+    pub at: Duration,
+    pub len: usize,
+}
+
+//Time from Pipeline::post until the last stage finished an item. The
+//percentiles come from a histogram, and are within an eighth of the exact
+//ones; the rest is exact
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyMetrics {
+    pub items: usize,
+    #[serde(serialize_with = "as_secs")]
+    pub min: Duration,
+    #[serde(serialize_with = "as_secs")]
+    pub mean: Duration,
+    #[serde(serialize_with = "as_secs")]
+    pub p50: Duration,
+    #[serde(serialize_with = "as_secs")]
+    pub p99: Duration,
+    #[serde(serialize_with = "as_secs")]
+    pub max: Duration,
+}
+
+fn as_secs<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+impl PipelineMetrics {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("metrics are always serializable")
+    }
+}
+
+impl LatencyMetrics {
+    fn from_histogram(histogram: &LatencyHistogram) -> Option<LatencyMetrics> {
+        if histogram.items == 0 {
+            return None;
+        }
+        Some(LatencyMetrics {
+            items: histogram.items,
+            min: histogram.min,
+            mean: Duration::from_nanos((histogram.total.as_nanos() / histogram.items as u128) as u64),
+            p50: histogram.percentile(50),
+            p99: histogram.percentile(99),
+            max: histogram.max,
+        })
+    }
+}
+
+//Internals: Latencies counted by bucket. Each power of two of nanoseconds
+//is split in eight buckets, so a bucket is at most an eighth of its value
+//wide, and a histogram takes the same room however many items it counts
+struct LatencyHistogram {
+    counts: Vec<u64>,
+    items: usize,
+    total: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+const LATENCY_BUCKETS: usize = 62 * 8;
+
+impl LatencyHistogram {
+    fn new() -> LatencyHistogram {
+        LatencyHistogram {
+            counts: vec![0; LATENCY_BUCKETS],
+            items: 0,
+            total: Duration::from_secs(0),
+            min: Duration::MAX,
+            max: Duration::from_secs(0),
+        }
+    }
+
+    //Below 8ns, one bucket per nanosecond
+    fn bucket(nanos: u64) -> usize {
+        if nanos < 8 {
+            return nanos as usize;
+        }
+        let exponent = 63 - nanos.leading_zeros() as usize;
+        let sub_bucket = (nanos >> (exponent - 3)) as usize & 7;
+        (exponent - 2) * 8 + sub_bucket
+    }
+
+    fn lower_bound(bucket: usize) -> u64 {
+        if bucket < 8 {
+            return bucket as u64;
+        }
+        let exponent = bucket / 8 + 2;
+        ((8 + bucket % 8) as u64) << (exponent - 3)
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let nanos = latency.as_nanos().min(u64::MAX as u128) as u64;
+        self.counts[LatencyHistogram::bucket(nanos)] += 1;
+        self.items += 1;
+        self.total += latency;
+        self.min = self.min.min(latency);
+        self.max = self.max.max(latency);
+    }
+
+    fn merge(&mut self, other: &LatencyHistogram) {
+        if other.items == 0 {
+            return;
+        }
+        self.counts.iter_mut().zip(&other.counts).for_each(|(count, other)| *count += other);
+        self.items += other.items;
+        self.total += other.total;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    fn percentile(&self, p: usize) -> Duration {
+        let rank = ((self.items - 1) * p / 100) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen > rank {
+                let lower_bound = Duration::from_nanos(LatencyHistogram::lower_bound(bucket));
+                return lower_bound.clamp(self.min, self.max);
+            }
+        }
+        self.max
+    }
+}
+
+//Internals: Collects the metrics of one pipeline. Replicas only touch the
+//shared state when sampling the queue (at most once per sample interval)
+//and once when they exit; everything else is counted locally, except for
+//when each item entered the pipeline, see InFlight.
+pub struct MetricsCollector {
+    started: Instant,
+    sample_interval: Duration,
+    stages: Mutex<BTreeMap<usize, Arc<StageRecorder>>>,
+    labels: Mutex<BTreeMap<usize, String>>,
+    source: Arc<StageRecorder>,
+    in_flight: Arc<InFlight>,
+    placement: Mutex<Placement>,
+}
+
+impl MetricsCollector {
+    pub fn new(sample_interval: Duration) -> MetricsCollector {
+        MetricsCollector {
+            started: Instant::now(),
+            sample_interval,
+            stages: Mutex::new(BTreeMap::new()),
+            labels: Mutex::new(BTreeMap::new()),
+            source: Arc::new(StageRecorder::new()),
+            in_flight: Arc::new(InFlight::new()),
+            placement: Mutex::new(Placement::default()),
+        }
+    }
+
+    pub fn stage(&self, index: usize) -> Arc<StageRecorder> {
+        self.stages.lock()
+            .entry(index)
+            .or_insert_with(|| Arc::new(StageRecorder::new()))
+            .clone()
+    }
+
+    pub fn labelled(&self, stage: usize, label: &str) {
+        self.labels.lock().insert(stage, label.to_string());
+    }
+
+    pub fn source(&self) -> Arc<StageRecorder> {
+        self.source.clone()
+    }
+
+    //Called once the replicas are started
+    pub fn placed(&self, placement: Placement) {
+        *self.placement.lock() = placement;
+    }
+
+    //`posted` is when Pipeline::post was called, `order` what it got
+    pub fn item_posted(&self, order: u64, posted: Instant) {
+        self.in_flight.entered(0, order, posted);
+    }
+
+    //Called when the pipeline is built, by stages that renumber items
+    pub fn renumbers(&self, numbering: Numbering) {
+        self.in_flight.registered.lock().insert(numbering);
+    }
+
+    //Item `to` of a renumbering stage was made from the items `from` it
+    //received, and entered the pipeline with the first of them
+    pub fn item_renumbered(&self, numbering: Numbering, from: &[u64], to: u64) {
+        let numbering = self.in_flight.position(numbering);
+        self.in_flight.renumbered(numbering - 1, from, numbering, to);
+    }
+
+    //A renumbering stage is done with item `order` it received, and will
+    //not make more items from it
+    pub fn item_consumed(&self, numbering: Numbering, order: u64) {
+        let numbering = self.in_flight.position(numbering);
+        self.in_flight.consumed(numbering - 1, order);
+    }
+
+    //Item `from` got the order `to` in the same numbering, when a feedback
+    //loop sends it around again. It keeps the time it entered the pipeline
+    pub fn item_requeued(&self, numbering: Numbering, from: u64, to: u64) {
+        let numbering = self.in_flight.position(numbering);
+        self.in_flight.renumbered(numbering, &[from], numbering, to);
+        self.in_flight.consumed(numbering, from);
+    }
+
+    pub fn replica_probe(&self, stage: Arc<StageRecorder>) -> ReplicaProbe {
+        let now = Instant::now();
+        ReplicaProbe {
+            recorder: Some(ProbeState {
+                stage,
+                in_flight: self.in_flight.clone(),
+                started: self.started,
+                sample_interval: self.sample_interval,
+                last_mark: now,
+                last_sample: None,
+                metrics: ReplicaMetrics {
+                    items: 0,
+                    busy: Duration::from_secs(0),
+                    idle: Duration::from_secs(0),
+                },
+                latency: None,
+            })
+        }
+    }
+
+    pub fn snapshot(&self) -> PipelineMetrics {
+        let labels = self.labels.lock();
+        let stages = self.stages.lock().iter()
+            .map(|(index, recorder)| StageMetrics {
+                label: labels.get(index).cloned(),
+                ..recorder.snapshot(*index)
+            })
+            .collect();
+        PipelineMetrics {
+            elapsed: self.started.elapsed(),
+            stages,
+            source: self.source.replicas.lock().first().cloned(),
+            latency: LatencyMetrics::from_histogram(&self.in_flight.latency.lock()),
+            items_in_flight: self.in_flight.len(),
+            placement: self.placement.lock().clone(),
+        }
+    }
+}
+
+/*
+ * Internals: When the items still in flight entered the pipeline, for end-to-
+ * end latency. Items are numbered by Pipeline::post, then again by each
+ * stage that renumbers them; entry times follow the items from one
+ * numbering to the next, and are forgotten once the item completed, was
+ * dropped, or was handed on by a renumbering stage. So only items in flight
+ * take room. The times of a numbering are split in shards by order, so the
+ * replicas of a stage, which hold consecutive orders, rarely wait for each
+ * other, and each shard lock is only taken once or twice per item.
+ *
+ * A replica can reach an item before Pipeline::post recorded it, since the
+ * order is only known once the item is in the first queue. What happens to
+ * an item before its entry time is known is kept in its entry and done once
+ * the time is there.
+ */
+struct InFlight {
+    //Numberings of the renumbering stages, known once the pipeline is built
+    registered: Mutex<BTreeSet<Numbering>>,
+    //Post orders first, then one per registered numbering, in their order
+    numberings: OnceLock<(Vec<Numbering>, Vec<EntryTimes>)>,
+    //Latencies of the replicas that exited, and of items that completed
+    //before they were posted
+    latency: Mutex<LatencyHistogram>,
+}
+
+struct EntryTimes {
+    shards: Vec<Mutex<HashMap<u64, Entry>>>,
+}
+
+const ENTRY_SHARDS: usize = 64;
+
+#[derive(Default)]
+struct Entry {
+    entered: Option<Instant>,
+    //Only while the entry time is not known yet: numbering and order of the
+    //items it was renumbered into, when it completed and whether it was
+    //consumed (dropped, or handed on)
+    renumbered: Vec<(usize, u64)>,
+    completed: Option<Instant>,
+    consumed: bool,
+}
+
+impl EntryTimes {
+    fn new() -> EntryTimes {
+        EntryTimes { shards: (0..ENTRY_SHARDS).map(|_| Mutex::new(HashMap::new())).collect() }
+    }
+
+    fn shard(&self, order: u64) -> &Mutex<HashMap<u64, Entry>> {
+        &self.shards[order as usize % ENTRY_SHARDS]
+    }
+}
+
+impl InFlight {
+    fn new() -> InFlight {
+        InFlight {
+            registered: Mutex::new(BTreeSet::new()),
+            numberings: OnceLock::new(),
+            latency: Mutex::new(LatencyHistogram::new()),
+        }
+    }
+
+    //No stage registers once items flow
+    fn times(&self) -> &[EntryTimes] {
+        let (_, times) = self.numberings.get_or_init(|| {
+            let registered: Vec<Numbering> = self.registered.lock().iter().copied().collect();
+            let times = (0..=registered.len()).map(|_| EntryTimes::new()).collect();
+            (registered, times)
+        });
+        times
+    }
+
+    fn position(&self, numbering: Numbering) -> usize {
+        self.times();
+        let (registered, _) = self.numberings.get().expect("initialized by times");
+        registered.binary_search(&numbering).expect("renumbering stages register when the pipeline is built") + 1
+    }
+
+    fn entered(&self, numbering: usize, order: u64, at: Instant) {
+        let mut pending = vec![(numbering, order, at)];
+        while let Some((numbering, order, at)) = pending.pop() {
+            let mut shard = self.times()[numbering].shard(order).lock();
+            let entry = shard.entry(order).or_default();
+            let at = entry.entered.map_or(at, |entered| entered.min(at));
+            entry.entered = Some(at);
+            pending.extend(entry.renumbered.drain(..).map(|(numbering, order)| (numbering, order, at)));
+            if let Some(completed) = entry.completed {
+                self.latency.lock().record(completed.saturating_duration_since(at));
+                shard.remove(&order);
+            } else if entry.consumed {
+                shard.remove(&order);
+            }
+        }
+    }
+
+    //The new item enters with the first of the items it was made from that
+    //has an entry time. When none has one yet, it waits for one of them
+    fn renumbered(&self, from_numbering: usize, from: &[u64], numbering: usize, to: u64) {
+        let times = &self.times()[from_numbering];
+        let first = from.iter()
+            .filter_map(|order| times.shard(*order).lock().get(order).and_then(|entry| entry.entered))
+            .min();
+        match (first, from.first()) {
+            (Some(first), _) => self.entered(numbering, to, first),
+            (None, Some(order)) => {
+                let mut shard = times.shard(*order).lock();
+                let entry = shard.entry(*order).or_default();
+                match entry.entered {
+                    Some(entered) => {
+                        drop(shard);
+                        self.entered(numbering, to, entered);
+                    }
+                    None => entry.renumbered.push((numbering, to))
+                }
+            }
+            (None, None) => {}
+        }
+    }
+
+    fn consumed(&self, numbering: usize, order: u64) {
+        let mut shard = self.times()[numbering].shard(order).lock();
+        let entry = shard.entry(order).or_default();
+        if entry.entered.is_some() {
+            shard.remove(&order);
+        } else {
+            entry.consumed = true;
+        }
+    }
+
+    //Items reach the last stage in the last numbering
+    fn completed(&self, order: u64, at: Instant) -> Option<Duration> {
+        let times = self.times();
+        let mut shard = times[times.len() - 1].shard(order).lock();
+        let entry = shard.entry(order).or_default();
+        match entry.entered {
+            Some(entered) => {
+                shard.remove(&order);
+                Some(at.saturating_duration_since(entered))
+            }
+            None => {
+                entry.completed = Some(at);
+                None
+            }
+        }
+    }
+
+    fn dropped(&self, order: u64) {
+        let last = self.times().len() - 1;
+        self.consumed(last, order);
+    }
+
+    fn len(&self) -> usize {
+        self.times().iter()
+            .flat_map(|times| &times.shards)
+            .map(|shard| shard.lock().len())
+            .sum()
+    }
+}
+
+//Internals: Where a stage gives items new orders: behind its replicas
+//(fan-out, batching and feedback loop exits) or in front of them (feedback
+//loop entries). Numberings follow each other in this order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Numbering {
+    pub stage: usize,
+    pub behind: bool,
+}
+
+impl Numbering {
+    pub fn in_front(stage: usize) -> Numbering {
+        Numbering { stage, behind: false }
+    }
+
+    pub fn behind(stage: usize) -> Numbering {
+        Numbering { stage, behind: true }
+    }
+}
+
+//Internals: Metrics shared by the replicas of one stage
+pub struct StageRecorder {
+    replicas: Mutex<Vec<ReplicaMetrics>>,
+    queue_samples: Mutex<Vec<QueueSample>>,
+    reorder_high_water: Mutex<usize>,
+}
+
+impl StageRecorder {
+    fn new() -> StageRecorder {
+        StageRecorder {
+            replicas: Mutex::new(vec![]),
+            queue_samples: Mutex::new(vec![]),
+            reorder_high_water: Mutex::new(0),
+        }
+    }
+
+    //Called once the stream ended
+    pub fn reorder_held(&self, items: usize) {
+        let mut high_water = self.reorder_high_water.lock();
+        *high_water = (*high_water).max(items);
+    }
+
+    fn snapshot(&self, stage: usize) -> StageMetrics {
+        let replicas = self.replicas.lock().clone();
+        let mut queue_samples = self.queue_samples.lock().clone();
+        queue_samples.sort_by_key(|sample| sample.at);
+        StageMetrics {
+            stage,
+            label: None,
+            items: replicas.iter().map(|replica| replica.items).sum(),
+            max_queue_len: queue_samples.iter().map(|sample| sample.len).max().unwrap_or(0),
+            replicas,
+            queue_samples,
+            reorder_high_water: *self.reorder_high_water.lock(),
+        }
+    }
+}
+
+//Internals: Per replica timer. Does nothing when metrics are disabled.
+//Time between dequeued() and processed() is busy, the rest is idle.
+pub struct ReplicaProbe {
+    recorder: Option<ProbeState>,
+}
+
+struct ProbeState {
+    stage: Arc<StageRecorder>,
+    in_flight: Arc<InFlight>,
+    started: Instant,
+    sample_interval: Duration,
+    last_mark: Instant,
+    last_sample: Option<Instant>,
+    metrics: ReplicaMetrics,
+    //Only for replicas of the last stage, once they completed an item
+    latency: Option<Box<LatencyHistogram>>,
+}
+
+impl ReplicaProbe {
+    pub fn disabled() -> ReplicaProbe {
+        ReplicaProbe { recorder: None }
+    }
+
+    pub fn dequeued<F>(&mut self, queue_len: F) where F: FnOnce() -> usize {
+        self.waited();
+        if let Some(state) = &mut self.recorder {
+            let now = state.last_mark;
+            let due = match state.last_sample {
+                Some(last) => now - last >= state.sample_interval,
+                None => true,
+            };
+            if due {
+                state.last_sample = Some(now);
+                state.stage.queue_samples.lock().push(QueueSample {
+                    at: now - state.started,
+                    len: queue_len(),
+                });
+            }
+        }
+    }
+
+    //Ends an idle period without sampling a queue
+    pub fn waited(&mut self) {
+        if let Some(state) = &mut self.recorder {
+            let now = Instant::now();
+            state.metrics.idle += now - state.last_mark;
+            state.last_mark = now;
+        }
+    }
+
+    pub fn processed(&mut self) {
+        if let Some(state) = &mut self.recorder {
+            let now = Instant::now();
+            state.metrics.busy += now - state.last_mark;
+            state.metrics.items += 1;
+            state.last_mark = now;
+        }
+    }
+
+    //Called by the last stage, for end-to-end latency
+    pub fn completed(&mut self, order: u64) {
+        if let Some(state) = &mut self.recorder {
+            if let Some(latency) = state.in_flight.completed(order, Instant::now()) {
+                state.latency.get_or_insert_with(|| Box::new(LatencyHistogram::new())).record(latency);
+            }
+        }
+    }
+
+    //Called by the last stage for the items it got as Dropped, or failed on
+    pub fn dropped(&mut self, order: u64) {
+        if let Some(state) = &self.recorder {
+            state.in_flight.dropped(order);
+        }
+    }
+}
+
+impl Drop for ReplicaProbe {
+    fn drop(&mut self) {
+        if let Some(state) = self.recorder.take() {
+            state.stage.replicas.lock().push(state.metrics);
+            if let Some(latency) = state.latency {
+                state.in_flight.latency.lock().merge(&latency);
+            }
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use std::cmp::Reverse;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use parking_lot::{Condvar, Mutex};
+use tokio1::runtime::Handle;
+use crate::blocks::MonitorLoop;
+use crate::sync::atomic::AtomicUsize as Running;
+
+//Time between two decisions of the scaler
+const SCALING_INTERVAL: Duration = Duration::from_millis(5);
+
+/*
+ * Adaptive blocks (BlockMode::Adaptive) start with their share of the thread
+ * budget left by the other blocks. A single scaler thread per pipeline then
+ * decides how many replicas each of them should run, see rebalance:
+ *
+ *  - A stage whose queue would take longer than one interval to drain with
+ *    its current replicas, at its measured service time, gets one more.
+ *  - A stage with an empty queue and replicas busy less than half of the
+ *    time gives one back.
+ *
+ * The replicas of adaptive stages plus the replicas of all other stages stay
+ * within the thread budget of the pipeline. When the budget is used up, a
+ * stage under pressure takes a replica from the adaptive stage with the
+ * smallest backlog.
+ *
+ * The scaler starts a replica as soon as a stage is given one more. Replicas
+ * beyond the count of their stage leave between two items, so a stage never
+ * runs fewer than one. Replicas started by the scaler are not pinned and do
+ * not show up in Pipeline::placement; the scaler joins them before it ends.
+ *
+ * Once Stop is on its way into the queue of a block, its replicas no longer
+ * leave: each of them has to see the Stop. The scaler may still start more to
+ * drain the queue, as long as some are running to count them in.
+ */
+pub struct ReplicaScaler {
+    budget: usize,
+    fixed_replicas: AtomicUsize,
+    stages: Mutex<Vec<Arc<AdaptiveStage>>>,
+    wake: Arc<ScalerWake>,
+    //Where replicas running as tasks are started, see Pipeline::new
+    runtime: Mutex<Option<Handle>>
+}
+
+struct ScalerWake {
+    lock: Mutex<()>,
+    stage_finished: Condvar
+}
+
+//Internals: Creates replica `n` of an adaptive block
+pub type ReplicaSpawner = Box<dyn FnMut(&Arc<AdaptiveStage>, usize) -> MonitorLoop + Send>;
+
+impl ReplicaScaler {
+    pub fn new(budget: usize) -> ReplicaScaler {
+        ReplicaScaler {
+            budget: budget.max(1),
+            fixed_replicas: AtomicUsize::new(0),
+            stages: Mutex::new(vec![]),
+            wake: Arc::new(ScalerWake {
+                lock: Mutex::new(()),
+                stage_finished: Condvar::new()
+            }),
+            runtime: Mutex::new(None)
+        }
+    }
+
+    //Replicas of non-adaptive blocks, which count towards the budget
+    pub fn reserve(&self, replicas: usize) {
+        self.fixed_replicas.fetch_add(replicas, Ordering::Relaxed);
+    }
+
+    //`running` counts the replicas of the block, which starts with the one
+    //given by AdaptiveStage::first_replica
+    pub fn register<F>(
+        &self,
+        max: usize,
+        running: Arc<Running>,
+        queue_len: F,
+        spawn: ReplicaSpawner
+    ) -> Arc<AdaptiveStage>
+        where F: Fn() -> usize + Send + Sync + 'static {
+        let adaptive = Arc::new(AdaptiveStage {
+            max: max.max(1),
+            active: AtomicUsize::new(1),
+            running,
+            replicas: Mutex::new(Replicas {
+                spawn: Some(spawn),
+                started: 0,
+                stopping: false
+            }),
+            busy_nanos: AtomicU64::new(0),
+            processed: AtomicU64::new(0),
+            queue_len: Box::new(queue_len),
+            scaler: self.wake.clone()
+        });
+        self.stages.lock().push(adaptive.clone());
+        adaptive
+    }
+
+    //Replicas of async pipelines are started on the pipeline runtime
+    pub fn spawn_on(&self, runtime: Option<&Handle>) {
+        *self.runtime.lock() = runtime.cloned();
+    }
+
+    //Nothing when the pipeline has no adaptive block. Otherwise shares the
+    //budget left by the other blocks between the adaptive ones, and gives
+    //the replicas this takes along with the scaler loop, which ends once
+    //the replicas of all of them have left
+    pub fn monitor(self: &Arc<Self>) -> Vec<MonitorLoop> {
+        let stages = self.stages.lock();
+        if stages.is_empty() {
+            return vec![];
+        }
+        let share = self.spare(0) / stages.len();
+        let mut monitors: Vec<MonitorLoop> = stages.iter()
+            .flat_map(|stage| stage.set_active(share.clamp(1, stage.max)))
+            .collect();
+        let scaler = self.clone();
+        monitors.push(MonitorLoop::new(move || scaler.run()).named("rust-spp-scaler"));
+        monitors
+    }
+
+    fn spare(&self, active: usize) -> usize {
+        self.budget.saturating_sub(self.fixed_replicas.load(Ordering::Relaxed) + active)
+    }
+
+    //Rebalances until the stream ends, then joins the replicas it started
+    fn run(&self) {
+        let runtime = self.runtime.lock().clone();
+        let stages = self.stages.lock().clone();
+        let mut started = vec![];
+        let mut service = vec![None; stages.len()];
+        let mut waiting = self.wake.lock.lock();
+        while !stages.iter().all(|stage| stage.is_finished()) {
+            self.wake.stage_finished.wait_for(&mut waiting, SCALING_INTERVAL);
+            let loads: Vec<StageLoad> = stages.iter().zip(service.iter_mut())
+                .map(|(stage, service)| stage.load(service))
+                .collect();
+            let running = stages.iter().zip(&loads)
+                .filter(|(stage, _)| !stage.is_finished())
+                .map(|(_, load)| load.active);
+            let spare = self.spare(running.sum());
+            for (stage, active) in stages.iter().zip(rebalance(&loads, spare)) {
+                if active != stage.active() {
+                    started.extend(stage.set_active(active).into_iter()
+                        .map(|replica| replica.spawn(runtime.as_ref(), None).0));
+                }
+            }
+        }
+        drop(waiting);
+
+        for replica in started {
+            replica.join(runtime.as_ref());
+        }
+    }
+}
+
+//Internals: What the scaler saw of an adaptive stage during the last interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct StageLoad {
+    //Most replicas the stage may run
+    max: usize,
+    //Replicas the stage runs now
+    active: usize,
+    //Time the active replicas need to go through the queue
+    backlog: Duration,
+    //Nothing queued, and the replicas busy less than half of the time
+    idle: bool,
+    //Stop is in the queue of the stage: it may still get replicas to drain
+    //the queue, but keeps the ones it has
+    stopped: bool
+}
+
+//Internals: One decision of the scaler, see ReplicaScaler. Gives the
+//replicas each stage should run, given their loads and the replicas left
+//in the thread budget
+pub(crate) fn rebalance(loads: &[StageLoad], mut spare: usize) -> Vec<usize> {
+    let mut active: Vec<usize> = loads.iter().map(|load| load.active).collect();
+    let mut by_backlog: Vec<usize> = (0..loads.len()).collect();
+    by_backlog.sort_by_key(|&index| Reverse(loads[index].backlog));
+
+    for &index in &by_backlog {
+        let load = &loads[index];
+        if load.backlog > SCALING_INTERVAL && active[index] < load.max {
+            if spare > 0 {
+                spare -= 1;
+                active[index] += 1;
+            } else if let Some(&donor) = by_backlog.iter().rev().find(|&&donor| {
+                !loads[donor].stopped && active[donor] > 1 && loads[donor].backlog * 4 < load.backlog
+            }) {
+                active[donor] -= 1;
+                active[index] += 1;
+            }
+        } else if load.idle && !load.stopped && active[index] > 1 {
+            spare += 1;
+            active[index] -= 1;
+        }
+    }
+    active
+}
+
+//Internals: The scaling state of one adaptive block, shared by its replicas
+pub struct AdaptiveStage {
+    max: usize,
+    //Replicas the scaler wants the block to run
+    active: AtomicUsize,
+    //Replicas running. Shared with them, as they leave once they saw Stop
+    running: Arc<Running>,
+    replicas: Mutex<Replicas>,
+    //Since the last scaler decision
+    busy_nanos: AtomicU64,
+    processed: AtomicU64,
+    queue_len: Box<dyn Fn() -> usize + Send + Sync>,
+    scaler: Arc<ScalerWake>
+}
+
+struct Replicas {
+    //Taken once the last replica left, which also lets go of the next block
+    spawn: Option<ReplicaSpawner>,
+    //Replicas created so far, which numbers the next one
+    started: usize,
+    //Stop is on its way into the queue
+    stopping: bool
+}
+
+impl AdaptiveStage {
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+
+    fn is_stopping(&self) -> bool {
+        self.replicas.lock().stopping
+    }
+
+    //All replicas have seen Stop and left
+    pub fn is_finished(&self) -> bool {
+        self.replicas.lock().spawn.is_none()
+    }
+
+    //The replica the block starts with, along with the other blocks
+    pub fn first_replica(self: &Arc<Self>) -> MonitorLoop {
+        let mut replicas = self.replicas.lock();
+        let Replicas { spawn, started, .. } = &mut *replicas;
+        let spawn = spawn.as_mut().expect("the block finished before it started");
+        *started += 1;
+        spawn(self, 0)
+    }
+
+    //Creates replicas until the block runs `active` of them, for the caller
+    //to start. Extra ones leave by themselves, see retire
+    fn set_active(self: &Arc<Self>, active: usize) -> Vec<MonitorLoop> {
+        let mut replicas = self.replicas.lock();
+        self.active.store(active, Ordering::Release);
+        let Replicas { spawn, started, .. } = &mut *replicas;
+        let mut created = vec![];
+        let Some(spawn) = spawn else {
+            return created;
+        };
+        //Once Stop is in the queue, a replica may only join while others are
+        //still running: the last one to leave forwards the Stop
+        while self.running.load(Ordering::Acquire) < active && self.running
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |running| (running > 0).then_some(running + 1))
+            .is_ok() {
+            created.push(spawn(self, *started));
+            *started += 1;
+        }
+        created
+    }
+
+    //Called by a replica between two items: true when it should leave, as
+    //the block runs more replicas than the scaler wants. Never once Stop is
+    //on its way, as the replicas running then must all see it
+    pub fn retire(&self) -> bool {
+        if self.running.load(Ordering::Acquire) <= self.active() {
+            return false;
+        }
+        let replicas = self.replicas.lock();
+        if replicas.stopping || self.running.load(Ordering::Acquire) <= self.active() {
+            return false;
+        }
+        self.running.fetch_sub(1, Ordering::AcqRel);
+        true
+    }
+
+    //Called before Stop goes into the queue of the block
+    pub fn stop(&self) {
+        self.replicas.lock().stopping = true;
+    }
+
+    //Called by the last replica to leave, once it forwarded the Stop
+    pub fn finished(&self) {
+        let spawn = self.replicas.lock().spawn.take();
+        drop(spawn);
+        let _waiting = self.scaler.lock.lock();
+        self.scaler.stage_finished.notify_all();
+    }
+
+    //A replica went through one item, started at `started`
+    pub fn served(&self, started: Instant) {
+        self.busy_nanos.fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        self.processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    //Takes the counters of the last interval. The service time of the last
+    //interval that processed anything is kept in `service`; before the first
+    //item, a waiting queue is assumed to be worth one interval per item
+    fn load(&self, service: &mut Option<Duration>) -> StageLoad {
+        let busy = self.busy_nanos.swap(0, Ordering::Relaxed);
+        let processed = self.processed.swap(0, Ordering::Relaxed);
+        if let Some(average) = busy.checked_div(processed) {
+            *service = Some(Duration::from_nanos(average));
+        }
+        let queued = (self.queue_len)().min(u32::MAX as usize) as u32;
+        let active = self.active();
+        let busy = Duration::from_nanos(busy);
+        StageLoad {
+            max: self.max,
+            active,
+            backlog: service.unwrap_or(SCALING_INTERVAL).saturating_mul(queued) / active as u32,
+            idle: queued == 0 && busy < SCALING_INTERVAL * active as u32 / 2,
+            stopped: self.is_stopping()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(active: usize, backlog_millis: u64) -> StageLoad {
+        StageLoad {
+            max: 4,
+            active,
+            backlog: Duration::from_millis(backlog_millis),
+            idle: false,
+            stopped: false
+        }
+    }
+
+    #[test]
+    fn slow_stage_gets_more_replicas() {
+        let mut loads = vec![load(1, 0), load(1, 50)];
+        for expected in [2, 3, 4, 4] {
+            let active = rebalance(&loads, 8);
+            assert_eq!(active, vec![1, expected]);
+            loads[1].active = expected;
+        }
+    }
+
+    #[test]
+    fn busiest_stage_takes_the_spare_replicas_first() {
+        let loads = vec![load(1, 20), load(1, 50), load(1, 30)];
+        assert_eq!(rebalance(&loads, 2), vec![1, 2, 2]);
+    }
+
+    #[test]
+    fn replicas_move_to_the_longest_backlog_once_the_budget_is_used() {
+        let loads = vec![load(3, 1), load(1, 50)];
+        assert_eq!(rebalance(&loads, 0), vec![2, 2]);
+        // Backlogs within a factor of four of each other keep their replicas
+        let loads = vec![load(3, 20), load(1, 50)];
+        assert_eq!(rebalance(&loads, 0), vec![3, 1]);
+    }
+
+    #[test]
+    fn idle_stage_gives_replicas_back() {
+        let idle = StageLoad { idle: true, ..load(3, 0) };
+        assert_eq!(rebalance(&[idle], 0), vec![2]);
+        assert_eq!(rebalance(&[StageLoad { active: 1, ..idle }], 0), vec![1]);
+    }
+
+    #[test]
+    fn stopped_stages_keep_their_replicas() {
+        // They may still get more to drain their queue
+        let stopped = StageLoad { stopped: true, ..load(1, 50) };
+        assert_eq!(rebalance(&[stopped], 4), vec![2]);
+        let stopped = StageLoad { stopped: true, idle: true, ..load(3, 0) };
+        assert_eq!(rebalance(&[stopped, load(1, 50)], 0), vec![3, 1]);
+    }
+}
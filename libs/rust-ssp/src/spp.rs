@@ -1,46 +1,169 @@
 
-use std::thread;
-use std::thread::JoinHandle;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use parking_lot::Mutex;
+use tokio1::runtime::{Handle, Runtime};
+use crate::affinity::{Affinity, Placement, ReplicaPlacement, Topology};
 use crate::blocks::*;
+use crate::builder::PipelineBuilder;
+use crate::executor::Executor;
+use crate::metrics::PipelineMetrics;
+use crate::trace::PipelineTrace;
+use crate::stream::{OutputStream, PipelineStream};
 use crate::work_storage::WorkItem;
 
-pub struct Pipeline<TInput, TOutput, TCollected> {
+//Public API: Options for a whole pipeline, see pipeline![config = ...; ...]
+#[derive(Debug, Clone, Default)]
+pub struct PipelineConfig {
+    metrics: Option<Duration>,
+    tracing: bool,
+    executor: Executor,
+    thread_budget: Option<usize>,
+    affinity: Affinity,
+}
+
+impl PipelineConfig {
+    pub fn new() -> PipelineConfig {
+        PipelineConfig::default()
+    }
+
+    //Records per stage throughput, busy/idle time, queue lengths and
+    //end-to-end latency, available from Pipeline::metrics. Off by default.
+    pub fn metrics(mut self, enabled: bool) -> PipelineConfig {
+        self.metrics = if enabled {
+            Some(self.metrics.unwrap_or_else(|| Duration::from_millis(1)))
+        } else {
+            None
+        };
+        self
+    }
+
+    //Minimum time between two queue length samples of the same replica
+    pub fn queue_sample_interval(&self) -> Option<Duration> {
+        self.metrics
+    }
+
+    pub fn sample_queues_every(mut self, interval: Duration) -> PipelineConfig {
+        self.metrics = Some(interval);
+        self
+    }
+
+    //Records when each item was enqueued, dequeued and processed by which
+    //replica, available from Pipeline::trace. Off by default, and much more
+    //costly than metrics: every event is kept until the pipeline is dropped
+    pub fn tracing(mut self, enabled: bool) -> PipelineConfig {
+        self.tracing = enabled;
+        self
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.tracing
+    }
+
+    //Runs the replicas on OS threads (the default) or as tokio tasks
+    pub fn executor(&self) -> Executor {
+        self.executor
+    }
+
+    pub fn with_executor(mut self, executor: Executor) -> PipelineConfig {
+        self.executor = executor;
+        self
+    }
+
+    //Replicas that adaptive stages may keep busy, together with the replicas
+    //of all other stages. One per core by default
+    pub fn thread_budget(&self) -> usize {
+        self.thread_budget.unwrap_or_else(num_cpus::get)
+    }
+
+    pub fn with_thread_budget(mut self, replicas: usize) -> PipelineConfig {
+        self.thread_budget = Some(replicas);
+        self
+    }
+
+    //How replica threads are pinned to cores, see Pipeline::placement.
+    //Unpinned by default, and with Executor::Tokio, whose replicas are tasks
+    pub fn affinity(&self) -> &Affinity {
+        &self.affinity
+    }
+
+    pub fn with_affinity(mut self, affinity: Affinity) -> PipelineConfig {
+        self.affinity = affinity;
+        self
+    }
+}
+
+//Public API: A running pipeline, made by pipeline! or PipelineBuilder::build.
+//Breaking change: earlier versions also took the output type of the first
+//stage, as Pipeline<TInput, TOutput, TCollected>. The first stage can now
+//be of any kind, including the sink, so only the input and collected types
+//are left
+pub struct Pipeline<TInput, TCollected> {
     signaled_end: bool,
-    initial_block: Option<InOutBlock<TInput, TOutput, TCollected>>,
+    //Shared with the source thread, see post_source
+    initial_block: Option<Arc<Box<dyn PipelineBlock<TInput, TCollected>>>>,
     monitors: Vec<MonitorLoop>,
-    threads: Vec<JoinHandle<()>>
+    //The source given last, which waits for the ones before it
+    source: Option<JoinHandle<Result<(), ItemPostError>>>,
+    sources: Arc<Mutex<Sources>>,
+    threads: Vec<MonitorHandle>,
+    context: Arc<PipelineContext>,
+    output: Arc<SinkOutput<TCollected>>,
+    topology: Topology,
+    placement: Placement,
+    //Only with Executor::Tokio. Dropped after the replica tasks are joined
+    runtime: Option<Runtime>
 }
 
-impl<TInput: 'static, TOutput: 'static, TCollected: 'static> Pipeline<TInput, TOutput, TCollected> 
-where
-    TInput: Send,
-    TInput: Sync {
-   
+impl<TInput: 'static, TCollected: 'static> Pipeline<TInput, TCollected> {
+
+    //Starts describing a pipeline stage by stage, see PipelineBuilder
+    pub fn builder() -> PipelineBuilder<TInput, TInput, TCollected>
+    where
+        TInput: Send,
+        TCollected: Send {
+        PipelineBuilder::new()
+    }
+
+    //Runs blocks made by SealedPipelineBuilder::create; `output` must be the
+    //one given to it for stream to see the outputs. Breaking change: this
+    //used to take the InOutBlock made by pipeline_propagate!
     pub fn new(
-        initial_block: InOutBlock<TInput, TOutput, TCollected>,
-        monitors: Vec<MonitorLoop>
-    ) -> Pipeline<TInput, TOutput, TCollected> {
+        initial_block: Box<dyn PipelineBlock<TInput, TCollected>>,
+        monitors: Vec<MonitorLoop>,
+        context: Arc<PipelineContext>,
+        output: Arc<SinkOutput<TCollected>>
+    ) -> Pipeline<TInput, TCollected> {
+        let topology = Topology::current();
+        let runtime = context.executor.runtime();
+        context.scaler.spawn_on(runtime.as_ref().map(Runtime::handle));
         Pipeline {
-            initial_block: Some(initial_block),
-            monitors: monitors,
+            initial_block: Some(Arc::new(initial_block)),
+            monitors,
+            source: None,
+            sources: Arc::new(Mutex::new(Sources::default())),
             threads: vec![],
-            signaled_end: false
+            signaled_end: false,
+            runtime,
+            placement: Placement {
+                affinity: context.affinity.clone(),
+                replicas: vec![]
+            },
+            topology,
+            context,
+            output
         }
     }
 
-    fn end(&mut self) {
-        self.signaled_end = true;
-        match &self.initial_block {
-            Some(block) => block.send_stop(),
-            None => {}
-        }
-    }
-
-    pub fn end_and_wait(&mut self) {
+    //Ends the stream and waits for all items to go through. Fails with the
+    //first error raised by a stage; the remaining items were dropped.
+    pub fn end_and_wait(&mut self) -> Result<(), PipelineError> {
         self.end();
-        let all_threads = std::mem::replace(&mut self.threads, vec![]);
-        for thread in all_threads {
-            thread.join().unwrap();
+        self.join_monitors();
+        match self.context.error() {
+            Some(error) => Err(error),
+            None => Ok(())
         }
     }
 
@@ -48,112 +171,720 @@ where
         if self.signaled_end {
             return Err(ItemPostError::StreamEnded);
         }
+        if self.context.is_cancelled() {
+            return Err(ItemPostError::Cancelled);
+        }
         match &self.initial_block {
             Some(block) => {
-                block.process(WorkItem::Value(item));
+                let posting = self.context.posting();
+                let order = block.process(WorkItem::Value(item));
+                self.context.item_posted(order, posting);
                 Ok(())
             }
             None => Err(ItemPostError::UnknownError)
         }
-        
     }
 
-    pub fn collect(mut self) -> Vec<TCollected> {
-        self.end_and_wait();
-
-        let current_block = std::mem::replace(&mut self.initial_block, None);
-        match current_block {
+    //Like post, but fails instead of blocking when the first block has a
+    //bounded queue that is full. The item is handed back on failure.
+    pub fn try_post(&self, item: TInput) -> Result<(), TryPostError<TInput>> {
+        if self.signaled_end {
+            return Err(TryPostError::StreamEnded(item));
+        }
+        if self.context.is_cancelled() {
+            return Err(TryPostError::Cancelled(item));
+        }
+        match &self.initial_block {
             Some(block) => {
-                Box::new(block).collect()     
+                let posting = self.context.posting();
+                let order = block.try_process(item).map_err(TryPostError::Full)?;
+                self.context.item_posted(order, posting);
+                Ok(())
+            }
+            None => Err(TryPostError::StreamEnded(item))
+        }
+    }
+
+    //None unless enabled with PipelineConfig::metrics. Replicas report their
+    //counters when they exit, so call this after end_and_wait.
+    pub fn metrics(&self) -> Option<PipelineMetrics> {
+        self.context.metrics.as_ref().map(|metrics| metrics.snapshot())
+    }
+
+    //None unless enabled with PipelineConfig::tracing. Replicas hand in their
+    //events when they exit, so call this after end_and_wait.
+    pub fn trace(&self) -> Option<PipelineTrace> {
+        self.context.trace.as_ref().map(|trace| trace.snapshot())
+    }
+
+    //Posts every item of `source` from a thread of its own, owned by the
+    //pipeline, and returns right away. Posting blocks while the first stage
+    //is full, so a bounded first stage throttles the source; it also stops
+    //early once a stage failed or the pipeline was cancelled, see
+    //wait_source. A source given while another one runs posts after it, and
+    //ending the pipeline lets the sources finish first. Shows up as
+    //PipelineMetrics::source.
+    //Dropping the pipeline also waits for the sources, so an endless one
+    //must be stopped with cancel first, or the drop never returns
+    pub fn post_source<I>(&mut self, source: I) -> Result<(), ItemPostError>
+    where
+        I: IntoIterator<Item = TInput>,
+        I::IntoIter: Send + 'static,
+        TInput: Send {
+        if self.signaled_end {
+            return Err(ItemPostError::StreamEnded);
+        }
+        if self.context.is_cancelled() {
+            return Err(ItemPostError::Cancelled);
+        }
+        let block = self.initial_block.clone().ok_or(ItemPostError::UnknownError)?;
+        let context = self.context.clone();
+        let previous = self.source.take();
+        self.sources.lock().running += 1;
+        let running = SourceRunning { sources: self.sources.clone(), block: block.clone() };
+        let mut items = source.into_iter();
+        let source = thread::Builder::new().name("rust-spp-source".to_string()).spawn(move || {
+            let _running = running;
+            if let Some(previous) = previous {
+                previous.join().expect("the source thread panicked")?;
+            }
+            let mut probe = context.source_probe();
+            loop {
+                if context.is_cancelled() {
+                    return Err(ItemPostError::Cancelled);
+                }
+                if context.has_failed() {
+                    return Err(ItemPostError::Failed);
+                }
+                probe.waited();
+                let Some(item) = items.next() else {
+                    return Ok(());
+                };
+                probe.processed();
+                let posting = context.posting();
+                context.item_posted(block.process(WorkItem::Value(item)), posting);
             }
-            None => vec![]
+        });
+        self.source = Some(source.expect("could not start the source thread"));
+        Ok(())
+    }
+
+    //Waits for the sources given to post_source. Fails when they stopped
+    //early, as the pipeline was cancelled or a stage failed; collect then
+    //gives the error of the stage
+    pub fn wait_source(&mut self) -> Result<(), ItemPostError> {
+        match self.source.take() {
+            Some(source) => source.join().expect("the source thread panicked"),
+            None => Ok(())
         }
     }
 
+    //Feeds the whole source, then ends the stream and collects
+    pub fn run_source<I>(mut self, source: I) -> Result<Vec<TCollected>, PipelineError>
+    where
+        I: IntoIterator<Item = TInput>,
+        I::IntoIter: Send + 'static,
+        TInput: Send {
+        //Only fails when the stream already ended or the pipeline was
+        //cancelled, which collect reports
+        let _ = self.post_source(source);
+        self.collect()
+    }
+
+    //The outputs of the last stage as soon as they are ready, instead of all
+    //at once from collect, which then returns nothing. Outputs collected so
+    //far come first. The stream ends after the pipeline ended and the last
+    //stage finished, so it can be drained on another thread while posting.
+    //Panics when called twice
+    pub fn stream(&self) -> OutputStream<TCollected> {
+        OutputStream::new(self.output.stream())
+    }
+
+    //Ends the pipeline and streams its outputs. A stage failure comes
+    //last, after the outputs that made it through
+    pub fn into_stream(mut self) -> PipelineStream<TInput, TCollected> {
+        let outputs = self.stream();
+        self.end();
+        PipelineStream::new(self, outputs)
+    }
+
+    pub fn collect(mut self) -> Result<Vec<TCollected>, PipelineError> {
+        self.end_and_wait()?;
+
+        match self.initial_block.take().map(Arc::try_unwrap) {
+            Some(Ok(block)) => Ok(block.collect()),
+            Some(Err(_)) => panic!("Could not unwrap Arc in call to collect"),
+            None => Ok(vec![])
+        }
+    }
+
+    //Replicas are started stage by stage, so pinned ones take the cores of
+    //the affinity policy in stage order
     pub fn start(&mut self) {
-        let monitors = std::mem::replace(&mut self.monitors, vec![]);
-        
+        let mut monitors = std::mem::take(&mut self.monitors);
+        monitors.sort_by_key(|monitor| monitor.stage().unwrap_or(usize::MAX));
+
+        let pinned = monitors.iter()
+            .filter(|monitor| monitor.stage().is_some() && monitor.needs_thread(self.runtime()))
+            .count();
+        let mut cores = self.context.affinity.cores(&self.topology, pinned).into_iter();
+        let mut replica = 0;
         for monitor in monitors {
-            self.threads.push(thread::spawn(move || {
-                monitor.run();
-            }))
+            let stage = monitor.stage();
+            let core = match stage {
+                Some(_) if monitor.needs_thread(self.runtime()) => cores.next().flatten(),
+                _ => None
+            };
+            let (thread, core) = monitor.spawn(self.runtime(), core);
+            self.threads.push(thread);
+            //Where the thread ended up, so a core the OS refused is not reported
+            if let Some(stage) = stage {
+                replica = match self.placement.replicas.last() {
+                    Some(last) if last.stage == stage => replica + 1,
+                    _ => 0
+                };
+                self.placement.replicas.push(ReplicaPlacement { stage, replica, core });
+            }
+        }
+        if let Some(metrics) = &self.context.metrics {
+            metrics.placed(self.placement.clone());
         }
     }
-}
 
-impl<TInput, TOutput, TCollected> Drop for Pipeline<TInput, TOutput, TCollected> {
-    fn drop(&mut self) {
+    //A handle that aborts the pipeline from anywhere, see CancelHandle
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle { context: self.context.clone() }
+    }
+
+    pub fn cancel(&self) {
+        self.context.cancel();
+    }
 
-        let block = std::mem::replace(&mut self.initial_block, None);
+    pub fn is_cancelled(&self) -> bool {
+        self.context.is_cancelled()
+    }
 
-        if !self.signaled_end {
-            block.unwrap().send_stop();
+    //Where the replicas were started, see PipelineConfig::with_affinity.
+    //Replicas that adaptive stages start while items flow are not pinned,
+    //and are left out
+    pub fn placement(&self) -> &Placement {
+        &self.placement
+    }
+}
+
+impl<TInput, TCollected> Pipeline<TInput, TCollected> {
+    //Running sources end the stream themselves once they are done, so that
+    //Stop comes after what they post
+    fn end(&mut self) {
+        if self.signaled_end {
+            return;
+        }
+        self.signaled_end = true;
+        let mut sources = self.sources.lock();
+        if sources.running > 0 {
+            sources.end_after = true;
+        } else if let Some(block) = &self.initial_block {
+            block.process(WorkItem::Stop);
         }
+    }
 
-        let all_threads = std::mem::replace(&mut self.threads, vec![]);
+    //Called from the thread that owns the pipeline, never from a task
+    fn join_monitors(&mut self) {
+        if let Some(source) = self.source.take() {
+            source.join().expect("the source thread panicked").ok();
+        }
+        let all_threads = std::mem::take(&mut self.threads);
         for thread in all_threads {
-            thread.join().unwrap();
+            thread.join(self.runtime());
         }
-    }   
+    }
+
+    fn runtime(&self) -> Option<&Handle> {
+        self.runtime.as_ref().map(Runtime::handle)
+    }
+}
+
+//Lets the running sources finish, see post_source
+impl<TInput, TCollected> Drop for Pipeline<TInput, TCollected> {
+    fn drop(&mut self) {
+        self.end();
+        self.join_monitors();
+    }
 }
 
+//Internals: Sources posting into a pipeline, see Pipeline::post_source.
+//When the pipeline was ended meanwhile, the last one to finish ends the
+//stream
+#[derive(Default)]
+struct Sources {
+    running: usize,
+    end_after: bool
+}
+
+//Internals: Held by a source thread until it finishes, even if its
+//iterator panics
+struct SourceRunning<TInput, TCollected> {
+    sources: Arc<Mutex<Sources>>,
+    block: Arc<Box<dyn PipelineBlock<TInput, TCollected>>>
+}
+
+impl<TInput, TCollected> Drop for SourceRunning<TInput, TCollected> {
+    fn drop(&mut self) {
+        let mut sources = self.sources.lock();
+        sources.running -= 1;
+        if sources.running == 0 && sources.end_after {
+            self.block.process(WorkItem::Stop);
+        }
+    }
+}
+
+
+/*
+ * Public API: Aborts a pipeline, e.g. to enforce a time limit. Can be cloned
+ * and used from any thread while the owner keeps posting or collecting.
+ *
+ * Once cancelled, posting fails with Cancelled, a running source stops and
+ * the stages drop every item still queued or in flight without running user
+ * code, so replicas reach the end of the stream as soon as they finish the
+ * item they hold. The pipeline still has to be ended: end_and_wait returns
+ * promptly, and collect returns the outputs that reached the last stage
+ * before the cancellation (in post order after collect_ordered!).
+ */
+#[derive(Clone)]
+pub struct CancelHandle {
+    context: Arc<PipelineContext>
+}
+
+impl CancelHandle {
+    pub fn cancel(&self) {
+        self.context.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.context.is_cancelled()
+    }
+
+    //Cancels after `timeout` from a thread of its own. Nothing happens if the
+    //pipeline is gone by then
+    pub fn cancel_after(&self, timeout: Duration) {
+        let handle = self.clone();
+        thread::Builder::new()
+            .name("rust-spp-timeout".to_string())
+            .spawn(move || {
+                thread::sleep(timeout);
+                handle.cancel();
+            })
+            .expect("could not start the timeout thread");
+    }
+}
 
 #[derive(Debug)]
 pub enum ItemPostError {
     StreamEnded,
+    //See CancelHandle
+    Cancelled,
+    //A stage failed, see Pipeline::collect for the error
+    Failed,
     UnknownError
 }
 
+pub enum TryPostError<T> {
+    Full(T),
+    StreamEnded(T),
+    Cancelled(T)
+}
+
+impl<T> TryPostError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            TryPostError::Full(item) => item,
+            TryPostError::StreamEnded(item) => item,
+            TryPostError::Cancelled(item) => item
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for TryPostError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TryPostError::Full(_) => write!(f, "Full(..)"),
+            TryPostError::StreamEnded(_) => write!(f, "StreamEnded(..)"),
+            TryPostError::Cancelled(_) => write!(f, "Cancelled(..)")
+        }
+    }
+}
+
+//Deprecated: Creates the blocks of the given stages, numbered from $index,
+//and adds their replicas to $threads, as pipeline! did before it expanded
+//to the builder. A Pipeline made from the returned block collects the
+//outputs of the last stage, but cannot stream them, see Pipeline::new
+#[deprecated(note = "build pipelines with pipeline! or Pipeline::builder")]
 #[macro_export]
 macro_rules! pipeline_propagate {
-    ($threads:expr, $s1:expr) => {
+    (@stages $threads:expr, $context:expr, $builder:expr; $sink:expr) => {
+        $builder.sink_stage($sink).create(&$context, &SinkOutput::new(), &mut $threads)
+    };
+    (@stages $threads:expr, $context:expr, $builder:expr; $stage:expr, $($tail:expr),+) => {
+        pipeline_propagate!(@stages $threads, $context, $builder.stage($stage); $($tail),+)
+    };
+    ($threads:expr, $context:expr, $index:expr, $($stages:expr),+) => {
+        pipeline_propagate!(@stages $threads, $context, Pipeline::builder().first_stage($index); $($stages),+)
+    };
+}
+
+#[macro_export]
+macro_rules! pipeline {
+    (config = $config:expr; $($stages:expr),+) => {
+        pipeline!(@stages Pipeline::builder().config($config); $($stages),+)
+    };
+    (@stages $builder:expr; $sink:expr) => {
+        $builder.sink_stage($sink).build()
+    };
+    (@stages $builder:expr; $stage:expr, $($tail:expr),+) => {
+        pipeline!(@stages $builder.stage($stage); $($tail),+)
+    };
+    ($($stages:expr),+) => {
+        pipeline!(config = PipelineConfig::default(); $($stages),+)
+    };
+}
+
+
+//Every stage macro also takes a label first, see InOutStage::label:
+//parallel!(label = "decode"; Decode, threads, bounded(8))
+#[macro_export]
+macro_rules! parallel {
+    (label = $label:expr; $($args:tt)+) => {
+        parallel!($($args)+).label($label)
+    };
+    ($block:expr, $threads:expr, bounded($capacity:expr)) => {
+        parallel!($block, $threads).bounded($capacity)
+    };
+    ($block:expr, $threads:expr, ring($capacity:expr)) => {
+        parallel!($block, $threads).ring($capacity)
+    };
+    ($block:expr, $threads:expr, work_stealing) => {
+        parallel!($block, $threads).work_stealing()
+    };
+    ($block:expr, $threads:expr, adaptive) => {
+        parallel!($block, $threads).adaptive()
+    };
+    ($block:expr, $threads:expr) => {
+        {
+            let factory: TransformerFactory<_,_> = Box::new(move || Box::new(FromInOut($block)));
+            InOutStage::new(BlockMode::Parallel($threads), factory)
+        }
+    };
+}
+
+
+#[macro_export]
+macro_rules! parallel_ordered {
+    (label = $label:expr; $($args:tt)+) => {
+        parallel_ordered!($($args)+).label($label)
+    };
+    ($block:expr, $threads:expr, bounded($capacity:expr)) => {
+        parallel_ordered!($block, $threads).bounded($capacity)
+    };
+    ($block:expr, $threads:expr, ring($capacity:expr)) => {
+        parallel_ordered!($block, $threads).ring($capacity)
+    };
+    ($block:expr, $threads:expr, reorder_window($window:expr)) => {
+        parallel_ordered!($block, $threads).reorder_window($window)
+    };
+    ($block:expr, $threads:expr, work_stealing) => {
+        parallel_ordered!($block, $threads).work_stealing()
+    };
+    ($block:expr, $threads:expr, adaptive) => {
+        parallel_ordered!($block, $threads).adaptive()
+    };
+    ($block:expr, $threads:expr) => {
         {
-            let (mode, factory) = $s1;
-            let mut block = InBlock::new(mode, factory);
-            $threads.push(block.monitor_posts());
-            block
+            let factory: TransformerFactory<_,_> = Box::new(move || Box::new(FromInOut($block)));
+            InOutStage::new(BlockMode::ParallelOrdered($threads), factory)
         }
     };
+}
+
+
+//Items whose keys are equal go to the same replica, so the node can keep
+//per-key state; see InOutStage::keyed
+#[macro_export]
+macro_rules! parallel_keyed {
+    (label = $label:expr; $($args:tt)+) => {
+        parallel_keyed!($($args)+).label($label)
+    };
+    ($block:expr, $threads:expr, $key:expr, bounded($capacity:expr)) => {
+        parallel_keyed!($block, $threads, $key).bounded($capacity)
+    };
+    ($block:expr, $threads:expr, $key:expr, ring($capacity:expr)) => {
+        parallel_keyed!($block, $threads, $key).ring($capacity)
+    };
+    ($block:expr, $threads:expr, $key:expr) => {
+        parallel!($block, $threads).keyed($key)
+    };
+}
 
-    ($threads:expr, $s1:expr $(, $tail:expr)*) => {
+
+#[macro_export]
+macro_rules! try_parallel {
+    (label = $label:expr; $($args:tt)+) => {
+        try_parallel!($($args)+).label($label)
+    };
+    ($block:expr, $threads:expr, bounded($capacity:expr)) => {
+        try_parallel!($block, $threads).bounded($capacity)
+    };
+    ($block:expr, $threads:expr, ring($capacity:expr)) => {
+        try_parallel!($block, $threads).ring($capacity)
+    };
+    ($block:expr, $threads:expr, work_stealing) => {
+        try_parallel!($block, $threads).work_stealing()
+    };
+    ($block:expr, $threads:expr, adaptive) => {
+        try_parallel!($block, $threads).adaptive()
+    };
+    ($block:expr, $threads:expr) => {
         {
-            let (mode, factory) = $s1;
-            let mut block = InOutBlock::new(
-                Box::new(pipeline_propagate!($threads, $($tail),*)),
-                mode, factory);
-            $threads.extend(block.monitor_posts());
-            block
+            let factory: TransformerFactory<_,_> = Box::new(move || Box::new(FromTryInOut($block)));
+            InOutStage::new(BlockMode::Parallel($threads), factory)
         }
     };
 }
 
 
 #[macro_export]
-macro_rules! pipeline {
-    ($s1:expr $(, $tail:expr)*) => {
+macro_rules! try_parallel_ordered {
+    (label = $label:expr; $($args:tt)+) => {
+        try_parallel_ordered!($($args)+).label($label)
+    };
+    ($block:expr, $threads:expr, bounded($capacity:expr)) => {
+        try_parallel_ordered!($block, $threads).bounded($capacity)
+    };
+    ($block:expr, $threads:expr, ring($capacity:expr)) => {
+        try_parallel_ordered!($block, $threads).ring($capacity)
+    };
+    ($block:expr, $threads:expr, reorder_window($window:expr)) => {
+        try_parallel_ordered!($block, $threads).reorder_window($window)
+    };
+    ($block:expr, $threads:expr, work_stealing) => {
+        try_parallel_ordered!($block, $threads).work_stealing()
+    };
+    ($block:expr, $threads:expr, adaptive) => {
+        try_parallel_ordered!($block, $threads).adaptive()
+    };
+    ($block:expr, $threads:expr) => {
+        {
+            let factory: TransformerFactory<_,_> = Box::new(move || Box::new(FromTryInOut($block)));
+            InOutStage::new(BlockMode::ParallelOrdered($threads), factory)
+        }
+    };
+}
+
+
+//Fan-out stages: the block returns any number of outputs, see InOutMany
+#[macro_export]
+macro_rules! parallel_many {
+    (label = $label:expr; $($args:tt)+) => {
+        parallel_many!($($args)+).label($label)
+    };
+    ($block:expr, $threads:expr, bounded($capacity:expr)) => {
+        parallel_many!($block, $threads).bounded($capacity)
+    };
+    ($block:expr, $threads:expr, ring($capacity:expr)) => {
+        parallel_many!($block, $threads).ring($capacity)
+    };
+    ($block:expr, $threads:expr, work_stealing) => {
+        parallel_many!($block, $threads).work_stealing()
+    };
+    ($block:expr, $threads:expr, adaptive) => {
+        parallel_many!($block, $threads).adaptive()
+    };
+    ($block:expr, $threads:expr) => {
         {
-            let mut monitors = Vec::<MonitorLoop>::new();
-            let (mode, factory) = $s1;
-            let mut block = InOutBlock::new(
-                Box::new(pipeline_propagate!(monitors, $($tail),*)),
-                mode, factory);
-            monitors.extend(block.monitor_posts());
+            let factory: TransformerFactory<_,_> = Box::new(move || Box::new(FromInOutMany($block)));
+            InOutManyStage::new(BlockMode::Parallel($threads), factory)
+        }
+    };
+}
+
 
-            let mut pipeline = Pipeline::new(block, monitors);
-            pipeline.start();
-            pipeline
+#[macro_export]
+macro_rules! parallel_many_ordered {
+    (label = $label:expr; $($args:tt)+) => {
+        parallel_many_ordered!($($args)+).label($label)
+    };
+    ($block:expr, $threads:expr, bounded($capacity:expr)) => {
+        parallel_many_ordered!($block, $threads).bounded($capacity)
+    };
+    ($block:expr, $threads:expr, ring($capacity:expr)) => {
+        parallel_many_ordered!($block, $threads).ring($capacity)
+    };
+    ($block:expr, $threads:expr, reorder_window($window:expr)) => {
+        parallel_many_ordered!($block, $threads).reorder_window($window)
+    };
+    ($block:expr, $threads:expr, work_stealing) => {
+        parallel_many_ordered!($block, $threads).work_stealing()
+    };
+    ($block:expr, $threads:expr, adaptive) => {
+        parallel_many_ordered!($block, $threads).adaptive()
+    };
+    ($block:expr, $threads:expr) => {
+        {
+            let factory: TransformerFactory<_,_> = Box::new(move || Box::new(FromInOutMany($block)));
+            InOutManyStage::new(BlockMode::ParallelOrdered($threads), factory)
         }
     };
 }
 
 
+//Gathers items into Vecs of up to $size items; see BatchBlock
 #[macro_export]
-macro_rules! parallel {
+macro_rules! batch {
+    (label = $label:expr; $($args:tt)+) => {
+        batch!($($args)+).label($label)
+    };
+    ($size:expr, $timeout:expr, bounded($capacity:expr)) => {
+        batch!($size, $timeout).bounded($capacity)
+    };
+    ($size:expr, $timeout:expr) => {
+        BatchStage::new($size, $timeout)
+    };
+}
+
+
+//Hands items to branches, each a list of InOut stages: every branch gets a
+//copy with broadcast, by_key picks one by the hash of any Hash key, like
+//parallel_keyed!. See SplitStage and merge!
+//split!(broadcast; [parallel!(DetectFaces, 4)], [parallel!(Histogram::new(), 1)])
+#[macro_export]
+macro_rules! split {
+    (broadcast; $([$($stages:expr),+ $(,)?]),+ $(,)?) => {
+        SplitStage::new(Route::broadcast(), vec![$(Branch::new()$(.stage($stages))+),+])
+    };
+    (by_key($key:expr); $([$($stages:expr),+ $(,)?]),+ $(,)?) => {
+        SplitStage::new(Route::by_key($key), vec![$(Branch::new()$(.stage($stages))+),+])
+    };
+}
+
+
+//Puts the outputs of each item of a split! back together, in the order
+//the items reached the split; see Merge
+#[macro_export]
+macro_rules! merge {
+    (label = $label:expr) => {
+        merge!().label($label)
+    };
+    () => {
+        MergeStage::new()
+    };
+}
+
+
+//Async stages: the block returns a future, see AsyncInOut and TryAsyncInOut
+#[macro_export]
+macro_rules! async_parallel {
+    (label = $label:expr; $($args:tt)+) => {
+        async_parallel!($($args)+).label($label)
+    };
+    ($block:expr, $threads:expr, bounded($capacity:expr)) => {
+        async_parallel!($block, $threads).bounded($capacity)
+    };
+    ($block:expr, $threads:expr, ring($capacity:expr)) => {
+        async_parallel!($block, $threads).ring($capacity)
+    };
+    ($block:expr, $threads:expr, work_stealing) => {
+        async_parallel!($block, $threads).work_stealing()
+    };
+    ($block:expr, $threads:expr, adaptive) => {
+        async_parallel!($block, $threads).adaptive()
+    };
+    ($block:expr, $threads:expr) => {
+        {
+            let factory: TransformerFactory<_,_> = Box::new(move || Box::new(FromAsyncInOut($block)));
+            InOutStage::new(BlockMode::Parallel($threads), factory)
+        }
+    };
+}
+
+
+#[macro_export]
+macro_rules! async_parallel_ordered {
+    (label = $label:expr; $($args:tt)+) => {
+        async_parallel_ordered!($($args)+).label($label)
+    };
+    ($block:expr, $threads:expr, bounded($capacity:expr)) => {
+        async_parallel_ordered!($block, $threads).bounded($capacity)
+    };
+    ($block:expr, $threads:expr, ring($capacity:expr)) => {
+        async_parallel_ordered!($block, $threads).ring($capacity)
+    };
+    ($block:expr, $threads:expr, reorder_window($window:expr)) => {
+        async_parallel_ordered!($block, $threads).reorder_window($window)
+    };
+    ($block:expr, $threads:expr, work_stealing) => {
+        async_parallel_ordered!($block, $threads).work_stealing()
+    };
+    ($block:expr, $threads:expr, adaptive) => {
+        async_parallel_ordered!($block, $threads).adaptive()
+    };
+    ($block:expr, $threads:expr) => {
+        {
+            let factory: TransformerFactory<_,_> = Box::new(move || Box::new(FromAsyncInOut($block)));
+            InOutStage::new(BlockMode::ParallelOrdered($threads), factory)
+        }
+    };
+}
+
+
+#[macro_export]
+macro_rules! try_async_parallel {
+    (label = $label:expr; $($args:tt)+) => {
+        try_async_parallel!($($args)+).label($label)
+    };
+    ($block:expr, $threads:expr, bounded($capacity:expr)) => {
+        try_async_parallel!($block, $threads).bounded($capacity)
+    };
+    ($block:expr, $threads:expr, ring($capacity:expr)) => {
+        try_async_parallel!($block, $threads).ring($capacity)
+    };
+    ($block:expr, $threads:expr, work_stealing) => {
+        try_async_parallel!($block, $threads).work_stealing()
+    };
+    ($block:expr, $threads:expr, adaptive) => {
+        try_async_parallel!($block, $threads).adaptive()
+    };
+    ($block:expr, $threads:expr) => {
+        {
+            let factory: TransformerFactory<_,_> = Box::new(move || Box::new(FromTryAsyncInOut($block)));
+            InOutStage::new(BlockMode::Parallel($threads), factory)
+        }
+    };
+}
+
+
+#[macro_export]
+macro_rules! try_async_parallel_ordered {
+    (label = $label:expr; $($args:tt)+) => {
+        try_async_parallel_ordered!($($args)+).label($label)
+    };
+    ($block:expr, $threads:expr, bounded($capacity:expr)) => {
+        try_async_parallel_ordered!($block, $threads).bounded($capacity)
+    };
+    ($block:expr, $threads:expr, ring($capacity:expr)) => {
+        try_async_parallel_ordered!($block, $threads).ring($capacity)
+    };
+    ($block:expr, $threads:expr, reorder_window($window:expr)) => {
+        try_async_parallel_ordered!($block, $threads).reorder_window($window)
+    };
+    ($block:expr, $threads:expr, work_stealing) => {
+        try_async_parallel_ordered!($block, $threads).work_stealing()
+    };
+    ($block:expr, $threads:expr, adaptive) => {
+        try_async_parallel_ordered!($block, $threads).adaptive()
+    };
     ($block:expr, $threads:expr) => {
         {
-            let mode = BlockMode::Parallel($threads);
-            let factory: Box<FnMut() -> Box<InOut<_,_>>> = Box::new(move || Box::new($block));
-            (mode, factory)
+            let factory: TransformerFactory<_,_> = Box::new(move || Box::new(FromTryAsyncInOut($block)));
+            InOutStage::new(BlockMode::ParallelOrdered($threads), factory)
         }
     };
 }
@@ -161,22 +892,85 @@ macro_rules! parallel {
 
 #[macro_export]
 macro_rules! sequential {
+    (label = $label:expr; $($args:tt)+) => {
+        sequential!($($args)+).label($label)
+    };
+    ($block:expr, bounded($capacity:expr)) => {
+        sequential!($block).bounded($capacity)
+    };
+    ($block:expr, ring($capacity:expr)) => {
+        sequential!($block).ring($capacity)
+    };
     ($block:expr) => {
         {
-            let mode = BlockMode::Sequential(OrderingMode::Unordered);
-            let factory: Box<FnMut() -> Box<In<_, _>>> = Box::new(move || Box::new($block));
-            (mode, factory)
+            let factory: HandlerFactory<_, _> = Box::new(move || Box::new($block));
+            InStage::new(BlockMode::Sequential(OrderingMode::Unordered), factory)
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! parallel_sink {
+    (label = $label:expr; $($args:tt)+) => {
+        parallel_sink!($($args)+).label($label)
+    };
+    ($block:expr, $threads:expr, bounded($capacity:expr)) => {
+        parallel_sink!($block, $threads).bounded($capacity)
+    };
+    ($block:expr, $threads:expr, ring($capacity:expr)) => {
+        parallel_sink!($block, $threads).ring($capacity)
+    };
+    ($block:expr, $threads:expr, work_stealing) => {
+        parallel_sink!($block, $threads).work_stealing()
+    };
+    ($block:expr, $threads:expr) => {
+        {
+            let factory: HandlerFactory<_, _> = Box::new(move || Box::new($block));
+            InStage::new(BlockMode::Parallel($threads), factory)
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! parallel_sink_ordered {
+    (label = $label:expr; $($args:tt)+) => {
+        parallel_sink_ordered!($($args)+).label($label)
+    };
+    ($block:expr, $threads:expr, reorder_window($window:expr)) => {
+        parallel_sink_ordered!($block, $threads).reorder_window($window)
+    };
+    ($block:expr, $threads:expr, bounded($capacity:expr)) => {
+        parallel_sink_ordered!($block, $threads).bounded($capacity)
+    };
+    ($block:expr, $threads:expr, ring($capacity:expr)) => {
+        parallel_sink_ordered!($block, $threads).ring($capacity)
+    };
+    ($block:expr, $threads:expr, work_stealing) => {
+        parallel_sink_ordered!($block, $threads).work_stealing()
+    };
+    ($block:expr, $threads:expr) => {
+        {
+            let factory: HandlerFactory<_, _> = Box::new(move || Box::new($block));
+            InStage::new(BlockMode::ParallelOrdered($threads), factory)
         }
     };
 }
 
 #[macro_export]
 macro_rules! sequential_ordered {
+    (label = $label:expr; $($args:tt)+) => {
+        sequential_ordered!($($args)+).label($label)
+    };
+    ($block:expr, bounded($capacity:expr)) => {
+        sequential_ordered!($block).bounded($capacity)
+    };
+    ($block:expr, reorder_window($window:expr)) => {
+        sequential_ordered!($block).reorder_window($window)
+    };
     ($block:expr) => {
         {
-            let mode = BlockMode::Sequential(OrderingMode::Ordered);
-            let factory: Box<FnMut() -> Box<In<_, _>>> = Box::new(move || Box::new($block));
-            (mode, factory)
+            let factory: HandlerFactory<_, _> = Box::new(move || Box::new($block));
+            InStage::new(BlockMode::Sequential(OrderingMode::Ordered), factory)
         }
     };
 }
@@ -184,6 +978,9 @@ macro_rules! sequential_ordered {
 
 #[macro_export]
 macro_rules! collect {
+    (label = $label:expr) => {
+        collect!().label($label)
+    };
     () => {
         {
             sequential!(move |item: _| {item})
@@ -194,9 +991,18 @@ macro_rules! collect {
 
 #[macro_export]
 macro_rules! collect_ordered {
+    (label = $label:expr) => {
+        collect_ordered!().label($label)
+    };
+    (bounded($capacity:expr)) => {
+        collect_ordered!().bounded($capacity)
+    };
+    (reorder_window($window:expr)) => {
+        collect_ordered!().reorder_window($window)
+    };
     () => {
         {
             sequential_ordered!(move |item: _| {item})
         }
     };
-}
\ No newline at end of file
+}
@@ -0,0 +1,51 @@
+use std::sync::mpsc::Receiver;
+use crate::blocks::PipelineError;
+use crate::spp::Pipeline;
+
+//Public API: The outputs of the last stage, as they are produced. Made by
+//Pipeline::stream; ends once the pipeline ended
+pub struct OutputStream<T> {
+    receiver: Receiver<T>
+}
+
+impl<T> OutputStream<T> {
+    pub fn new(receiver: Receiver<T>) -> OutputStream<T> {
+        OutputStream { receiver }
+    }
+}
+
+impl<T> Iterator for OutputStream<T> {
+    type Item = T;
+
+    //Blocks until the next output is ready
+    fn next(&mut self) -> Option<T> {
+        self.receiver.recv().ok()
+    }
+}
+
+//Public API: The outputs of an ended pipeline, made by Pipeline::into_stream.
+//Owns the pipeline, which is waited for once the outputs run out
+pub struct PipelineStream<TInput, TCollected> {
+    pipeline: Option<Pipeline<TInput, TCollected>>,
+    outputs: OutputStream<TCollected>
+}
+
+impl<TInput, TCollected> PipelineStream<TInput, TCollected> {
+    pub fn new(pipeline: Pipeline<TInput, TCollected>, outputs: OutputStream<TCollected>) -> PipelineStream<TInput, TCollected> {
+        PipelineStream {
+            pipeline: Some(pipeline),
+            outputs
+        }
+    }
+}
+
+impl<TInput: 'static, TCollected: 'static> Iterator for PipelineStream<TInput, TCollected> {
+    type Item = Result<TCollected, PipelineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.outputs.next() {
+            Some(output) => Some(Ok(output)),
+            None => self.pipeline.take()?.end_and_wait().err().map(Err)
+        }
+    }
+}
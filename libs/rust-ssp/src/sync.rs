@@ -0,0 +1,105 @@
+/*
+ * Locks and atomics shared by the stage queues and the replicas of a block.
+ *
+ * Normally these are parking_lot and std. Built with `--cfg spp_loom`, they
+ * come from loom instead, so tests/loom.rs can explore the interleavings of
+ * the queues and of the end of the stream. Loom has the std API, wrapped
+ * here in the parking_lot one, and no clock: timed waits time out right
+ * away, which is one of the outcomes they allow anyway.
+ *
+ * The cfg is not called `loom` because tokio has code of its own under that
+ * one, which only builds in its own tests.
+ */
+
+#[cfg(not(spp_loom))]
+pub use parking_lot::{Condvar, Mutex, MutexGuard};
+#[cfg(not(spp_loom))]
+pub use std::sync::atomic;
+
+#[cfg(spp_loom)]
+pub use loom::sync::atomic;
+#[cfg(spp_loom)]
+pub use self::model::{Condvar, Mutex, MutexGuard};
+
+#[cfg(spp_loom)]
+mod model {
+    use std::ops::{Deref, DerefMut};
+    use std::time::Instant;
+
+    pub struct Mutex<T>(loom::sync::Mutex<T>);
+
+    //Only empty while a Condvar waits with the lock
+    pub struct MutexGuard<'a, T>(Option<loom::sync::MutexGuard<'a, T>>);
+
+    pub struct Condvar(loom::sync::Condvar);
+
+    pub struct WaitTimeoutResult;
+
+    impl<T> Mutex<T> {
+        pub fn new(value: T) -> Mutex<T> {
+            Mutex(loom::sync::Mutex::new(value))
+        }
+
+        pub fn lock(&self) -> MutexGuard<'_, T> {
+            MutexGuard(Some(self.0.lock().unwrap()))
+        }
+
+        pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+            self.0.try_lock().ok().map(|locked| MutexGuard(Some(locked)))
+        }
+
+        pub fn get_mut(&mut self) -> &mut T {
+            self.0.get_mut().unwrap()
+        }
+    }
+
+    impl<T> Deref for MutexGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            self.0.as_ref().unwrap()
+        }
+    }
+
+    impl<T> DerefMut for MutexGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            self.0.as_mut().unwrap()
+        }
+    }
+
+    impl WaitTimeoutResult {
+        pub fn timed_out(&self) -> bool {
+            true
+        }
+    }
+
+    impl Condvar {
+        pub fn new() -> Condvar {
+            Condvar(loom::sync::Condvar::new())
+        }
+
+        pub fn wait<T>(&self, guard: &mut MutexGuard<'_, T>) {
+            let locked = guard.0.take().unwrap();
+            guard.0 = Some(self.0.wait(locked).unwrap());
+        }
+
+        pub fn wait_until<T>(&self, _guard: &mut MutexGuard<'_, T>, _deadline: Instant) -> WaitTimeoutResult {
+            loom::thread::yield_now();
+            WaitTimeoutResult
+        }
+
+        pub fn notify_one(&self) {
+            self.0.notify_one();
+        }
+
+        pub fn notify_all(&self) {
+            self.0.notify_all();
+        }
+    }
+
+    impl Default for Condvar {
+        fn default() -> Condvar {
+            Condvar::new()
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+//Public API: What happened to an item, see PipelineConfig::tracing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEventKind {
+    //The item was put in the input queue of the stage
+    Enqueued,
+    //A replica took it out of the queue
+    Dequeued,
+    //The replica started and finished running the stage code on it
+    Started,
+    Finished,
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceEvent {
+    pub stage: usize,
+    //None for enqueue events, which happen on the thread of the producer
+    pub replica: Option<usize>,
+    //Order of the item at the stage
+    pub order: u64,
+    pub kind: TraceEventKind,
+    //Since the pipeline was built
+    pub at: Duration,
+}
+
+//Public API: The events recorded by a pipeline, see Pipeline::trace
+#[derive(Debug, Clone)]
+pub struct PipelineTrace {
+    pub events: Vec<TraceEvent>,
+    //Name of each stage, and of each of its replicas, as threads are named
+    pub stages: BTreeMap<usize, String>,
+    pub replicas: BTreeMap<(usize, usize), String>,
+}
+
+impl PipelineTrace {
+    /*
+     * Chrome Trace Event JSON, as loaded by Perfetto (ui.perfetto.dev) or
+     * chrome://tracing. Every replica is a thread of its own, with the time
+     * it spent on each item as a slice named after the order of the item.
+     * Dequeues are instant events on the thread of the replica, and enqueues
+     * on a "queue" thread of their own, listed before the replicas of the
+     * stage.
+     */
+    pub fn to_chrome_json(&self) -> String {
+        let mut events = vec![json!({
+            "name": "process_name", "ph": "M", "pid": 1,
+            "args": { "name": "rust-spp pipeline" }
+        })];
+        for (stage, name) in &self.stages {
+            events.extend(thread_metadata(queue_track(*stage), &format!("{} queue", name)));
+        }
+        for ((stage, replica), name) in &self.replicas {
+            events.extend(thread_metadata(replica_track(*stage, *replica), name));
+        }
+        for event in &self.events {
+            let tid = match event.replica {
+                Some(replica) => replica_track(event.stage, replica),
+                None => queue_track(event.stage)
+            };
+            let ts = event.at.as_secs_f64() * 1e6;
+            events.push(match event.kind {
+                TraceEventKind::Enqueued | TraceEventKind::Dequeued => json!({
+                    "name": if event.kind == TraceEventKind::Enqueued { "enqueue" } else { "dequeue" },
+                    "cat": "queue", "ph": "i", "s": "t", "ts": ts, "pid": 1, "tid": tid,
+                    "args": { "order": event.order }
+                }),
+                TraceEventKind::Started | TraceEventKind::Finished => json!({
+                    "name": format!("item {}", event.order),
+                    "cat": "process", "ph": if event.kind == TraceEventKind::Started { "B" } else { "E" },
+                    "ts": ts, "pid": 1, "tid": tid,
+                    "args": { "order": event.order }
+                }),
+            });
+        }
+        serde_json::to_string(&json!({ "traceEvents": events, "displayTimeUnit": "ms" }))
+            .expect("traces are always serializable")
+    }
+}
+
+//Thread ids in the exported trace, so the tracks of a stage stay together
+fn queue_track(stage: usize) -> usize {
+    stage * 1000
+}
+
+fn replica_track(stage: usize, replica: usize) -> usize {
+    stage * 1000 + replica + 1
+}
+
+fn thread_metadata(tid: usize, name: &str) -> [Value; 2] {
+    [
+        json!({ "name": "thread_name", "ph": "M", "pid": 1, "tid": tid, "args": { "name": name } }),
+        json!({ "name": "thread_sort_index", "ph": "M", "pid": 1, "tid": tid, "args": { "sort_index": tid } }),
+    ]
+}
+
+//Internals: Collects the events of one pipeline. Replicas keep theirs until
+//they exit; enqueues happen on any thread, so they go to the shared list
+//right away
+pub struct TraceCollector {
+    started: Instant,
+    events: Arc<Mutex<Vec<TraceEvent>>>,
+    stages: Mutex<BTreeMap<usize, String>>,
+    replicas: Mutex<BTreeMap<(usize, usize), String>>,
+}
+
+impl TraceCollector {
+    pub fn new() -> TraceCollector {
+        TraceCollector {
+            started: Instant::now(),
+            events: Arc::new(Mutex::new(vec![])),
+            stages: Mutex::new(BTreeMap::new()),
+            replicas: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn stage(&self, stage: usize, name: String) {
+        self.stages.lock().insert(stage, name);
+    }
+
+    pub fn enqueue_tracer(&self, stage: usize) -> EnqueueTracer {
+        EnqueueTracer {
+            recorder: Some(EnqueueState {
+                started: self.started,
+                events: self.events.clone(),
+                stage,
+            })
+        }
+    }
+
+    pub fn replica_tracer(&self, stage: usize, replica: usize, name: String) -> ReplicaTracer {
+        self.replicas.lock().insert((stage, replica), name);
+        ReplicaTracer {
+            recorder: Some(TracerState {
+                started: self.started,
+                events: self.events.clone(),
+                stage,
+                replica,
+                recorded: vec![],
+            })
+        }
+    }
+
+    pub fn snapshot(&self) -> PipelineTrace {
+        let mut events = self.events.lock().clone();
+        events.sort_by_key(|event| event.at);
+        PipelineTrace {
+            events,
+            stages: self.stages.lock().clone(),
+            replicas: self.replicas.lock().clone(),
+        }
+    }
+}
+
+impl Default for TraceCollector {
+    fn default() -> Self {
+        TraceCollector::new()
+    }
+}
+
+//Internals: Traces the items put in the input queue of a stage, by whichever
+//thread does. Does nothing when tracing is disabled
+#[derive(Clone)]
+pub struct EnqueueTracer {
+    recorder: Option<EnqueueState>,
+}
+
+#[derive(Clone)]
+struct EnqueueState {
+    started: Instant,
+    events: Arc<Mutex<Vec<TraceEvent>>>,
+    stage: usize,
+}
+
+impl EnqueueTracer {
+    pub fn disabled() -> EnqueueTracer {
+        EnqueueTracer { recorder: None }
+    }
+
+    //Runs `enqueue`, then records the order `order` finds in its result, if
+    //any, as of when `enqueue` started: a replica may take the item before
+    //it returns
+    pub fn enqueue<T, F, O>(&self, enqueue: F, order: O) -> T
+    where
+        F: FnOnce() -> T,
+        O: FnOnce(&T) -> Option<u64> {
+        match &self.recorder {
+            None => enqueue(),
+            Some(state) => {
+                let at = state.started.elapsed();
+                let result = enqueue();
+                if let Some(order) = order(&result) {
+                    state.events.lock().push(TraceEvent {
+                        stage: state.stage,
+                        replica: None,
+                        order,
+                        kind: TraceEventKind::Enqueued,
+                        at,
+                    });
+                }
+                result
+            }
+        }
+    }
+}
+
+//Internals: Per replica event list. Does nothing when tracing is disabled
+pub struct ReplicaTracer {
+    recorder: Option<TracerState>,
+}
+
+struct TracerState {
+    started: Instant,
+    events: Arc<Mutex<Vec<TraceEvent>>>,
+    stage: usize,
+    replica: usize,
+    recorded: Vec<TraceEvent>,
+}
+
+impl ReplicaTracer {
+    pub fn disabled() -> ReplicaTracer {
+        ReplicaTracer { recorder: None }
+    }
+
+    pub fn record(&mut self, kind: TraceEventKind, order: u64) {
+        if let Some(state) = &mut self.recorder {
+            state.recorded.push(TraceEvent {
+                stage: state.stage,
+                replica: Some(state.replica),
+                order,
+                kind,
+                at: state.started.elapsed(),
+            });
+        }
+    }
+}
+
+impl Drop for ReplicaTracer {
+    fn drop(&mut self) {
+        if let Some(state) = self.recorder.take() {
+            state.events.lock().extend(state.recorded);
+        }
+    }
+}
@@ -1,42 +1,153 @@
+use crate::executor;
 use crate::work_storage::*;
 use std::collections::BTreeMap;
 use std::sync::{Arc};
-use parking_lot::{Mutex, Condvar};
+use crate::sync::{Mutex, MutexGuard, Condvar};
+use tokio1::sync::Notify;
 
+/*
+ * Storage in front of an ordered sink. Items arrive in any order and the
+ * single consumer removes them by order, starting at 0.
+ *
+ * With a window of N, only the orders from the next one to remove up to
+ * N - 1 past it are taken in: producers with later items block until the
+ * consumer catches up, so a slow item holds back the previous stages instead
+ * of letting the storage grow to the rest of the stream. The item the
+ * consumer waits for is always inside the window, so this cannot block
+ * forever as long as the items reach the previous stage in order, see
+ * InStage::reorder_window.
+ *
+ * The consumer is only woken up by the item it waits for, and producers by
+ * the consumer moving the window.
+ */
 pub struct BlockingOrderedSet<T> {
-    storage: Mutex<BTreeMap<u64, TimestampedWorkItem<T>>>,
-    new_item_notifier: Condvar,
+    storage: Mutex<OrderedStorage<T>>,
+    next_arrived: Condvar,
+    room: Condvar,
+    window: Option<usize>,
+    //Same as next_arrived, for a consumer running as an async task
+    next_arrived_notify: Notify,
+}
+
+struct OrderedStorage<T> {
+    items: BTreeMap<u64, TimestampedWorkItem<T>>,
+    //Order the consumer waits for
+    next: u64,
+    //Most items held at once
+    high_water: usize,
+}
+
+impl<T> OrderedStorage<T> {
+    fn fits(&self, order: u64, window: Option<usize>) -> bool {
+        match window {
+            Some(window) => order < self.next + window as u64,
+            None => true
+        }
+    }
 }
 
 impl<T> BlockingOrderedSet<T> {
     pub fn new() -> Arc<BlockingOrderedSet<T>> {
+        BlockingOrderedSet::with_window(None)
+    }
+
+    pub fn windowed(window: usize) -> Arc<BlockingOrderedSet<T>> {
+        assert!(window > 0, "reorder window must be greater than zero");
+        BlockingOrderedSet::with_window(Some(window))
+    }
+
+    fn with_window(window: Option<usize>) -> Arc<BlockingOrderedSet<T>> {
         Arc::new(BlockingOrderedSet {
-            storage: Mutex::new(BTreeMap::<u64, TimestampedWorkItem<T>>::new()),
-            new_item_notifier: Condvar::new(),
+            storage: Mutex::new(OrderedStorage {
+                items: BTreeMap::new(),
+                next: 0,
+                high_water: 0,
+            }),
+            next_arrived: Condvar::new(),
+            room: Condvar::new(),
+            window,
+            next_arrived_notify: Notify::new(),
         })
     }
 
+    pub fn window(&self) -> Option<usize> {
+        self.window
+    }
+
     pub fn enqueue(&self, item: TimestampedWorkItem<T>) {
-        let mut queue = self.storage.lock();
-        match item {
-            TimestampedWorkItem(_, order) => queue.insert(order, item)
-        };
-        self.new_item_notifier.notify_one();
+        let mut storage = self.storage.lock();
+        self.wait_for_room(&mut storage, item.1);
+        self.insert(&mut storage, item);
+    }
+
+    //Whether enqueue would take the order without waiting. Once true, it
+    //stays true, since the window only moves forward
+    pub fn fits(&self, order: u64) -> bool {
+        self.storage.lock().fits(order, self.window)
+    }
+
+    fn insert(&self, storage: &mut OrderedStorage<T>, item: TimestampedWorkItem<T>) {
+        let order = item.1;
+        storage.items.insert(order, item);
+        storage.high_water = storage.high_water.max(storage.items.len());
+        if order == storage.next {
+            self.next_arrived.notify_one();
+            self.next_arrived_notify.notify_one();
+        }
+    }
+
+    //Producers may run as tasks, see executor::park
+    fn wait_for_room(&self, storage: &mut MutexGuard<OrderedStorage<T>>, order: u64) {
+        while !storage.fits(order, self.window) {
+            executor::park(|| self.room.wait(storage));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.storage.lock().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.storage.lock().items.is_empty()
+    }
+
+    pub fn high_water(&self) -> usize {
+        self.storage.lock().high_water
+    }
+
+    //Producers may be waiting for different orders, so all of them check
+    fn removed(&self, storage: &mut OrderedStorage<T>, item: u64) -> Option<TimestampedWorkItem<T>> {
+        let removed = storage.items.remove(&item)?;
+        storage.next = item + 1;
+        if self.window.is_some() {
+            self.room.notify_all();
+        }
+        Some(removed)
     }
 
     pub fn wait_and_remove(&self, item: u64) -> TimestampedWorkItem<T> {
         let mut storage = self.storage.lock();
-        while (*storage).is_empty() || !(*storage).contains_key(&item) {
-            self.new_item_notifier.wait(&mut storage);
+        storage.next = item;
+        loop {
+            if let Some(value) = self.removed(&mut storage, item) {
+                return value;
+            }
+            self.next_arrived.wait(&mut storage);
         }
-        let removed_item = storage.remove(&item);
+    }
 
-        match removed_item {
-            Some(value) => return value,
-            None => { panic!("Condition variable waited until item was found, but removal failed") }
+    pub async fn remove(&self, item: u64) -> TimestampedWorkItem<T> {
+        loop {
+            let mut notified = std::pin::pin!(self.next_arrived_notify.notified());
+            notified.as_mut().enable();
+            {
+                let mut storage = self.storage.lock();
+                storage.next = item;
+                if let Some(value) = self.removed(&mut storage, item) {
+                    return value;
+                }
+            }
+            notified.await;
         }
     }
 }
-
-unsafe impl<T> Send for BlockingOrderedSet<T> {}
-unsafe impl<T> Sync for BlockingOrderedSet<T> {}
@@ -1,66 +1,211 @@
-
 use std::collections::VecDeque;
 use std::sync::{Arc};
-use parking_lot::{Mutex, Condvar};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::sync::{Mutex, MutexGuard, Condvar};
+use crate::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Instant;
+use tokio1::sync::Notify;
+use crate::executor;
 use crate::work_storage::*;
 
 
 /*
  * Thread-safe queue for storing work items. Each enqueued item gets a timestamp
- * tag.
+ * tag. When created with a capacity, producers block (or fail, for try_enqueue)
+ * while the queue is full, applying backpressure to the previous stage.
+ *
+ * WorkItem::Stop is always the last item of a stream and is never removed:
+ * once it reaches the front, every consumer dequeues a copy of it. This lets
+ * all replicas of a block observe the end of the stream exactly once without
+ * anyone re-enqueueing it.
+ *
+ * Consumers running as async tasks wait on a Notify instead of the condition
+ * variable. Producers only pay for it once such a consumer showed up.
  */
 pub struct BlockingQueue<T> {
     queue: (Mutex<VecDeque<TimestampedWorkItem<T>>>, Condvar),
-    number_of_inserts: AtomicUsize
+    not_full: Condvar,
+    capacity: Option<usize>,
+    number_of_inserts: AtomicUsize,
+    item_available: Notify,
+    async_consumers: AtomicBool
 }
 
 impl<T> BlockingQueue<T> {
 
     pub fn new() -> Arc<BlockingQueue<T>> {
+        BlockingQueue::with_capacity(None)
+    }
+
+    pub fn bounded(capacity: usize) -> Arc<BlockingQueue<T>> {
+        assert!(capacity > 0, "bounded queue capacity must be greater than zero");
+        BlockingQueue::with_capacity(Some(capacity))
+    }
+
+    fn with_capacity(capacity: Option<usize>) -> Arc<BlockingQueue<T>> {
         Arc::new(BlockingQueue {
-            queue: (Mutex::new(VecDeque::<TimestampedWorkItem<T>>::new()), 
+            queue: (Mutex::new(VecDeque::<TimestampedWorkItem<T>>::new()),
                     Condvar::new()),
-            number_of_inserts: AtomicUsize::new(0)
+            not_full: Condvar::new(),
+            capacity,
+            number_of_inserts: AtomicUsize::new(0),
+            item_available: Notify::new(),
+            async_consumers: AtomicBool::new(false)
         })
     }
 
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    fn is_full(&self, queue: &VecDeque<TimestampedWorkItem<T>>) -> bool {
+        match self.capacity {
+            Some(capacity) => queue.len() >= capacity,
+            None => false
+        }
+    }
+
     pub fn enqueue(&self, item: WorkItem<T>) -> u64 {
         let (mutex, cvar) = &self.queue;
         let mut queue = mutex.lock();
+        self.wait_until_not_full(&mut queue);
+        let current = self.number_of_inserts.load(Ordering::SeqCst);
+
+        let is_stop = matches!(item, WorkItem::Stop);
+        queue.push_back(
+            TimestampedWorkItem(item, current as u64));
+
+        self.number_of_inserts.store(current + 1, Ordering::SeqCst);
+
+        self.notify(cvar, is_stop);
+        current as u64
+    }
+
+    //Same as enqueue, but gives the item back instead of blocking when full
+    pub fn try_enqueue(&self, item: WorkItem<T>) -> Result<u64, WorkItem<T>> {
+        let (mutex, cvar) = &self.queue;
+        let mut queue = mutex.lock();
+        if self.is_full(&queue) {
+            return Err(item);
+        }
         let current = self.number_of_inserts.load(Ordering::SeqCst);
-       
+
+        let is_stop = matches!(item, WorkItem::Stop);
         queue.push_back(
             TimestampedWorkItem(item, current as u64));
-        
+
         self.number_of_inserts.store(current + 1, Ordering::SeqCst);
 
-        cvar.notify_one();
-        return current as u64;
+        self.notify(cvar, is_stop);
+        Ok(current as u64)
     }
 
     pub fn enqueue_timestamped(&self, item: TimestampedWorkItem<T>) {
         let (mutex, cvar) = &self.queue;
-        mutex.lock().push_back(item);
-        cvar.notify_one();
+        let mut queue = mutex.lock();
+        self.wait_until_not_full(&mut queue);
+        let is_stop = matches!(item, TimestampedWorkItem(WorkItem::Stop, _));
+        queue.push_back(item);
+        self.notify(cvar, is_stop);
+    }
+
+    //Same as enqueue_timestamped, but gives the item back when full
+    pub fn try_enqueue_timestamped(&self, item: TimestampedWorkItem<T>) -> Result<(), TimestampedWorkItem<T>> {
+        let (mutex, cvar) = &self.queue;
+        let mut queue = mutex.lock();
+        if self.is_full(&queue) {
+            return Err(item);
+        }
+        let is_stop = matches!(item, TimestampedWorkItem(WorkItem::Stop, _));
+        queue.push_back(item);
+        self.notify(cvar, is_stop);
+        Ok(())
     }
-    
+
+    //Producers may run as tasks, see executor::park
+    fn wait_until_not_full(&self, queue: &mut MutexGuard<VecDeque<TimestampedWorkItem<T>>>) {
+        while self.is_full(queue) {
+            executor::park(|| self.not_full.wait(queue));
+        }
+    }
+
+    //Every waiting consumer must see a Stop, not just the one that wakes up first
+    fn notify(&self, cvar: &Condvar, is_stop: bool) {
+        if is_stop {
+            cvar.notify_all();
+        } else {
+            cvar.notify_one();
+        }
+        if self.async_consumers.load(Ordering::Acquire) {
+            if is_stop {
+                self.item_available.notify_waiters();
+            } else {
+                self.item_available.notify_one();
+            }
+        }
+    }
+
     pub fn wait_and_dequeue(&self) -> TimestampedWorkItem<T> {
-        let &(ref mutex, ref cvar) = &self.queue;
+        let (mutex, cvar) = &self.queue;
         let mut queue = mutex.lock();
         while queue.is_empty() {
             cvar.wait(&mut queue);
         }
+        self.pop(&mut queue)
+    }
 
-        debug_assert!(queue.is_empty() == false);
+    //Same as wait_and_dequeue, but gives up at the deadline
+    pub fn wait_and_dequeue_until(&self, deadline: Instant) -> Option<TimestampedWorkItem<T>> {
+        let (mutex, cvar) = &self.queue;
+        let mut queue = mutex.lock();
+        while queue.is_empty() {
+            if cvar.wait_until(&mut queue, deadline).timed_out() && queue.is_empty() {
+                return None;
+            }
+        }
+        Some(self.pop(&mut queue))
+    }
+
+    //Same as wait_and_dequeue, for consumers running as async tasks
+    pub async fn dequeue(&self) -> TimestampedWorkItem<T> {
+        self.async_consumers.store(true, Ordering::Release);
+        loop {
+            //Registered before looking at the queue, so an item enqueued
+            //right after the check still wakes this consumer up
+            let mut notified = std::pin::pin!(self.item_available.notified());
+            notified.as_mut().enable();
+            {
+                let mut queue = self.queue.0.lock();
+                if !queue.is_empty() {
+                    return self.pop(&mut queue);
+                }
+            }
+            notified.await;
+        }
+    }
+
+    fn pop(&self, queue: &mut VecDeque<TimestampedWorkItem<T>>) -> TimestampedWorkItem<T> {
+        debug_assert!(!queue.is_empty());
+
+        if let Some(TimestampedWorkItem(WorkItem::Stop, order)) = queue.front() {
+            return TimestampedWorkItem(WorkItem::Stop, *order);
+        }
 
         let popped = queue.pop_front();
 
         debug_assert!(popped.is_some());
-       
+
+        if self.capacity.is_some() {
+            self.not_full.notify_one();
+        }
+
         popped.unwrap()
     }
-}
 
-unsafe impl<T> Send for BlockingQueue<T> {}
-unsafe impl<T> Sync for BlockingQueue<T> {}
\ No newline at end of file
+    pub fn len(&self) -> usize {
+        self.queue.0.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.0.lock().is_empty()
+    }
+}
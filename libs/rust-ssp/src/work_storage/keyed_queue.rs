@@ -0,0 +1,118 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use parking_lot::Mutex;
+use crate::work_storage::*;
+
+//Public API: Picks the replica of a keyed stage for an item, see
+//InOutStage::keyed. Items with equal hashes go to the same replica
+pub type KeyFn<T> = Box<dyn Fn(&T) -> u64 + Send + Sync>;
+
+//Hashes whatever key the stage uses into a KeyFn
+pub fn key_fn<T, K, F>(key: F) -> KeyFn<T>
+where
+    K: Hash,
+    F: Fn(&T) -> K + Send + Sync + 'static
+{
+    Box::new(move |item| {
+        let mut hasher = DefaultHasher::new();
+        key(item).hash(&mut hasher);
+        hasher.finish()
+    })
+}
+
+/*
+ * Queue of a keyed block: one BlockingQueue per replica, and each item goes
+ * to the replica its key hashes to. A replica then sees all the items of its
+ * keys, in the order they arrived, and can keep per-key state in its node
+ * without locks. Dropped items only keep the orders dense, so they go to any
+ * replica; Stop goes to all of them.
+ *
+ * Orders are handed out under a lock, so that an item refused by a full
+ * queue does not leave a gap. The lock is let go before waiting for room,
+ * so that a full replica queue only holds up the items of its own keys.
+ */
+pub struct KeyedQueue<T> {
+    queues: Vec<Arc<BlockingQueue<T>>>,
+    key: KeyFn<T>,
+    number_of_inserts: Mutex<u64>,
+    consumers: AtomicUsize
+}
+
+impl<T> KeyedQueue<T> {
+    //Each replica queue holds up to `capacity` items, if any
+    pub fn new(replicas: usize, capacity: Option<usize>, key: KeyFn<T>) -> Arc<KeyedQueue<T>> {
+        let queues = (0..replicas.max(1))
+            .map(|_| match capacity {
+                Some(capacity) => BlockingQueue::bounded(capacity),
+                None => BlockingQueue::new()
+            })
+            .collect();
+        Arc::new(KeyedQueue {
+            queues,
+            key,
+            number_of_inserts: Mutex::new(0),
+            consumers: AtomicUsize::new(0)
+        })
+    }
+
+    //Each replica takes the next queue
+    pub fn consumer(&self) -> Arc<BlockingQueue<T>> {
+        let replica = self.consumers.fetch_add(1, Ordering::SeqCst);
+        self.queues[replica % self.queues.len()].clone()
+    }
+
+    fn queue(&self, item: &TimestampedWorkItem<T>) -> &Arc<BlockingQueue<T>> {
+        let slot = match item {
+            TimestampedWorkItem(WorkItem::Value(value), _) => (self.key)(value),
+            TimestampedWorkItem(_, order) => *order
+        };
+        &self.queues[(slot % self.queues.len() as u64) as usize]
+    }
+
+    //Items sent here always go in, so the order is theirs as soon as it is
+    //taken
+    pub fn enqueue(&self, item: WorkItem<T>) -> u64 {
+        let order = {
+            let mut number_of_inserts = self.number_of_inserts.lock();
+            *number_of_inserts += 1;
+            *number_of_inserts - 1
+        };
+        self.enqueue_timestamped(TimestampedWorkItem(item, order));
+        order
+    }
+
+    //Trying does not wait, so the order is only taken once the item is in
+    pub fn try_enqueue(&self, item: WorkItem<T>) -> Result<u64, WorkItem<T>> {
+        if let WorkItem::Stop = item {
+            return Ok(self.enqueue(item));
+        }
+        let mut number_of_inserts = self.number_of_inserts.lock();
+        let order = *number_of_inserts;
+        let item = TimestampedWorkItem(item, order);
+        self.queue(&item).try_enqueue_timestamped(item).map_err(|TimestampedWorkItem(item, _)| item)?;
+        *number_of_inserts += 1;
+        Ok(order)
+    }
+
+    pub fn enqueue_timestamped(&self, item: TimestampedWorkItem<T>) {
+        match item {
+            TimestampedWorkItem(WorkItem::Stop, order) => {
+                for queue in &self.queues {
+                    queue.enqueue_timestamped(TimestampedWorkItem(WorkItem::Stop, order));
+                }
+            }
+            item => self.queue(&item).enqueue_timestamped(item)
+        }
+    }
+
+    //Items waiting for all replicas
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
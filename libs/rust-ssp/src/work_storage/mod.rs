@@ -1,8 +1,17 @@
-
 pub mod blocking_queue;
 pub mod blocking_ordered_set;
+pub mod keyed_queue;
+pub mod reorder_buffer;
+pub mod ring_queue;
+pub mod stage_queue;
+pub mod stealing_queue;
 pub mod work_item;
 
 pub use blocking_queue::BlockingQueue;
 pub use blocking_ordered_set::BlockingOrderedSet;
-pub use work_item::{WorkItem, TimestampedWorkItem};
\ No newline at end of file
+pub use keyed_queue::{KeyedQueue, KeyFn, key_fn};
+pub use reorder_buffer::ReorderBuffer;
+pub use ring_queue::RingQueue;
+pub use stage_queue::{StageQueue, QueueConsumer};
+pub use stealing_queue::{StealingQueue, StealingConsumer};
+pub use work_item::{WorkItem, TimestampedWorkItem};
@@ -0,0 +1,81 @@
+use crate::work_storage::*;
+use std::collections::BTreeMap;
+
+/*
+ * Non-blocking reordering storage. Items are inserted in any order and
+ * released strictly by timestamp, starting at 0. Relies on the timestamps
+ * being dense, which holds because dropped items keep flowing as
+ * WorkItem::Dropped.
+ *
+ * A window does not stop inserts by itself: whoever inserts checks fits
+ * first and waits for the next item to be released, see
+ * InOutStage::reorder_window.
+ */
+pub struct ReorderBuffer<T> {
+    next_order: u64,
+    pending: BTreeMap<u64, WorkItem<T>>,
+    window: Option<usize>,
+    //Most items held at once
+    high_water: usize,
+}
+
+impl<T> ReorderBuffer<T> {
+    pub fn new() -> ReorderBuffer<T> {
+        ReorderBuffer {
+            next_order: 0,
+            pending: BTreeMap::new(),
+            window: None,
+            high_water: 0,
+        }
+    }
+
+    pub fn windowed(window: usize) -> ReorderBuffer<T> {
+        assert!(window > 0, "reorder window must be greater than zero");
+        ReorderBuffer { window: Some(window), ..ReorderBuffer::new() }
+    }
+
+    pub fn window(&self) -> Option<usize> {
+        self.window
+    }
+
+    //The next item to release is always inside the window
+    pub fn fits(&self, order: u64) -> bool {
+        match self.window {
+            Some(window) => order < self.next_order + window as u64,
+            None => true
+        }
+    }
+
+    pub fn insert(&mut self, item: TimestampedWorkItem<T>) {
+        let TimestampedWorkItem(work_item, order) = item;
+        debug_assert!(order >= self.next_order);
+        self.pending.insert(order, work_item);
+        self.high_water = self.high_water.max(self.pending.len());
+    }
+
+    //Removes the next item in order, if it has already arrived
+    pub fn pop_next(&mut self) -> Option<TimestampedWorkItem<T>> {
+        let order = self.next_order;
+        let item = self.pending.remove(&order)?;
+        self.next_order += 1;
+        Some(TimestampedWorkItem(item, order))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn high_water(&self) -> usize {
+        self.high_water
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+impl<T> Default for ReorderBuffer<T> {
+    fn default() -> Self {
+        ReorderBuffer::new()
+    }
+}
@@ -0,0 +1,328 @@
+use std::cell::UnsafeCell;
+use std::hint;
+use std::mem::MaybeUninit;
+use std::sync::Arc;
+use std::sync::atomic::{fence, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use parking_lot::{Condvar, Mutex};
+use tokio1::sync::Notify;
+use crate::executor;
+use crate::work_storage::*;
+
+const NOT_STOPPED: u64 = u64::MAX;
+//Rounds of busy waiting, then of yielding, before a thread parks
+const SPINS: u32 = 64;
+const YIELDS: u32 = 16;
+
+//Keeps the positions of producers and consumers on cache lines of their own
+#[repr(align(64))]
+struct Padded<T>(T);
+
+struct Slot<T> {
+    //Position the slot is ready for: written when it equals the position of
+    //a producer, read when it is one past the position of a consumer
+    sequence: AtomicUsize,
+    item: UnsafeCell<MaybeUninit<TimestampedWorkItem<T>>>,
+}
+
+/*
+ * Lock-free alternative to BlockingQueue: a bounded ring of slots, each with
+ * a sequence number telling producers and consumers whose turn it is
+ * (Vyukov's bounded queue). Producers and consumers claim positions with a
+ * compare-and-swap, unless the block says it has a single one of them, in
+ * which case a plain store does. Orders are the positions of the items, so
+ * they are dense and follow the queue.
+ *
+ * Threads waiting for an item, or for room, spin for a while, then yield,
+ * then park on a condition variable. The other side only takes the lock
+ * when someone is parked. Stop is not stored in the ring: once it is set, a
+ * consumer that finds the ring empty gets a copy of it, as with the sticky
+ * Stop of a BlockingQueue.
+ *
+ * The capacity is rounded up to a power of two, and is at least 2.
+ */
+pub struct RingQueue<T> {
+    slots: Box<[Slot<T>]>,
+    mask: usize,
+    tail: Padded<AtomicUsize>,
+    head: Padded<AtomicUsize>,
+    single_producer: bool,
+    single_consumer: bool,
+    stop_order: AtomicU64,
+    //Threads parked waiting for an item, or for room
+    sleepers: AtomicUsize,
+    full_sleepers: AtomicUsize,
+    sleep_lock: Mutex<()>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    item_available: Notify,
+    async_consumers: AtomicBool,
+}
+
+//Each slot is only accessed by the thread that claimed its position, and the
+//sequence numbers order the write of an item before its read
+unsafe impl<T: Send> Send for RingQueue<T> {}
+unsafe impl<T: Send> Sync for RingQueue<T> {}
+
+impl<T> RingQueue<T> {
+    pub fn mpmc(capacity: usize) -> Arc<RingQueue<T>> {
+        RingQueue::new(capacity, false, false)
+    }
+
+    //Only sound while a single thread at a time produces and consumes, each
+    //handing over to the next one with some synchronization. Blocks only ask
+    //for it when they have one replica and the stage before them forwards
+    //from a single thread, see StageContext::fed_by_one
+    pub fn spsc(capacity: usize) -> Arc<RingQueue<T>> {
+        RingQueue::new(capacity, true, true)
+    }
+
+    fn new(capacity: usize, single_producer: bool, single_consumer: bool) -> Arc<RingQueue<T>> {
+        assert!(capacity > 0, "ring queue capacity must be greater than zero");
+        let capacity = capacity.max(2).next_power_of_two();
+        let slots = (0..capacity)
+            .map(|position| Slot {
+                sequence: AtomicUsize::new(position),
+                item: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect();
+        Arc::new(RingQueue {
+            slots,
+            mask: capacity - 1,
+            tail: Padded(AtomicUsize::new(0)),
+            head: Padded(AtomicUsize::new(0)),
+            single_producer,
+            single_consumer,
+            stop_order: AtomicU64::new(NOT_STOPPED),
+            sleepers: AtomicUsize::new(0),
+            full_sleepers: AtomicUsize::new(0),
+            sleep_lock: Mutex::new(()),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            item_available: Notify::new(),
+            async_consumers: AtomicBool::new(false),
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_single_producer(&self) -> bool {
+        self.single_producer
+    }
+
+    //Puts the item in the ring, numbered by its position unless it has an
+    //order already. Gives it back when the ring is full
+    fn push(&self, item: WorkItem<T>, order: Option<u64>) -> Result<u64, WorkItem<T>> {
+        let mut position = self.tail.0.load(Ordering::Relaxed);
+        let slot = loop {
+            let slot = &self.slots[position & self.mask];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match (sequence as isize).wrapping_sub(position as isize) {
+                0 if self.single_producer => {
+                    self.tail.0.store(position + 1, Ordering::Relaxed);
+                    break slot;
+                }
+                0 => match self.tail.0.compare_exchange_weak(position, position + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => break slot,
+                    Err(current) => position = current
+                },
+                difference if difference < 0 => return Err(item),
+                _ => position = self.tail.0.load(Ordering::Relaxed)
+            }
+        };
+        let order = order.unwrap_or(position as u64);
+        unsafe { (*slot.item.get()).write(TimestampedWorkItem(item, order)); }
+        slot.sequence.store(position + 1, Ordering::Release);
+        self.item_pushed();
+        Ok(order)
+    }
+
+    fn pop(&self) -> Option<TimestampedWorkItem<T>> {
+        let mut position = self.head.0.load(Ordering::Relaxed);
+        let slot = loop {
+            let slot = &self.slots[position & self.mask];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match (sequence as isize).wrapping_sub(position as isize + 1) {
+                0 if self.single_consumer => {
+                    self.head.0.store(position + 1, Ordering::Relaxed);
+                    break slot;
+                }
+                0 => match self.head.0.compare_exchange_weak(position, position + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => break slot,
+                    Err(current) => position = current
+                },
+                difference if difference < 0 => return None,
+                _ => position = self.head.0.load(Ordering::Relaxed)
+            }
+        };
+        let item = unsafe { (*slot.item.get()).assume_init_read() };
+        slot.sequence.store(position + self.mask + 1, Ordering::Release);
+        self.room_made();
+        Some(item)
+    }
+
+    //Pairs with the fence in park: either the sleeper sees the item, or the
+    //producer sees the sleeper
+    fn item_pushed(&self) {
+        fence(Ordering::SeqCst);
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let _sleeping = self.sleep_lock.lock();
+            self.not_empty.notify_one();
+        }
+        if self.async_consumers.load(Ordering::Acquire) {
+            self.item_available.notify_one();
+        }
+    }
+
+    fn room_made(&self) {
+        fence(Ordering::SeqCst);
+        if self.full_sleepers.load(Ordering::SeqCst) > 0 {
+            let _sleeping = self.sleep_lock.lock();
+            self.not_full.notify_one();
+        }
+    }
+
+    fn stopped(&self) -> Option<u64> {
+        match self.stop_order.load(Ordering::SeqCst) {
+            NOT_STOPPED => None,
+            order => Some(order)
+        }
+    }
+
+    fn stop(&self, order: u64) {
+        self.stop_order.store(order, Ordering::SeqCst);
+        let _sleeping = self.sleep_lock.lock();
+        self.not_empty.notify_all();
+        self.item_available.notify_waiters();
+    }
+
+    pub fn enqueue(&self, item: WorkItem<T>) -> u64 {
+        match item {
+            WorkItem::Stop => {
+                let order = self.tail.0.load(Ordering::SeqCst) as u64;
+                self.stop(order);
+                order
+            }
+            item => self.push_waiting(item, None)
+        }
+    }
+
+    pub fn try_enqueue(&self, item: WorkItem<T>) -> Result<u64, WorkItem<T>> {
+        match item {
+            WorkItem::Stop => Ok(self.enqueue(WorkItem::Stop)),
+            item => self.push(item, None)
+        }
+    }
+
+    pub fn enqueue_timestamped(&self, item: TimestampedWorkItem<T>) {
+        match item {
+            TimestampedWorkItem(WorkItem::Stop, order) => self.stop(order),
+            TimestampedWorkItem(item, order) => {
+                self.push_waiting(item, Some(order));
+            }
+        }
+    }
+
+    fn push_waiting(&self, mut item: WorkItem<T>, order: Option<u64>) -> u64 {
+        let mut rounds = 0;
+        loop {
+            match self.push(item, order) {
+                Ok(order) => return order,
+                Err(rejected) => item = rejected
+            }
+            if !wait(&mut rounds) {
+                self.park_producer();
+            }
+        }
+    }
+
+    //Producers may run as tasks, see executor::park
+    fn park_producer(&self) {
+        let mut sleeping = self.sleep_lock.lock();
+        self.full_sleepers.fetch_add(1, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+        if self.len() >= self.capacity() {
+            executor::park(|| self.not_full.wait(&mut sleeping));
+        }
+        self.full_sleepers.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn park_consumer(&self) {
+        let mut sleeping = self.sleep_lock.lock();
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+        if self.is_empty() && self.stopped().is_none() {
+            self.not_empty.wait(&mut sleeping);
+        }
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    //The Stop is read before looking at the ring, so any item pushed before
+    //it is found
+    fn try_dequeue(&self) -> Option<TimestampedWorkItem<T>> {
+        let stopped = self.stopped();
+        match (self.pop(), stopped) {
+            (Some(item), _) => Some(item),
+            (None, Some(order)) => Some(TimestampedWorkItem(WorkItem::Stop, order)),
+            (None, None) => None
+        }
+    }
+
+    pub fn wait_and_dequeue(&self) -> TimestampedWorkItem<T> {
+        let mut rounds = 0;
+        loop {
+            if let Some(item) = self.try_dequeue() {
+                return item;
+            }
+            if !wait(&mut rounds) {
+                self.park_consumer();
+            }
+        }
+    }
+
+    //Same as wait_and_dequeue, for consumers running as async tasks
+    pub async fn dequeue(&self) -> TimestampedWorkItem<T> {
+        self.async_consumers.store(true, Ordering::Release);
+        loop {
+            let mut notified = std::pin::pin!(self.item_available.notified());
+            notified.as_mut().enable();
+            if let Some(item) = self.try_dequeue() {
+                return item;
+            }
+            notified.await;
+        }
+    }
+
+    //Claimed positions, some of which may still be being written
+    pub fn len(&self) -> usize {
+        let tail = self.tail.0.load(Ordering::SeqCst);
+        let head = self.head.0.load(Ordering::SeqCst);
+        tail.saturating_sub(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+//Spins, then yields. False once it is time to park
+fn wait(rounds: &mut u32) -> bool {
+    *rounds += 1;
+    if *rounds <= SPINS {
+        hint::spin_loop();
+        true
+    } else if *rounds <= SPINS + YIELDS {
+        thread::yield_now();
+        true
+    } else {
+        false
+    }
+}
+
+impl<T> Drop for RingQueue<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
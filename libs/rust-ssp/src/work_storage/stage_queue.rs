@@ -0,0 +1,110 @@
+use std::sync::Arc;
+use crate::work_storage::*;
+
+//Internals: The input queue of a block, shared by all its replicas. Keyed
+//queues give each replica a queue of its own
+pub enum StageQueue<T> {
+    Shared(Arc<BlockingQueue<T>>),
+    Stealing(Arc<StealingQueue<T>>),
+    Keyed(Arc<KeyedQueue<T>>),
+    Ring(Arc<RingQueue<T>>)
+}
+
+//Internals: How one replica takes items from the block queue
+pub enum QueueConsumer<T> {
+    Shared(Arc<BlockingQueue<T>>),
+    Stealing(StealingConsumer<T>),
+    Ring(Arc<RingQueue<T>>)
+}
+
+impl<T> Clone for StageQueue<T> {
+    fn clone(&self) -> StageQueue<T> {
+        match self {
+            StageQueue::Shared(queue) => StageQueue::Shared(queue.clone()),
+            StageQueue::Stealing(queue) => StageQueue::Stealing(queue.clone()),
+            StageQueue::Keyed(queue) => StageQueue::Keyed(queue.clone()),
+            StageQueue::Ring(queue) => StageQueue::Ring(queue.clone())
+        }
+    }
+}
+
+impl<T> StageQueue<T> {
+    pub fn enqueue(&self, item: WorkItem<T>) -> u64 {
+        match self {
+            StageQueue::Shared(queue) => queue.enqueue(item),
+            StageQueue::Stealing(queue) => queue.enqueue(item),
+            StageQueue::Keyed(queue) => queue.enqueue(item),
+            StageQueue::Ring(queue) => queue.enqueue(item)
+        }
+    }
+
+    pub fn try_enqueue(&self, item: WorkItem<T>) -> Result<u64, WorkItem<T>> {
+        match self {
+            StageQueue::Shared(queue) => queue.try_enqueue(item),
+            StageQueue::Stealing(queue) => Ok(queue.enqueue(item)),
+            StageQueue::Keyed(queue) => queue.try_enqueue(item),
+            StageQueue::Ring(queue) => queue.try_enqueue(item)
+        }
+    }
+
+    pub fn enqueue_timestamped(&self, item: TimestampedWorkItem<T>) {
+        match self {
+            StageQueue::Shared(queue) => queue.enqueue_timestamped(item),
+            StageQueue::Stealing(queue) => queue.enqueue_timestamped(item),
+            StageQueue::Keyed(queue) => queue.enqueue_timestamped(item),
+            StageQueue::Ring(queue) => queue.enqueue_timestamped(item)
+        }
+    }
+
+    pub fn consumer(&self) -> QueueConsumer<T> {
+        match self {
+            StageQueue::Shared(queue) => QueueConsumer::Shared(queue.clone()),
+            StageQueue::Stealing(queue) => QueueConsumer::Stealing(queue.consumer()),
+            StageQueue::Keyed(queue) => QueueConsumer::Shared(queue.consumer()),
+            StageQueue::Ring(queue) => QueueConsumer::Ring(queue.clone())
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            StageQueue::Shared(queue) => queue.len(),
+            StageQueue::Stealing(queue) => queue.len(),
+            StageQueue::Keyed(queue) => queue.len(),
+            StageQueue::Ring(queue) => queue.len()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> QueueConsumer<T> {
+    pub fn wait_and_dequeue(&mut self) -> TimestampedWorkItem<T> {
+        match self {
+            QueueConsumer::Shared(queue) => queue.wait_and_dequeue(),
+            QueueConsumer::Stealing(consumer) => consumer.wait_and_dequeue(),
+            QueueConsumer::Ring(queue) => queue.wait_and_dequeue()
+        }
+    }
+
+    pub async fn dequeue(&mut self) -> TimestampedWorkItem<T> {
+        match self {
+            QueueConsumer::Shared(queue) => queue.dequeue().await,
+            QueueConsumer::Stealing(consumer) => consumer.dequeue().await,
+            QueueConsumer::Ring(queue) => queue.dequeue().await
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            QueueConsumer::Shared(queue) => queue.len(),
+            QueueConsumer::Stealing(consumer) => consumer.len(),
+            QueueConsumer::Ring(queue) => queue.len()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,240 @@
+use std::iter;
+use std::sync::Arc;
+use std::sync::atomic::{fence, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+use parking_lot::{Condvar, Mutex, RwLock};
+use tokio1::sync::Notify;
+use crate::work_storage::*;
+
+const NOT_STOPPED: u64 = u64::MAX;
+
+/*
+ * Work-stealing alternative to BlockingQueue for parallel blocks. Producers
+ * push into a shared injector. Each replica moves batches from it into its
+ * own deque and, once both are empty, steals from its siblings, so replicas
+ * only contend on shared state when they run out of local work.
+ *
+ * Stop is not stored with the items. Once it is enqueued, a replica that
+ * finds no work anywhere gets a copy of it, the same way every replica sees
+ * the sticky Stop of a BlockingQueue. Work still sitting in the deque of a
+ * sibling is finished by that sibling before it stops.
+ *
+ * Work-stealing queues are unbounded. Replicas that leave early, as those
+ * of adaptive stages do, give what is left in their deque back to the
+ * injector, see StealingConsumer::drop.
+ */
+pub struct StealingQueue<T> {
+    injector: Injector<TimestampedWorkItem<T>>,
+    //With the id of the consumer each belongs to
+    stealers: RwLock<Vec<(usize, Stealer<TimestampedWorkItem<T>>)>>,
+    consumers: AtomicUsize,
+    number_of_inserts: AtomicUsize,
+    stop_order: AtomicU64,
+    //Replicas with nothing to do sleep here. Producers only take the lock
+    //when someone is sleeping
+    sleepers: AtomicUsize,
+    sleep_lock: Mutex<()>,
+    wake_up: Condvar,
+    item_available: Notify,
+    async_consumers: AtomicBool
+}
+
+impl<T> StealingQueue<T> {
+    pub fn new() -> Arc<StealingQueue<T>> {
+        Arc::new(StealingQueue {
+            injector: Injector::new(),
+            stealers: RwLock::new(vec![]),
+            consumers: AtomicUsize::new(0),
+            number_of_inserts: AtomicUsize::new(0),
+            stop_order: AtomicU64::new(NOT_STOPPED),
+            sleepers: AtomicUsize::new(0),
+            sleep_lock: Mutex::new(()),
+            wake_up: Condvar::new(),
+            item_available: Notify::new(),
+            async_consumers: AtomicBool::new(false)
+        })
+    }
+
+    //Each replica needs its own consumer, holding its local deque
+    pub fn consumer(self: &Arc<Self>) -> StealingConsumer<T> {
+        let local = Worker::new_fifo();
+        let id = self.consumers.fetch_add(1, Ordering::Relaxed);
+        self.stealers.write().push((id, local.stealer()));
+        StealingConsumer {
+            queue: self.clone(),
+            local,
+            id
+        }
+    }
+
+    pub fn enqueue(&self, item: WorkItem<T>) -> u64 {
+        let order = self.number_of_inserts.fetch_add(1, Ordering::SeqCst) as u64;
+        self.enqueue_timestamped(TimestampedWorkItem(item, order));
+        order
+    }
+
+    pub fn enqueue_timestamped(&self, item: TimestampedWorkItem<T>) {
+        match item {
+            TimestampedWorkItem(WorkItem::Stop, order) => {
+                self.stop_order.store(order, Ordering::SeqCst);
+                let _sleeping = self.sleep_lock.lock();
+                self.wake_up.notify_all();
+                self.item_available.notify_waiters();
+            }
+            item => {
+                self.injector.push(item);
+                self.wake(1);
+            }
+        }
+    }
+
+    //Wakes up to `consumers` replicas for new items in the injector
+    fn wake(&self, consumers: usize) {
+        //Pairs with the fence in sleep: either the sleeper sees the items,
+        //or this producer sees the sleeper
+        fence(Ordering::SeqCst);
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let _sleeping = self.sleep_lock.lock();
+            match consumers {
+                1 => { self.wake_up.notify_one(); }
+                _ => { self.wake_up.notify_all(); }
+            }
+        }
+        if self.async_consumers.load(Ordering::Acquire) {
+            match consumers {
+                1 => self.item_available.notify_one(),
+                _ => self.item_available.notify_waiters()
+            }
+        }
+    }
+
+    fn stopped(&self) -> Option<u64> {
+        match self.stop_order.load(Ordering::SeqCst) {
+            NOT_STOPPED => None,
+            order => Some(order)
+        }
+    }
+
+    fn has_work(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.read().iter().any(|(_, stealer)| !stealer.is_empty())
+    }
+
+    fn sleep(&self) {
+        let mut sleeping = self.sleep_lock.lock();
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+        if !self.has_work() && self.stopped().is_none() {
+            self.wake_up.wait(&mut sleeping);
+        }
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    //Items waiting in the injector and in the deques of all replicas
+    pub fn len(&self) -> usize {
+        self.injector.len() + self.stealers.read().iter().map(|(_, stealer)| stealer.len()).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.has_work()
+    }
+}
+
+pub struct StealingConsumer<T> {
+    queue: Arc<StealingQueue<T>>,
+    local: Worker<TimestampedWorkItem<T>>,
+    id: usize
+}
+
+impl<T> StealingConsumer<T> {
+    fn find(&self) -> Option<TimestampedWorkItem<T>> {
+        self.local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.queue.injector.steal_batch_and_pop(&self.local)
+                    .or_else(|| self.queue.stealers.read().iter().map(|(_, stealer)| stealer.steal()).collect())
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(Steal::success)
+        })
+    }
+
+    //The Stop is only returned when there is no work left to find. It is read
+    //before searching, so any item enqueued before it is seen by the search.
+    fn try_dequeue(&self) -> Option<TimestampedWorkItem<T>> {
+        let stopped = self.queue.stopped();
+        match (self.find(), stopped) {
+            (Some(item), _) => Some(item),
+            (None, Some(order)) => Some(TimestampedWorkItem(WorkItem::Stop, order)),
+            (None, None) => None
+        }
+    }
+
+    pub fn wait_and_dequeue(&mut self) -> TimestampedWorkItem<T> {
+        loop {
+            if let Some(item) = self.try_dequeue() {
+                return item;
+            }
+            self.queue.sleep();
+        }
+    }
+
+    pub async fn dequeue(&mut self) -> TimestampedWorkItem<T> {
+        self.queue.async_consumers.store(true, Ordering::Release);
+        loop {
+            let mut notified = std::pin::pin!(self.queue.item_available.notified());
+            notified.as_mut().enable();
+            if let Some(item) = self.try_dequeue() {
+                return item;
+            }
+            notified.await;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+//The items still in the deque of a replica that leaves would only be found
+//by stealing, and the sleeping replicas would not hear of them. They go
+//back to the injector instead, and the stealer of the deque goes away
+impl<T> Drop for StealingConsumer<T> {
+    fn drop(&mut self) {
+        self.queue.stealers.write().retain(|(id, _)| *id != self.id);
+        let left = iter::from_fn(|| self.local.pop())
+            .map(|item| self.queue.injector.push(item))
+            .count();
+        if left > 0 {
+            self.queue.wake(left);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaving_replicas_give_their_items_back() {
+        let queue = StealingQueue::new();
+        let staying = queue.consumer();
+        for item in 0..10 {
+            queue.enqueue(WorkItem::Value(item));
+        }
+        for _ in 0..20 {
+            let mut leaving = queue.consumer();
+            match leaving.wait_and_dequeue() {
+                TimestampedWorkItem(WorkItem::Value(item), _) => queue.enqueue(WorkItem::Value(item)),
+                _ => panic!("only values were enqueued")
+            };
+            //The rest of the batch it took is still in its deque
+            assert!(!leaving.local.is_empty());
+        }
+        assert_eq!(queue.stealers.read().len(), 1);
+        assert_eq!(queue.injector.len(), 10);
+        assert_eq!(staying.len(), 10);
+    }
+}
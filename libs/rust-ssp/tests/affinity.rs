@@ -0,0 +1,107 @@
+// Pinning replica threads to cores, and recording where they went.
+
+use rust_spp::*;
+
+fn two_nodes() -> Topology {
+    Topology::new(vec![vec![0, 1], vec![2, 3]])
+}
+
+#[test]
+fn policies_order_the_cores() {
+    assert_eq!(Affinity::Unpinned.cores(&two_nodes(), 2), vec![None, None]);
+    assert_eq!(Affinity::Compact.cores(&two_nodes(), 5), vec![Some(0), Some(1), Some(2), Some(3), Some(0)]);
+    assert_eq!(Affinity::Scatter.cores(&two_nodes(), 5), vec![Some(0), Some(2), Some(1), Some(3), Some(0)]);
+    assert_eq!(Affinity::Cores(vec![3, 1]).cores(&two_nodes(), 3), vec![Some(3), Some(1), Some(3)]);
+}
+
+#[test]
+#[should_panic(expected = "core 7 is not available")]
+fn unknown_cores_are_rejected() {
+    Affinity::Cores(vec![1, 7]).cores(&two_nodes(), 2);
+}
+
+#[test]
+fn policies_parse_from_their_names() {
+    for policy in [Affinity::Unpinned, Affinity::Compact, Affinity::Scatter, Affinity::Cores(vec![0, 2, 3])] {
+        assert_eq!(policy.to_string().parse::<Affinity>().unwrap(), policy);
+    }
+    assert_eq!("0,4-6".parse::<Affinity>().unwrap(), Affinity::Cores(vec![0, 4, 5, 6]));
+    assert!("everywhere".parse::<Affinity>().is_err());
+    assert!(",".parse::<Affinity>().is_err());
+}
+
+#[test]
+fn policies_are_checked_against_the_topology() {
+    assert_eq!(Affinity::Cores(vec![3, 1]).check(&two_nodes()), Ok(()));
+    assert_eq!(Affinity::Compact.check(&two_nodes()), Ok(()));
+    assert!(Affinity::Cores(vec![1, 7]).check(&two_nodes()).unwrap_err().contains("core 7"));
+    assert!(Affinity::Cores(vec![]).check(&two_nodes()).is_err());
+    assert!(Affinity::Scatter.check(&Topology::new(vec![])).is_err());
+    assert_eq!(Affinity::Unpinned.check(&Topology::new(vec![])), Ok(()));
+}
+
+// The cores the calling thread may run on, as the kernel reports them
+#[cfg(target_os = "linux")]
+fn allowed_cores() -> String {
+    std::fs::read_to_string("/proc/thread-self/status").unwrap()
+        .lines()
+        .find_map(|line| line.strip_prefix("Cpus_allowed_list:"))
+        .unwrap()
+        .trim()
+        .to_string()
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn replicas_run_on_their_cores() {
+    let core = Topology::current().nodes[0][0];
+    let mut pipeline = pipeline![
+        config = PipelineConfig::new().with_affinity(Affinity::Cores(vec![core])).metrics(true);
+        parallel!(|item: u64| Some((item, allowed_cores())), 2),
+        sequential!(|(item, cores): (u64, String)| (item, cores, allowed_cores()))
+    ];
+    let placement = pipeline.placement().clone();
+    assert_eq!(placement.affinity, Affinity::Cores(vec![core]));
+    let stages: Vec<(usize, usize, Option<usize>)> = placement.replicas.iter()
+        .map(|replica| (replica.stage, replica.replica, replica.core))
+        .collect();
+    assert_eq!(stages, vec![(0, 0, Some(core)), (0, 1, Some(core)), (1, 0, Some(core))]);
+
+    for item in 0..10 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    assert_eq!(pipeline.metrics().unwrap().placement, placement);
+    for (_, first, last) in pipeline.collect().unwrap() {
+        assert_eq!(first, core.to_string());
+        assert_eq!(last, core.to_string());
+    }
+}
+
+#[test]
+fn unpinned_by_default() {
+    let pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 3),
+        collect!()
+    ];
+    assert_eq!(pipeline.placement().affinity, Affinity::Unpinned);
+    assert_eq!(pipeline.placement().replicas.len(), 4);
+    assert!(pipeline.placement().replicas.iter().all(|replica| replica.core.is_none()));
+}
+
+#[test]
+fn tokio_leaves_the_replicas_unpinned() {
+    let pipeline = pipeline![
+        config = PipelineConfig::new()
+            .with_executor(Executor::Tokio { worker_threads: 2 })
+            .with_affinity(Affinity::Compact);
+        parallel!(|item: u64| Some(item), 2),
+        collect!()
+    ];
+    // The replica tasks move between the runtime threads
+    assert!(pipeline.placement().replicas.iter().all(|replica| replica.core.is_none()));
+    for item in 0..10 {
+        pipeline.post(item).unwrap();
+    }
+    assert_eq!(pipeline.collect().unwrap().len(), 10);
+}
@@ -0,0 +1,158 @@
+// Bounded stage queues: producers must wait for room instead of growing the
+// queue, try_enqueue and try_post must hand the item back when it is full,
+// and the items must still go through once, in order where asked.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use rust_spp::*;
+
+mod common;
+use common::executors;
+
+const PIPELINES: usize = 300;
+
+fn replicas(i: usize) -> i32 {
+    (i % 6) as i32 + 1
+}
+
+#[test]
+fn full_queue_hands_the_item_back() {
+    let queue = BlockingQueue::bounded(2);
+    assert_eq!(queue.capacity(), Some(2));
+    assert_eq!(queue.try_enqueue(WorkItem::Value(0)).ok(), Some(0));
+    assert_eq!(queue.try_enqueue(WorkItem::Value(1)).ok(), Some(1));
+    match queue.try_enqueue(WorkItem::Value(2)) {
+        Err(WorkItem::Value(2)) => {}
+        _ => panic!("the queue should be full"),
+    }
+    assert!(matches!(queue.wait_and_dequeue(), TimestampedWorkItem(WorkItem::Value(0), 0)));
+    assert_eq!(queue.try_enqueue(WorkItem::Value(2)).ok(), Some(2));
+    assert_eq!(queue.len(), 2);
+}
+
+#[test]
+fn unbounded_queue_has_no_capacity() {
+    let queue = BlockingQueue::new();
+    assert_eq!(queue.capacity(), None);
+    for item in 0..1000 {
+        assert!(queue.try_enqueue(WorkItem::Value(item)).is_ok());
+    }
+    assert_eq!(queue.len(), 1000);
+}
+
+#[test]
+#[should_panic(expected = "greater than zero")]
+fn zero_capacity_is_rejected() {
+    BlockingQueue::<u64>::bounded(0);
+}
+
+#[test]
+fn producer_waits_for_room() {
+    let queue = BlockingQueue::bounded(1);
+    let enqueued = Arc::new(AtomicUsize::new(0));
+    let producer = {
+        let queue = queue.clone();
+        let enqueued = enqueued.clone();
+        thread::spawn(move || {
+            for item in 0..3u64 {
+                queue.enqueue(WorkItem::Value(item));
+                enqueued.fetch_add(1, Ordering::SeqCst);
+            }
+            queue.enqueue(WorkItem::Stop);
+        })
+    };
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(enqueued.load(Ordering::SeqCst), 1);
+    assert_eq!(queue.len(), 1);
+    let mut items = vec![];
+    loop {
+        match queue.wait_and_dequeue() {
+            TimestampedWorkItem(WorkItem::Value(item), order) => items.push((item, order)),
+            TimestampedWorkItem(WorkItem::Dropped, _) => panic!("nothing was dropped"),
+            TimestampedWorkItem(WorkItem::Stop, _) => break,
+        }
+        assert!(queue.len() <= 1);
+    }
+    producer.join().unwrap();
+    assert_eq!(items, vec![(0, 0), (1, 1), (2, 2)]);
+}
+
+#[test]
+fn unordered_collect_sees_every_item_once() {
+    for executor in executors() {
+        for i in 0..PIPELINES {
+            let items = i % 41;
+            let pipeline = pipeline![
+                config = PipelineConfig::new().with_executor(executor);
+                parallel!(|item: usize| Some(item), replicas(i), bounded(i % 4 + 1)),
+                parallel!(|item: usize| Some(item), replicas(i + 3), bounded(1)),
+                parallel_sink!(|item: usize| item, replicas(i + 5), bounded(2))
+            ];
+            for item in 0..items {
+                pipeline.post(item).unwrap();
+            }
+            let mut collected = pipeline.collect().unwrap();
+            collected.sort();
+            assert_eq!(collected, (0..items).collect::<Vec<_>>(), "{:?}", executor);
+        }
+    }
+}
+
+#[test]
+fn posting_into_a_full_queue() {
+    let gate = Arc::new(parking_lot::Mutex::new(()));
+    let gate_lock = gate.clone();
+    let closed = gate.lock();
+    let pipeline = pipeline![
+        parallel!({ let gate = gate_lock.clone(); move |item: u64| { drop(gate.lock()); Some(item) } }, 1, bounded(2)),
+        collect_ordered!()
+    ];
+    //One item is taken by the replica, two more fill the queue
+    pipeline.post(0).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert!(pipeline.try_post(1).is_ok());
+    assert!(pipeline.try_post(2).is_ok());
+    assert!(pipeline.try_post(3).is_err());
+    drop(closed);
+    pipeline.post(3).unwrap();
+    assert_eq!(pipeline.collect().unwrap(), vec![0, 1, 2, 3]);
+}
+
+#[test]
+fn posting_into_a_full_ordered_sink() {
+    let gate = Arc::new(parking_lot::Mutex::new(()));
+    let gate_lock = gate.clone();
+    let closed = gate.lock();
+    let pipeline = pipeline![
+        sequential_ordered!({ let gate = gate_lock.clone(); move |item: u64| { drop(gate.lock()); item } }, bounded(2))
+    ];
+    //One item is taken by the sink, two more fill its storage
+    pipeline.post(0).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert!(pipeline.try_post(1).is_ok());
+    assert!(pipeline.try_post(2).is_ok());
+    assert!(pipeline.try_post(3).is_err());
+    drop(closed);
+    pipeline.post(3).unwrap();
+    assert_eq!(pipeline.collect().unwrap(), vec![0, 1, 2, 3]);
+}
+
+#[test]
+fn bounded_ordered_collect_keeps_post_order() {
+    for executor in executors() {
+        for i in 0..PIPELINES {
+            let items = i % 41;
+            let pipeline = pipeline![
+                config = PipelineConfig::new().with_executor(executor);
+                parallel_ordered!(|item: usize| Some(item), replicas(i), bounded(i % 4 + 1)),
+                collect_ordered!(bounded(i % 3 + 1))
+            ];
+            for item in 0..items {
+                pipeline.post(item).unwrap();
+            }
+            assert_eq!(pipeline.collect().unwrap(), (0..items).collect::<Vec<_>>(), "{:?}", executor);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+// The builder API, including pipelines whose stages are chosen at runtime.
+
+use rust_spp::*;
+
+#[derive(Clone)]
+struct AddOne;
+
+impl InOut<u64, u64> for AddOne {
+    fn process(&mut self, input: u64) -> Option<u64> {
+        Some(input + 1)
+    }
+}
+
+#[test]
+fn stages_chosen_at_runtime() {
+    for stages in 0..6 {
+        let mut builder = Pipeline::builder();
+        for _ in 0..stages {
+            builder = builder.stage_parallel_ordered(AddOne, 3);
+        }
+        let pipeline = builder.collect_ordered().build();
+        for item in 0..50 {
+            pipeline.post(item).unwrap();
+        }
+        let expected: Vec<u64> = (0..50).map(|item| item + stages).collect();
+        assert_eq!(pipeline.collect().unwrap(), expected);
+    }
+}
+
+#[test]
+fn typed_stages_and_sinks() {
+    let pipeline = Pipeline::builder()
+        .stage_parallel(|item: u32| if item.is_multiple_of(2) { Some(item as u64) } else { None }, 4)
+        .stage_seq(|item: u64| Some(item.to_string()))
+        .try_stage_parallel_ordered(|item: String| item.parse::<u64>().map(Some), 2)
+        .sink_ordered(|item: u64| item * 10)
+        .build();
+    for item in 0..10 {
+        pipeline.post(item).unwrap();
+    }
+    assert_eq!(pipeline.collect().unwrap(), vec![0, 20, 40, 60, 80]);
+}
+
+#[test]
+fn sink_only_pipeline() {
+    let pipeline = Pipeline::builder()
+        .sink_parallel(|item: u64| item, 4)
+        .build();
+    for item in 0..100 {
+        pipeline.post(item).unwrap();
+    }
+    let mut collected = pipeline.collect().unwrap();
+    collected.sort();
+    assert_eq!(collected, (0..100).collect::<Vec<_>>());
+}
+
+#[test]
+fn stage_descriptors_from_macros() {
+    let mut pipeline = Pipeline::builder()
+        .config(PipelineConfig::new().metrics(true))
+        .stage(parallel!(AddOne, 2, bounded(4)))
+        .stage(InOutStage::new(BlockMode::Parallel(2), Box::new(|| Box::new(FromInOut(AddOne)))).bounded(1))
+        .sink_stage(sequential!(|_item: u64| {}))
+        .build();
+    for item in 0..20 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let metrics = pipeline.metrics().unwrap();
+    assert_eq!(metrics.stages.len(), 3);
+    assert!(metrics.stages.iter().all(|stage| stage.items == 20));
+}
+
+//Callers from before the builder create the blocks themselves
+#[test]
+#[allow(deprecated)]
+fn pipeline_propagate_still_creates_the_blocks() {
+    let context = PipelineContext::new(&PipelineConfig::default());
+    let mut monitors = Vec::<MonitorLoop>::new();
+    let block = pipeline_propagate!(monitors, context, 0,
+        parallel_ordered!(|item: u64| Some(item * 2), 3),
+        collect_ordered!());
+    let mut pipeline = Pipeline::new(block, monitors, context, SinkOutput::new());
+    pipeline.start();
+    for item in 0..20 {
+        pipeline.post(item).unwrap();
+    }
+    assert_eq!(pipeline.collect().unwrap(), (0..20).map(|item| item * 2).collect::<Vec<_>>());
+}
@@ -0,0 +1,124 @@
+// Cancelling a running pipeline: queued items are dropped and the outputs
+// that made it through are kept.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use rust_spp::*;
+
+mod common;
+use common::executors;
+
+#[test]
+fn cancel_drops_the_queued_items() {
+    for executor in executors() {
+        let processed = Arc::new(AtomicUsize::new(0));
+        let counter = processed.clone();
+        let pipeline = pipeline![
+            config = PipelineConfig::new().with_executor(executor);
+            parallel_ordered!({
+                let counter = counter.clone();
+                move |item: u64| {
+                    thread::sleep(Duration::from_millis(2));
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    Some(item)
+                }
+            }, 2),
+            collect_ordered!()
+        ];
+        for item in 0..1000 {
+            pipeline.post(item).unwrap();
+        }
+        thread::sleep(Duration::from_millis(20));
+        let started = Instant::now();
+        pipeline.cancel();
+        let collected = pipeline.collect().unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(1), "{:?}", executor);
+        assert!(processed.load(Ordering::SeqCst) < 1000, "{:?}", executor);
+        // The ordered sink only kept a prefix of the posted items
+        assert_eq!(collected, (0..collected.len() as u64).collect::<Vec<_>>(), "{:?}", executor);
+    }
+}
+
+#[test]
+fn posting_fails_once_cancelled() {
+    let mut pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 2),
+        collect!()
+    ];
+    pipeline.post(1).unwrap();
+    let handle = pipeline.cancel_handle();
+    handle.cancel();
+    assert!(pipeline.is_cancelled());
+    assert!(matches!(pipeline.post(2), Err(ItemPostError::Cancelled)));
+    assert!(matches!(pipeline.try_post(3), Err(TryPostError::Cancelled(3))));
+    assert!(matches!(pipeline.post_source(0..10), Err(ItemPostError::Cancelled)));
+    assert!(pipeline.collect().unwrap().len() <= 1);
+}
+
+#[test]
+fn timeout_stops_an_endless_source() {
+    let pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 2, bounded(16)),
+        sequential!(|_item: u64| ())
+    ];
+    pipeline.cancel_handle().cancel_after(Duration::from_millis(50));
+    let started = Instant::now();
+    let collected = pipeline.run_source(0..).unwrap();
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert!(!collected.is_empty());
+}
+
+#[test]
+fn cancel_from_another_thread_unblocks_collect() {
+    let pipeline = pipeline![
+        parallel!(|item: u64| { thread::sleep(Duration::from_millis(1)); Some(item) }, 1),
+        collect!()
+    ];
+    for item in 0..10_000 {
+        pipeline.post(item).unwrap();
+    }
+    let handle = pipeline.cancel_handle();
+    let canceller = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(20));
+        handle.cancel();
+    });
+    let collected = pipeline.collect().unwrap();
+    canceller.join().unwrap();
+    assert!(collected.len() < 10_000);
+}
+
+#[test]
+fn stream_ends_early_once_cancelled() {
+    let pipeline = pipeline![
+        parallel_ordered!(|item: u64| { thread::sleep(Duration::from_millis(1)); Some(item) }, 2),
+        collect_ordered!()
+    ];
+    for item in 0..5_000 {
+        pipeline.post(item).unwrap();
+    }
+    let handle = pipeline.cancel_handle();
+    let mut streamed = vec![];
+    for output in pipeline.into_stream() {
+        streamed.push(output.unwrap());
+        if streamed.len() == 10 {
+            handle.cancel();
+        }
+    }
+    assert!(streamed.len() < 5_000);
+    assert_eq!(streamed, (0..streamed.len() as u64).collect::<Vec<_>>());
+}
+
+#[test]
+fn failures_before_the_cancellation_are_reported() {
+    let pipeline = pipeline![
+        try_parallel!(|item: u64| if item == 0 { Err("bad item") } else { Ok(Some(item)) }, 1),
+        collect!()
+    ];
+    pipeline.post(0).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    pipeline.cancel();
+    assert_eq!(pipeline.collect().unwrap_err().order, 0);
+}
@@ -0,0 +1,9 @@
+// Fixtures shared by the integration tests.
+
+use rust_spp::Executor;
+
+//The executors pipelines under test run on: OS threads, and a tokio runtime
+//with fewer workers than most pipelines have replicas
+pub fn executors() -> Vec<Executor> {
+    vec![Executor::Threads, Executor::Tokio { worker_threads: 2 }]
+}
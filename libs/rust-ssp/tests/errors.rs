@@ -0,0 +1,104 @@
+// Panics and errors raised by stages must surface through collect and
+// end_and_wait instead of killing monitor threads or hanging the pipeline.
+
+use std::thread;
+use std::time::Duration;
+use rust_spp::*;
+
+#[test]
+fn panicking_stage_is_reported_with_stage_and_order() {
+    let pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 4),
+        parallel!(|item: u64| {
+            if item == 42 {
+                panic!("bad item {}", item);
+            }
+            Some(item)
+        }, 4),
+        collect!()
+    ];
+    for item in 0..100 {
+        pipeline.post(item).unwrap();
+    }
+    let error = pipeline.collect().unwrap_err();
+    assert_eq!(error.stage, 1);
+    assert_eq!(error.order, 42);
+    assert_eq!(error.kind, PipelineErrorKind::Panicked("bad item 42".to_string()));
+}
+
+//The sink is fed by several replicas, so items reach it in any order
+#[test]
+fn panicking_sink_is_reported_with_the_posted_order() {
+    let pipeline = pipeline![
+        parallel!(|item: u64| {
+            thread::sleep(Duration::from_millis(20 - item));
+            Some(item)
+        }, 20),
+        sequential!(|item: u64| {
+            if item == 19 {
+                panic!("bad item {}", item);
+            }
+            item
+        })
+    ];
+    for item in 0..20 {
+        pipeline.post(item).unwrap();
+    }
+    let error = pipeline.collect().unwrap_err();
+    assert_eq!(error.stage, 1);
+    assert_eq!(error.order, 19);
+}
+
+#[test]
+fn failing_try_stage_is_reported() {
+    let pipeline = pipeline![
+        try_parallel_ordered!(|item: u64| {
+            if item == 7 {
+                Err(format!("cannot handle {}", item))
+            } else {
+                Ok(Some(item))
+            }
+        }, 3),
+        collect_ordered!()
+    ];
+    for item in 0..20 {
+        pipeline.post(item).unwrap();
+    }
+    let error = pipeline.collect().unwrap_err();
+    assert_eq!(error.stage, 0);
+    assert_eq!(error.order, 7);
+    assert_eq!(error.kind, PipelineErrorKind::Failed("cannot handle 7".to_string()));
+    assert_eq!(error.to_string(), "stage 0 failed on item 7: cannot handle 7");
+}
+
+#[test]
+fn try_stage_without_errors_collects_everything() {
+    let pipeline = pipeline![
+        try_parallel!(|item: u64| -> Result<Option<u64>, String> {
+            Ok(if item.is_multiple_of(2) { Some(item) } else { None })
+        }, 4),
+        collect_ordered!()
+    ];
+    for item in 0..10 {
+        pipeline.post(item).unwrap();
+    }
+    assert_eq!(pipeline.collect().unwrap(), vec![0, 2, 4, 6, 8]);
+}
+
+#[test]
+fn panicking_sink_does_not_hang_end_and_wait() {
+    let mut pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 2),
+        sequential_ordered!(|item: u64| {
+            if item == 3 {
+                panic!("sink failure");
+            }
+        })
+    ];
+    for item in 0..10 {
+        pipeline.post(item).unwrap();
+    }
+    let error = pipeline.end_and_wait().unwrap_err();
+    assert_eq!(error.stage, 1);
+    assert_eq!(error.order, 3);
+}
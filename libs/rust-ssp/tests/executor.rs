@@ -0,0 +1,99 @@
+// The same pipelines on OS threads and on a tokio runtime, with sync and
+// async stages.
+
+use std::time::Duration;
+use rust_spp::*;
+
+fn executors() -> Vec<Executor> {
+    vec![
+        Executor::Threads,
+        Executor::Tokio { worker_threads: 1 },
+        Executor::Tokio { worker_threads: 4 },
+    ]
+}
+
+#[test]
+fn same_definition_runs_on_every_executor() {
+    for executor in executors() {
+        for replicas in 1..6 {
+            let pipeline = pipeline![
+                config = PipelineConfig::new().with_executor(executor);
+                parallel!(|item: u64| if item.is_multiple_of(5) { None } else { Some(item) }, replicas, bounded(2)),
+                async_parallel_ordered!(|item: u64| async move {
+                    tokio1::time::sleep(Duration::from_micros(item % 7)).await;
+                    Some(item * 2)
+                }, replicas + 1, bounded(1)),
+                parallel!(|item: u64| Some(item + 1), replicas),
+                collect_ordered!()
+            ];
+            for item in 0..200 {
+                pipeline.post(item).unwrap();
+            }
+            let expected: Vec<u64> = (0..200)
+                .filter(|item: &u64| !item.is_multiple_of(5))
+                .map(|item| item * 2 + 1)
+                .collect();
+            assert_eq!(pipeline.collect().unwrap(), expected, "{:?}", executor);
+        }
+    }
+}
+
+#[test]
+fn unordered_sinks_on_tasks_terminate() {
+    for i in 0..200 {
+        let mut pipeline = pipeline![
+            config = PipelineConfig::new().with_executor(Executor::Tokio { worker_threads: (i % 3) + 1 });
+            parallel!(|item: usize| Some(item), (i % 4) as i32 + 1),
+            parallel_sink!(|_item: usize| {}, (i % 5) as i32 + 1)
+        ];
+        for item in 0..(i % 7) {
+            pipeline.post(item).unwrap();
+        }
+        pipeline.end_and_wait().unwrap();
+    }
+}
+
+#[test]
+fn async_stage_errors_are_reported() {
+    for executor in executors() {
+        let pipeline = Pipeline::builder()
+            .config(PipelineConfig::new().with_executor(executor))
+            .stage(try_async_parallel!(|item: u64| async move {
+                if item == 13 {
+                    Err(format!("cannot read {}", item))
+                } else {
+                    Ok(Some(item))
+                }
+            }, 3))
+            .collect()
+            .build();
+        for item in 0..50 {
+            pipeline.post(item).unwrap();
+        }
+        let error = pipeline.collect().unwrap_err();
+        assert_eq!(error.stage, 0);
+        assert_eq!(error.order, 13);
+        assert_eq!(error.kind, PipelineErrorKind::Failed("cannot read 13".to_string()));
+    }
+}
+
+#[test]
+fn async_stage_panics_are_reported() {
+    let pipeline = Pipeline::builder()
+        .config(PipelineConfig::new().with_executor(Executor::tokio()))
+        .async_stage_parallel(|item: u64| async move {
+            tokio1::task::yield_now().await;
+            if item == 3 {
+                panic!("async failure");
+            }
+            Some(item)
+        }, 2)
+        .collect()
+        .build();
+    for item in 0..10 {
+        pipeline.post(item).unwrap();
+    }
+    let error = pipeline.collect().unwrap_err();
+    assert_eq!(error.order, 3);
+    assert_eq!(error.kind, PipelineErrorKind::Panicked("async failure".to_string()));
+}
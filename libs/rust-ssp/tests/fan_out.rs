@@ -0,0 +1,127 @@
+// One-to-many and many-to-one stages: items are renumbered after them, so
+// ordered stages downstream still see every order exactly once.
+
+use std::thread;
+use std::time::Duration;
+use rust_spp::*;
+
+mod common;
+use common::executors;
+
+#[test]
+fn ordered_fan_out_keeps_post_order() {
+    for executor in executors() {
+        for i in 0..100 {
+            let pipeline = pipeline![
+                config = PipelineConfig::new().with_executor(executor);
+                parallel_many_ordered!(|item: usize| (0..item % 4).map(move |copy| item * 10 + copy), (i % 5) as i32 + 1),
+                parallel_ordered!(|item: usize| Some(item), (i % 3) as i32 + 1),
+                collect_ordered!()
+            ];
+            for item in 0..(i % 23) {
+                pipeline.post(item).unwrap();
+            }
+            let expected: Vec<usize> = (0..(i % 23))
+                .flat_map(|item| (0..item % 4).map(move |copy| item * 10 + copy))
+                .collect();
+            assert_eq!(pipeline.collect().unwrap(), expected, "{:?}", executor);
+        }
+    }
+}
+
+#[test]
+fn unordered_fan_out_gives_dense_orders() {
+    for i in 0..200 {
+        let pipeline = pipeline![
+            parallel_many!(|item: usize| vec![item; item % 3], (i % 4) as i32 + 1),
+            collect_ordered!()
+        ];
+        for item in 0..(i % 19) {
+            pipeline.post(item).unwrap();
+        }
+        let mut collected = pipeline.collect().unwrap();
+        collected.sort();
+        let expected: Vec<usize> = (0..(i % 19)).flat_map(|item| vec![item; item % 3]).collect();
+        assert_eq!(collected, expected);
+    }
+}
+
+#[test]
+fn batches_keep_post_order() {
+    for executor in executors() {
+        for i in 0..100 {
+            let items = i % 31;
+            let size = i % 6 + 1;
+            let pipeline = pipeline![
+                config = PipelineConfig::new().with_executor(executor);
+                parallel_ordered!(|item: usize| if item.is_multiple_of(7) { None } else { Some(item) }, 3),
+                batch!(size, Duration::from_secs(60)),
+                collect_ordered!()
+            ];
+            for item in 0..items {
+                pipeline.post(item).unwrap();
+            }
+            let kept: Vec<usize> = (0..items).filter(|item| !item.is_multiple_of(7)).collect();
+            let expected: Vec<Vec<usize>> = kept.chunks(size).map(|chunk| chunk.to_vec()).collect();
+            assert_eq!(pipeline.collect().unwrap(), expected, "{:?}", executor);
+        }
+    }
+}
+
+#[test]
+fn bounded_batches_keep_post_order() {
+    for executor in executors() {
+        let pipeline = pipeline![
+            config = PipelineConfig::new().with_executor(executor);
+            batch!(4, Duration::from_secs(60), bounded(1)),
+            parallel!(|batch: Vec<usize>| {
+                thread::sleep(Duration::from_millis(1));
+                Some(batch)
+            }, 1, bounded(1)),
+            collect_ordered!()
+        ];
+        for item in 0..50 {
+            pipeline.post(item).unwrap();
+        }
+        let expected: Vec<Vec<usize>> = (0..50).collect::<Vec<_>>().chunks(4).map(|chunk| chunk.to_vec()).collect();
+        assert_eq!(pipeline.collect().unwrap(), expected, "{:?}", executor);
+    }
+}
+
+#[test]
+fn batches_leave_after_timeout() {
+    for executor in executors() {
+        let pipeline = Pipeline::builder()
+            .config(PipelineConfig::new().with_executor(executor))
+            .batch(100, Duration::from_millis(10))
+            .collect()
+            .build();
+        for item in 0..3 {
+            pipeline.post(item).unwrap();
+        }
+        thread::sleep(Duration::from_millis(300));
+        for item in 3..5 {
+            pipeline.post(item).unwrap();
+        }
+        assert_eq!(pipeline.collect().unwrap(), vec![vec![0, 1, 2], vec![3, 4]], "{:?}", executor);
+    }
+}
+
+#[test]
+fn latency_follows_renumbered_items() {
+    let mut pipeline = Pipeline::builder()
+        .config(PipelineConfig::new().metrics(true))
+        .stage_many(|item: u64| vec![item, item], 2)
+        .batch(3, Duration::from_millis(1))
+        .sink(|_batch: Vec<u64>| {})
+        .build();
+    for item in 0..30 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let metrics = pipeline.metrics().unwrap();
+    assert_eq!(metrics.stages[0].items, 30);
+    assert_eq!(metrics.stages[1].items, 60);
+    assert_eq!(metrics.stages[2].items, 20);
+    assert_eq!(metrics.latency.unwrap().items, 20);
+}
@@ -0,0 +1,153 @@
+// Feedback loops: items go around until the last stage of the loop lets them
+// out, and the stream only ends once the loop is empty.
+
+use std::time::Duration;
+use rust_spp::*;
+
+mod common;
+use common::executors;
+
+// Steps of the Collatz sequence until reaching 1
+fn collatz_steps(mut value: u64) -> u64 {
+    let mut steps = 0;
+    while value != 1 {
+        value = if value.is_multiple_of(2) { value / 2 } else { 3 * value + 1 };
+        steps += 1;
+    }
+    steps
+}
+
+#[test]
+fn items_go_around_until_done() {
+    for executor in executors() {
+        for i in 0..30 {
+            let pipeline = Pipeline::builder()
+                .config(PipelineConfig::new().with_executor(executor))
+                .stage(parallel!(|start: u64| Some((start, start, 0u64)), 2))
+                .feedback(|body| body
+                    .stage(parallel!(|(start, value, steps): (u64, u64, u64)| Some(
+                        if value == 1 {
+                            Feedback::Done((start, steps))
+                        } else if value.is_multiple_of(2) {
+                            Feedback::Again((start, value / 2, steps + 1))
+                        } else {
+                            Feedback::Again((start, 3 * value + 1, steps + 1))
+                        }
+                    ), i % 4 + 1)))
+                .collect_ordered()
+                .build();
+            for start in 1..=(i as u64 * 3) {
+                pipeline.post(start).unwrap();
+            }
+            let mut collected = pipeline.collect().unwrap();
+            collected.sort();
+            let expected: Vec<(u64, u64)> = (1..=(i as u64 * 3)).map(|start| (start, collatz_steps(start))).collect();
+            assert_eq!(collected, expected, "{:?}", executor);
+        }
+    }
+}
+
+#[test]
+fn loop_body_can_hold_several_stages() {
+    for i in 0..30 {
+        let pipeline = Pipeline::builder()
+            .feedback(|body| body
+                .stage(parallel_ordered!(|(item, rounds): (u64, u32)| Some((item, rounds + 1)), i % 3 + 1))
+                .stage(parallel!(|(item, rounds): (u64, u32)| Some((item * 2, rounds)), 2))
+                .stage(parallel!(|(item, rounds): (u64, u32)| Some(
+                    if rounds == 3 { Feedback::Done(item) } else { Feedback::Again((item, rounds)) }
+                ), 1)))
+            .stage(parallel_ordered!(|item: u64| Some(item + 1), 2))
+            .collect()
+            .build();
+        for item in 0..(i as u64) {
+            pipeline.post((item, 0)).unwrap();
+        }
+        let mut collected = pipeline.collect().unwrap();
+        collected.sort();
+        assert_eq!(collected, (0..(i as u64)).map(|item| item * 8 + 1).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn items_can_be_dropped_inside_the_loop() {
+    let pipeline = Pipeline::builder()
+        .feedback(|body| body
+            .stage(parallel!(|item: u64| match item {
+                item if item % 7 == 0 => None,
+                item if item > 100 => Some(Feedback::Done(item)),
+                item => Some(Feedback::Again(item * 3))
+            }, 3)))
+        .collect()
+        .build();
+    for item in 1..50 {
+        pipeline.post(item).unwrap();
+    }
+    let collected = pipeline.collect().unwrap();
+    assert!(collected.iter().all(|item| *item > 100 && item % 7 != 0));
+    assert!(collected.len() < 49);
+}
+
+#[test]
+fn empty_stream_ends() {
+    let mut pipeline = Pipeline::builder()
+        .feedback(|body| body.stage(parallel!(|item: u64| Some(Feedback::<u64, u64>::Done(item)), 2)))
+        .collect()
+        .build();
+    pipeline.end_and_wait().unwrap();
+    assert!(pipeline.collect().unwrap().is_empty());
+}
+
+#[test]
+fn stream_waits_for_items_still_in_the_loop() {
+    let pipeline = Pipeline::builder()
+        .feedback(|body| body
+            .stage(parallel!(|(item, rounds): (u64, u32)| {
+                std::thread::sleep(Duration::from_millis(1));
+                Some(if rounds == 20 { Feedback::Done(item) } else { Feedback::Again((item, rounds + 1)) })
+            }, 2)))
+        .collect()
+        .build();
+    for item in 0..5 {
+        pipeline.post((item, 0)).unwrap();
+    }
+    // Ends while every item still has most of its rounds to go
+    let mut collected = pipeline.collect().unwrap();
+    collected.sort();
+    assert_eq!(collected, vec![0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn failures_inside_the_loop_end_it() {
+    let pipeline = Pipeline::builder()
+        .stage(parallel!(|item: u64| Some(item), 1))
+        .feedback(|body| body
+            .stage(try_parallel!(|item: u64| if item == 40 { Err("too far") } else { Ok(Some(item + 1)) }, 2).label("step"))
+            .stage(parallel!(|item: u64| Some(if item.is_multiple_of(10) { Feedback::Done(item) } else { Feedback::Again(item) }), 1)))
+        .collect()
+        .build();
+    for item in 0..5 {
+        pipeline.post(item * 10).unwrap();
+    }
+    let error = pipeline.collect().unwrap_err();
+    assert_eq!(error.stage, 1);
+    assert_eq!(error.label.as_deref(), Some("step"));
+}
+
+#[test]
+fn latency_follows_items_out_of_the_loop() {
+    let mut pipeline = Pipeline::builder()
+        .config(PipelineConfig::new().metrics(true))
+        .feedback(|body| body
+            .stage(parallel!(|item: u64| Some(if item >= 16 { Feedback::Done(item) } else { Feedback::Again(item * 2) }), 2)))
+        .sink(|_item: u64| {})
+        .build();
+    for item in 1..=20 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let metrics = pipeline.metrics().unwrap();
+    assert!(metrics.stages[0].items > 20);
+    assert_eq!(metrics.stages[1].items, 20);
+    assert_eq!(metrics.latency.unwrap().items, 20);
+}
@@ -0,0 +1,179 @@
+// Keyed stages: items with the same key always reach the same replica, so
+// nodes can keep per-key state without locks.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use rust_spp::*;
+
+mod common;
+use common::executors;
+
+//Counts the items of each key it sees
+#[derive(Clone)]
+struct CountPerKey {
+    seen: HashMap<u64, u64>,
+}
+
+impl InOut<(u64, u64), (u64, u64, u64)> for CountPerKey {
+    fn process(&mut self, (key, item): (u64, u64)) -> Option<(u64, u64, u64)> {
+        let count = self.seen.entry(key).or_insert(0);
+        *count += 1;
+        Some((key, item, *count))
+    }
+}
+
+#[test]
+fn same_key_same_state() {
+    for executor in executors() {
+        for i in 0..30 {
+            let pipeline = pipeline![
+                config = PipelineConfig::new().with_executor(executor);
+                parallel_keyed!(CountPerKey { seen: HashMap::new() }, (i % 4) + 1, |(key, _): &(u64, u64)| *key),
+                collect!()
+            ];
+            for item in 0..(i as u64 * 5) {
+                pipeline.post((item % 7, item)).unwrap();
+            }
+            let mut collected = pipeline.collect().unwrap();
+            collected.sort();
+            //Every key was counted by a single node, in post order
+            let mut expected: Vec<(u64, u64, u64)> = (0..(i as u64 * 5))
+                .map(|item| (item % 7, item, item / 7 + 1))
+                .collect();
+            expected.sort();
+            assert_eq!(collected, expected, "{:?}", executor);
+        }
+    }
+}
+
+#[test]
+fn each_key_stays_on_one_thread() {
+    let pipeline = pipeline![
+        parallel!(|item: u64| Some(format!("file-{}", item % 13)), 3),
+        parallel_keyed!(|file: String| Some((file, thread::current().id())), 4, |file: &String| file.clone()),
+        collect!()
+    ];
+    for item in 0..200 {
+        pipeline.post(item).unwrap();
+    }
+    let mut threads = HashMap::new();
+    for (file, thread) in pipeline.collect().unwrap() {
+        assert_eq!(*threads.entry(file).or_insert(thread), thread);
+    }
+    assert_eq!(threads.len(), 13);
+}
+
+#[test]
+fn keyed_ordered_stage_keeps_post_order() {
+    for i in 0..30 {
+        let pipeline = Pipeline::builder()
+            .stage(parallel!(|item: u64| if item % 5 == 4 { None } else { Some(item) }, 2))
+            .stage(parallel_ordered!(|item: u64| {
+                thread::sleep(Duration::from_micros(item % 3 * 100));
+                Some(item * 2)
+            }, (i % 4) + 1).keyed(|item: &u64| item % 3))
+            .collect_ordered()
+            .build();
+        for item in 0..(i as u64 * 2) {
+            pipeline.post(item).unwrap();
+        }
+        let mut collected = pipeline.collect().unwrap();
+        let expected: Vec<u64> = (0..(i as u64 * 2)).filter(|item| item % 5 != 4).map(|item| item * 2).collect();
+        collected.sort();
+        assert_eq!(collected, expected);
+    }
+}
+
+#[test]
+fn bounded_keyed_queues_fill_up_per_replica() {
+    let key = key_fn(|item: &u64| *item);
+    let busy = (0..).find(|item| key(item) % 2 == 0).unwrap();
+    let other = (0..).find(|item| key(item) % 2 == 1).unwrap();
+    let started = Arc::new(AtomicBool::new(false));
+    let open = Arc::new(AtomicBool::new(false));
+    let (started_flag, open_flag) = (started.clone(), open.clone());
+    let pipeline = Pipeline::builder()
+        .stage(parallel_keyed!({
+            let (started, open) = (started_flag.clone(), open_flag.clone());
+            move |item: u64| {
+                started.store(true, Ordering::SeqCst);
+                while !open.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Some(item)
+            }
+        }, 2, |item: &u64| *item, bounded(1)))
+        .collect()
+        .build();
+    pipeline.post(busy).unwrap();
+    //The replica of the first key is stuck on it, with its queue empty
+    while !started.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(pipeline.try_post(busy).is_ok());
+    assert!(matches!(pipeline.try_post(busy), Err(TryPostError::Full(item)) if item == busy));
+    //The other replica has a queue of its own
+    assert!(pipeline.try_post(other).is_ok());
+    open.store(true, Ordering::SeqCst);
+    let mut collected = pipeline.collect().unwrap();
+    collected.sort();
+    let mut expected = vec![busy, busy, other];
+    expected.sort();
+    assert_eq!(collected, expected);
+}
+
+//A producer waiting for room in the queue of one key leaves the others free
+//to post items with other keys
+#[test]
+fn full_replica_queue_only_holds_back_its_keys() {
+    let key = key_fn(|item: &u64| *item);
+    let busy = (0..).find(|item| key(item) % 2 == 0).unwrap();
+    let other = (0..).find(|item| key(item) % 2 == 1).unwrap();
+    let open = Arc::new(AtomicBool::new(false));
+    let open_flag = open.clone();
+    let mut pipeline = Pipeline::builder()
+        .stage(parallel_keyed!({
+            let open = open_flag.clone();
+            move |item: u64| {
+                while !open.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Some(item)
+            }
+        }, 2, |item: &u64| *item, bounded(1)))
+        .collect()
+        .build();
+    //The replica of the busy key holds one item and its queue another, so
+    //the source waits for room with the third one
+    pipeline.post_source(vec![busy; 3]).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert!(pipeline.try_post(other).is_ok());
+    open.store(true, Ordering::SeqCst);
+    let mut collected = pipeline.collect().unwrap();
+    collected.sort();
+    let mut expected = vec![busy, busy, busy, other];
+    expected.sort();
+    assert_eq!(collected, expected);
+}
+
+#[test]
+fn builder_keyed_stage_with_labels() {
+    let mut pipeline = Pipeline::builder()
+        .config(PipelineConfig::new().metrics(true))
+        .stage(parallel_keyed!(label = "per-face"; |item: u64| Some(item), 3, |item: &u64| item % 4))
+        .stage_parallel_keyed(|item: u64| Some(item + 1), 2, |item: &u64| *item)
+        .sink(|_item: u64| {})
+        .build();
+    for item in 0..40 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let metrics = pipeline.metrics().unwrap();
+    assert_eq!(metrics.stages[0].label.as_deref(), Some("per-face"));
+    assert_eq!(metrics.stages[0].replicas.len(), 3);
+    assert_eq!(metrics.stages[0].items, 40);
+    assert_eq!(metrics.stages[1].items, 40);
+}
@@ -0,0 +1,97 @@
+// Stage labels: thread names, error reports and metrics.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use rust_spp::*;
+
+fn thread_name() -> String {
+    thread::current().name().unwrap_or("").to_string()
+}
+
+#[test]
+fn replica_threads_are_named_after_their_stage() {
+    let pipeline = pipeline![
+        parallel!(label = "decode"; |item: u64| Some((item, thread_name())), 2),
+        parallel!(|(item, decode): (u64, String)| Some((item, decode, thread_name())), 1),
+        collect!(label = "gather")
+    ];
+    for item in 0..20 {
+        pipeline.post(item).unwrap();
+    }
+    let collected = pipeline.collect().unwrap();
+    assert_eq!(collected.len(), 20);
+    for (_, decode, second) in collected {
+        assert!(decode == "decode-0" || decode == "decode-1", "{}", decode);
+        assert_eq!(second, "stage-1-0");
+    }
+}
+
+#[test]
+fn sink_and_source_threads_are_named() {
+    let sink = Arc::new(Mutex::new(vec![]));
+    let source = Arc::new(Mutex::new(vec![]));
+    let items = {
+        let source = source.clone();
+        (0..5u64).inspect(move |_| source.lock().unwrap().push(thread_name()))
+    };
+    let names = sink.clone();
+    Pipeline::builder()
+        .stage(parallel!(|item: u64| Some(item), 2))
+        .sink_stage(sequential!({
+            let names = names.clone();
+            move |_item: u64| names.lock().unwrap().push(thread_name())
+        }).label("write"))
+        .run_source(items)
+        .unwrap();
+    assert_eq!(sink.lock().unwrap().len(), 5);
+    assert!(sink.lock().unwrap().iter().all(|name| name == "write-0"));
+    assert!(source.lock().unwrap().iter().all(|name| name == "rust-spp-source"));
+}
+
+#[test]
+fn errors_name_the_stage() {
+    let pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 2),
+        try_parallel!(label = "parse"; |item: u64| if item == 3 { Err("not a number") } else { Ok(Some(item)) }, 2),
+        collect!()
+    ];
+    for item in 0..10 {
+        pipeline.post(item).unwrap();
+    }
+    let error = pipeline.collect().unwrap_err();
+    assert_eq!(error.label.as_deref(), Some("parse"));
+    assert_eq!(error.to_string(), "stage 1 (parse) failed on item 3: not a number");
+}
+
+#[test]
+fn panics_name_the_stage() {
+    let pipeline = pipeline![
+        parallel!(label = "explode"; |item: u64| if item == 2 { panic!("boom") } else { Some(item) }, 1, bounded(4)),
+        collect!()
+    ];
+    for item in 0..5 {
+        pipeline.post(item).unwrap();
+    }
+    let error = pipeline.collect().unwrap_err();
+    assert_eq!(error.to_string(), "stage 0 (explode) panicked on item 2: boom");
+}
+
+#[test]
+fn metrics_carry_the_labels() {
+    let mut pipeline = Pipeline::builder()
+        .config(PipelineConfig::new().metrics(true))
+        .stage(parallel_many!(|item: u64| vec![item, item], 2).label("split"))
+        .stage(batch!(label = "group"; 4, Duration::from_millis(1)))
+        .stage(parallel!(|batch: Vec<u64>| Some(batch.len()), 1))
+        .collect()
+        .build();
+    for item in 0..8 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let metrics = pipeline.metrics().unwrap();
+    let labels: Vec<Option<&str>> = metrics.stages.iter().map(|stage| stage.label.as_deref()).collect();
+    assert_eq!(labels, vec![Some("split"), Some("group"), None, None]);
+    assert!(metrics.to_json().contains("\"label\": \"group\""));
+}
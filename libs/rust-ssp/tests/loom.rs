@@ -0,0 +1,196 @@
+// Model checks of the stage queues and of the end of the stream under loom,
+// which runs every test over all the interleavings of its threads (up to a
+// few preemptions each). Only built with the loom cfg:
+//
+//     RUSTFLAGS="--cfg spp_loom" cargo test --release --test loom
+//
+// Loom primitives only work inside a model, so the queues and blocks are
+// created in each test closure, and the threads are loom threads.
+#![cfg(spp_loom)]
+
+use std::sync::Arc;
+use loom::sync::Mutex;
+use loom::thread;
+use rust_spp::*;
+
+fn model<F: Fn() + Sync + Send + 'static>(test: F) {
+    let mut builder = loom::model::Builder::new();
+    builder.preemption_bound = Some(3);
+    builder.check(test);
+}
+
+//Dequeues until the Stop, returning the values
+fn drain(queue: &BlockingQueue<u64>) -> Vec<u64> {
+    let mut items = vec![];
+    loop {
+        match queue.wait_and_dequeue() {
+            TimestampedWorkItem(WorkItem::Value(item), _) => items.push(item),
+            TimestampedWorkItem(WorkItem::Dropped, _) => {}
+            TimestampedWorkItem(WorkItem::Stop, _) => return items,
+        }
+    }
+}
+
+#[test]
+fn every_consumer_sees_the_stop_after_the_items() {
+    model(|| {
+        let queue = BlockingQueue::new();
+        let consumers: Vec<_> = (0..2)
+            .map(|_| {
+                let queue = queue.clone();
+                thread::spawn(move || drain(&queue))
+            })
+            .collect();
+        queue.enqueue(WorkItem::Value(1));
+        queue.enqueue(WorkItem::Dropped);
+        assert_eq!(queue.enqueue(WorkItem::Stop), 2);
+        let mut items: Vec<u64> = consumers.into_iter().flat_map(|consumer| consumer.join().unwrap()).collect();
+        items.sort();
+        assert_eq!(items, vec![1]);
+        //The Stop stays for whoever comes next
+        assert_eq!(queue.len(), 1);
+    });
+}
+
+#[test]
+fn bounded_queue_producers_wait_for_room() {
+    model(|| {
+        let queue = BlockingQueue::bounded(1);
+        let producers: Vec<_> = (0..2)
+            .map(|producer| {
+                let queue = queue.clone();
+                thread::spawn(move || { queue.enqueue(WorkItem::Value(producer)); })
+            })
+            .collect();
+        let mut items = vec![];
+        for _ in 0..2 {
+            assert!(queue.len() <= 1);
+            match queue.wait_and_dequeue() {
+                TimestampedWorkItem(WorkItem::Value(item), _) => items.push(item),
+                _ => panic!("only values were enqueued"),
+            }
+        }
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        items.sort();
+        assert_eq!(items, vec![0, 1]);
+    });
+}
+
+#[test]
+fn ordered_set_gives_items_back_in_order() {
+    model(|| {
+        let set = BlockingOrderedSet::new();
+        let producers: Vec<_> = [(1, WorkItem::Value(1)), (0, WorkItem::Value(0)), (2, WorkItem::Stop)]
+            .into_iter()
+            .map(|(order, item)| {
+                let set = set.clone();
+                thread::spawn(move || set.enqueue(TimestampedWorkItem(item, order)))
+            })
+            .collect();
+        assert!(matches!(set.wait_and_remove(0), TimestampedWorkItem(WorkItem::Value(0), 0)));
+        assert!(matches!(set.wait_and_remove(1), TimestampedWorkItem(WorkItem::Value(1), 1)));
+        assert!(matches!(set.wait_and_remove(2), TimestampedWorkItem(WorkItem::Stop, 2)));
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        assert!(set.is_empty());
+    });
+}
+
+#[test]
+fn windowed_ordered_set_holds_one_item() {
+    model(|| {
+        let set = BlockingOrderedSet::windowed(1);
+        let producers: Vec<_> = (0..2u64)
+            .rev()
+            .map(|order| {
+                let set = set.clone();
+                thread::spawn(move || set.enqueue(TimestampedWorkItem(WorkItem::Value(order), order)))
+            })
+            .collect();
+        for order in 0..2 {
+            assert!(matches!(set.wait_and_remove(order), TimestampedWorkItem(WorkItem::Value(item), _) if item == order));
+        }
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        assert_eq!(set.high_water(), 1);
+    });
+}
+
+//What the block after the one under test received
+#[derive(Debug, PartialEq, Eq)]
+enum Seen {
+    Value(u64),
+    Dropped(u64),
+    Stop,
+}
+
+struct Recorder(Arc<Mutex<Vec<Seen>>>);
+
+impl PipelineBlock<u64, ()> for Recorder {
+    fn process(&self, _input: WorkItem<u64>) -> u64 {
+        unreachable!("only the block under test posts here")
+    }
+
+    fn try_process(&self, _input: u64) -> Result<u64, u64> {
+        unreachable!("only the block under test posts here")
+    }
+
+    fn process_timestamped(&self, input: TimestampedWorkItem<u64>) {
+        self.0.lock().unwrap().push(match input {
+            TimestampedWorkItem(WorkItem::Value(item), _) => Seen::Value(item),
+            TimestampedWorkItem(WorkItem::Dropped, order) => Seen::Dropped(order),
+            TimestampedWorkItem(WorkItem::Stop, _) => Seen::Stop,
+        });
+    }
+
+    fn collect(self: Box<Self>) -> Vec<()> {
+        vec![]
+    }
+}
+
+//Runs a block with two replicas over two items and the Stop, and returns
+//what it forwarded. The second item is filtered out
+fn run_block(ordering: OrderingMode) -> Vec<Seen> {
+    let seen = Arc::new(Mutex::new(vec![]));
+    let factory: TransformerFactory<u64, u64> = Box::new(|| Box::new(FromInOut(
+        |item: u64| if item == 1 { None } else { Some(item * 10) })));
+    let mut block = InOutBlock::new_block(
+        Box::new(Recorder(seen.clone())), factory, 2, ordering, QueueMode::Unbounded);
+    let context = StageContext::new(0, &PipelineContext::new(&PipelineConfig::new()), None);
+    let replicas: Vec<_> = block.monitor_posts(&context)
+        .into_iter()
+        .map(|monitor| thread::spawn(move || monitor.run()))
+        .collect();
+    block.process(WorkItem::Value(0));
+    block.process(WorkItem::Value(1));
+    block.process(WorkItem::Stop);
+    for replica in replicas {
+        replica.join().unwrap();
+    }
+    let mut seen = seen.lock().unwrap();
+    std::mem::take(&mut *seen)
+}
+
+#[test]
+fn stop_is_forwarded_once_after_every_item() {
+    model(|| {
+        let mut seen = run_block(OrderingMode::Unordered);
+        assert_eq!(seen.pop(), Some(Seen::Stop));
+        seen.sort_by_key(|seen| match seen {
+            Seen::Value(item) => *item,
+            _ => u64::MAX,
+        });
+        assert_eq!(seen, vec![Seen::Value(0), Seen::Dropped(1)]);
+    });
+}
+
+#[test]
+fn ordered_block_forwards_in_order_then_stops() {
+    model(|| {
+        assert_eq!(run_block(OrderingMode::Ordered), vec![Seen::Value(0), Seen::Dropped(1), Seen::Stop]);
+    });
+}
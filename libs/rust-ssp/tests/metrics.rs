@@ -0,0 +1,150 @@
+// Opt-in runtime metrics: per stage counters, queue samples and latency.
+
+use rust_spp::*;
+
+#[test]
+fn metrics_are_off_by_default() {
+    let mut pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 2),
+        sequential!(|_item: u64| {})
+    ];
+    pipeline.post(1).unwrap();
+    pipeline.end_and_wait().unwrap();
+    assert!(pipeline.metrics().is_none());
+}
+
+#[test]
+fn stages_count_processed_items_per_replica() {
+    let mut pipeline = pipeline![
+        config = PipelineConfig::new().metrics(true);
+        parallel!(|item: u64| if item.is_multiple_of(2) { Some(item) } else { None }, 4),
+        parallel_ordered!(|item: u64| Some(item * 10), 3),
+        sequential!(|_item: u64| {})
+    ];
+    for item in 0..100 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+
+    let metrics = pipeline.metrics().unwrap();
+    let stages: Vec<_> = metrics.stages.iter().map(|stage| stage.stage).collect();
+    assert_eq!(stages, vec![0, 1, 2]);
+    assert_eq!(metrics.stages[0].items, 100);
+    assert_eq!(metrics.stages[1].items, 50);
+    assert_eq!(metrics.stages[2].items, 50);
+    assert_eq!(metrics.stages[0].replicas.len(), 4);
+    assert_eq!(metrics.stages[1].replicas.len(), 3);
+    assert_eq!(metrics.stages[2].replicas.len(), 1);
+    assert!(!metrics.stages[0].queue_samples.is_empty());
+
+    let latency = metrics.latency.unwrap();
+    assert_eq!(latency.items, 50);
+    assert!(latency.min <= latency.p50 && latency.p50 <= latency.p99 && latency.p99 <= latency.max);
+}
+
+#[test]
+fn metrics_dump_as_json() {
+    let mut pipeline = pipeline![
+        config = PipelineConfig::new().sample_queues_every(std::time::Duration::from_millis(5));
+        parallel!(|item: u64| Some(item), 2),
+        collect_ordered!()
+    ];
+    for item in 0..10 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let json = pipeline.metrics().unwrap().to_json();
+    assert!(json.contains("\"stages\""));
+    assert!(json.contains("\"busy\""));
+    assert!(json.contains("\"p99\""));
+    assert_eq!(pipeline.collect().unwrap(), (0..10).collect::<Vec<_>>());
+}
+
+//Entry times are only kept while items are in flight, whatever happens to
+//the items on the way
+#[test]
+fn entry_times_are_forgotten() {
+    let mut pipeline = Pipeline::builder()
+        .config(PipelineConfig::new().metrics(true))
+        .stage(parallel!(|item: u64| if item.is_multiple_of(5) { None } else { Some(item) }, 3))
+        .stage_many(|item: u64| vec![item; (item % 3) as usize], 2)
+        .batch(4, std::time::Duration::from_millis(1))
+        .stage(parallel!(|batch: Vec<u64>| {
+            if batch.contains(&7) {
+                panic!("bad batch");
+            }
+            Some(batch.len())
+        }, 2))
+        .sink(|_size: usize| {})
+        .build();
+    for item in 0..200 {
+        pipeline.post(item).unwrap();
+    }
+    assert!(pipeline.end_and_wait().is_err());
+    let metrics = pipeline.metrics().unwrap();
+    assert_eq!(metrics.items_in_flight, 0);
+    assert!(metrics.latency.unwrap().items > 0);
+}
+
+#[test]
+fn entry_times_of_looping_and_split_items_are_forgotten() {
+    let mut pipeline = Pipeline::builder()
+        .config(PipelineConfig::new().metrics(true))
+        .feedback(|body| body
+            .stage(parallel!(|item: u64| if item == 9 { None } else { Some(if item >= 16 { Feedback::Done(item) } else { Feedback::Again(item * 2) }) }, 2)))
+        .stage(split!(broadcast;
+            [parallel!(|item: u64| Some(item + 1), 2)],
+            [parallel!(|item: u64| if item.is_multiple_of(2) { None } else { Some(item) }, 2)]))
+        .stage(merge!())
+        .sink(|_outputs: Vec<Option<u64>>| {})
+        .build();
+    for item in 1..=50 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let metrics = pipeline.metrics().unwrap();
+    assert_eq!(metrics.items_in_flight, 0);
+    assert_eq!(metrics.latency.unwrap().items, 49);
+}
+
+#[test]
+fn latency_percentiles_are_close_to_the_exact_ones() {
+    let mut pipeline = pipeline![
+        config = PipelineConfig::new().metrics(true);
+        sequential!(|item: u64| std::thread::sleep(std::time::Duration::from_millis(item % 4)))
+    ];
+    for item in 0..40 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let latency = pipeline.metrics().unwrap().latency.unwrap();
+    assert_eq!(latency.items, 40);
+    assert!(latency.min <= latency.p50 && latency.p50 <= latency.p99 && latency.p99 <= latency.max);
+    assert!(latency.max >= std::time::Duration::from_millis(3));
+    assert!(latency.mean > latency.min && latency.mean < latency.max);
+}
+
+//The first item finishes after the second one was posted and went
+//through, so the sink sees the items in reverse order. Each latency must
+//still be the one of its own item
+#[test]
+fn latencies_follow_items_reaching_the_sink_out_of_order() {
+    let mut pipeline = pipeline![
+        config = PipelineConfig::new().metrics(true);
+        parallel!(|item: u64| {
+            if item == 0 {
+                std::thread::sleep(std::time::Duration::from_millis(400));
+            }
+            Some(item)
+        }, 2),
+        collect!()
+    ];
+    pipeline.post(0).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    pipeline.post(1).unwrap();
+    pipeline.end_and_wait().unwrap();
+    let latency = pipeline.metrics().unwrap().latency.unwrap();
+    assert_eq!(latency.items, 2);
+    assert!(latency.min < std::time::Duration::from_millis(100), "{:?}", latency);
+    assert!(latency.max >= std::time::Duration::from_millis(400), "{:?}", latency);
+}
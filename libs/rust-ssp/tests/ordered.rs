@@ -0,0 +1,158 @@
+// Ordered parallel stages and sinks: replicas finish items in any order, but
+// the stages after them, and the outputs of the pipeline, must see the items
+// in the order they were posted.
+
+use std::thread;
+use std::time::Duration;
+use rust_spp::*;
+
+mod common;
+use common::executors;
+
+const PIPELINES: usize = 100;
+
+fn replicas(i: usize) -> i32 {
+    (i % 6) as i32 + 1
+}
+
+//Early items take the longest, so replicas finish them last
+fn slow_start(item: usize) -> usize {
+    if item < 8 {
+        thread::sleep(Duration::from_millis((8 - item) as u64));
+    }
+    item
+}
+
+//A single replica after the ordered stage, remembering the last item it saw
+#[test]
+fn stateful_stage_sees_the_posted_order() {
+    for executor in executors() {
+        let pipeline = pipeline![
+            config = PipelineConfig::new().with_executor(executor);
+            parallel_ordered!(|item: usize| Some(slow_start(item)), 4),
+            sequential!({
+                let mut last = None;
+                move |item: usize| {
+                    assert!(last < Some(item), "{} came after {:?}", item, last);
+                    last = Some(item);
+                    item
+                }
+            })
+        ];
+        for item in 0..200 {
+            pipeline.post(item).unwrap();
+        }
+        let mut collected = pipeline.collect().unwrap();
+        collected.sort();
+        assert_eq!(collected, (0..200).collect::<Vec<_>>(), "{:?}", executor);
+    }
+}
+
+#[test]
+fn ordered_stage_skips_dropped_items() {
+    for executor in executors() {
+        for i in 0..PIPELINES {
+            let items = i % 43;
+            let pipeline = pipeline![
+                config = PipelineConfig::new().with_executor(executor);
+                parallel_ordered!(|item: usize| if item.is_multiple_of(3) { None } else { Some(item) }, replicas(i)),
+                parallel_ordered!(|item: usize| Some(item * 2), replicas(i + 2)),
+                collect_ordered!()
+            ];
+            for item in 0..items {
+                pipeline.post(item).unwrap();
+            }
+            let expected: Vec<usize> = (0..items).filter(|item| !item.is_multiple_of(3)).map(|item| item * 2).collect();
+            assert_eq!(pipeline.collect().unwrap(), expected, "{:?}", executor);
+        }
+    }
+}
+
+//Early items take the longest, so the unordered stage reverses the order
+#[test]
+fn single_replica_ordered_stage_restores_the_posted_order() {
+    for executor in executors() {
+        let pipeline = pipeline![
+            config = PipelineConfig::new().with_executor(executor);
+            parallel!(|item: u64| {
+                thread::sleep(Duration::from_millis((20 - item) * 5));
+                Some(item)
+            }, 20),
+            parallel_ordered!(|item: u64| Some(item), 1),
+            collect!()
+        ];
+        for item in 0..20 {
+            pipeline.post(item).unwrap();
+        }
+        assert_eq!(pipeline.collect().unwrap(), (0..20).collect::<Vec<_>>(), "{:?}", executor);
+    }
+}
+
+#[test]
+fn ordered_sink_collects_in_posted_order() {
+    for executor in executors() {
+        let pipeline = pipeline![
+            config = PipelineConfig::new().with_executor(executor);
+            parallel_sink_ordered!(|item: usize| slow_start(item) * 2, 4)
+        ];
+        for item in 0..100 {
+            pipeline.post(item).unwrap();
+        }
+        assert_eq!(pipeline.collect().unwrap(), (0..100).map(|item| item * 2).collect::<Vec<_>>(), "{:?}", executor);
+    }
+}
+
+#[test]
+fn ordered_sink_after_unordered_stages_with_drops() {
+    for executor in executors() {
+        for i in 0..PIPELINES {
+            let items = i % 47;
+            let pipeline = pipeline![
+                config = PipelineConfig::new().with_executor(executor);
+                parallel!(|item: usize| if item.is_multiple_of(4) { None } else { Some(item) }, replicas(i)),
+                parallel!(|item: usize| Some(item), replicas(i + 3)),
+                parallel_sink_ordered!(|item: usize| item, replicas(i + 1))
+            ];
+            for item in 0..items {
+                pipeline.post(item).unwrap();
+            }
+            let expected: Vec<usize> = (0..items).filter(|item| !item.is_multiple_of(4)).collect();
+            assert_eq!(pipeline.collect().unwrap(), expected, "{:?}", executor);
+        }
+    }
+}
+
+#[test]
+fn failing_ordered_sink_is_reported() {
+    let pipeline = Pipeline::builder()
+        .stage(parallel!(|item: usize| Some(item), 2))
+        .sink_parallel_ordered(|item: usize| {
+            if item == 5 {
+                panic!("handler failed on {}", item);
+            }
+            item
+        }, 3)
+        .build();
+    for item in 0..20 {
+        pipeline.post(item).unwrap();
+    }
+    let error = pipeline.collect().unwrap_err();
+    assert_eq!(error.stage, 1);
+    assert_eq!(error.order, 5);
+}
+
+#[test]
+fn ordered_sink_streams_in_posted_order() {
+    for executor in executors() {
+        let pipeline = pipeline![
+            config = PipelineConfig::new().with_executor(executor);
+            parallel_ordered!(|item: usize| Some(item), 3),
+            parallel_sink_ordered!(|item: usize| slow_start(item), 4, reorder_window(4))
+        ];
+        for item in 0..60 {
+            pipeline.post(item).unwrap();
+        }
+        let streamed: Vec<usize> = pipeline.into_stream().map(Result::unwrap).collect();
+        assert_eq!(streamed, (0..60).collect::<Vec<_>>(), "{:?}", executor);
+    }
+}
@@ -0,0 +1,87 @@
+// Parallel sinks: every replica gets its own handler and runs at the same
+// time as the others, and the end of the stream must reach all of them,
+// however many of them never saw an item.
+
+use std::sync::{Arc, Barrier};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use rust_spp::*;
+
+mod common;
+use common::executors;
+
+#[test]
+fn each_replica_has_its_own_handler() {
+    for executor in executors() {
+        let handlers = Arc::new(AtomicUsize::new(0));
+        let created = handlers.clone();
+        let pipeline = pipeline![
+            config = PipelineConfig::new().with_executor(executor);
+            parallel!(|item: usize| Some(item), 2),
+            parallel_sink!({ created.fetch_add(1, Ordering::SeqCst); |item: usize| item }, 5)
+        ];
+        for item in 0..100 {
+            pipeline.post(item).unwrap();
+        }
+        let mut collected = pipeline.collect().unwrap();
+        collected.sort();
+        assert_eq!(collected, (0..100).collect::<Vec<_>>());
+        assert_eq!(handlers.load(Ordering::SeqCst), 5, "{:?}", executor);
+    }
+}
+
+//Each replica holds its item until every replica holds one, which only
+//happens if the replicas run at the same time
+#[test]
+fn replicas_run_at_the_same_time() {
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || {
+        let barrier = Arc::new(Barrier::new(4));
+        let pipeline = pipeline![
+            parallel_sink!({ let barrier = barrier.clone(); move |item: usize| { barrier.wait(); item } }, 4)
+        ];
+        for item in 0..4 {
+            pipeline.post(item).unwrap();
+        }
+        done.send(pipeline.collect().unwrap()).unwrap();
+    });
+    let mut collected = finished.recv_timeout(Duration::from_secs(10)).expect("the replicas should run at the same time");
+    collected.sort();
+    assert_eq!(collected, vec![0, 1, 2, 3]);
+}
+
+#[test]
+fn idle_replicas_see_the_end_of_the_stream() {
+    for executor in executors() {
+        for items in 0..4 {
+            let mut pipeline = pipeline![
+                config = PipelineConfig::new().with_executor(executor);
+                parallel_sink!(|item: usize| item, 8)
+            ];
+            for item in 0..items {
+                pipeline.post(item).unwrap();
+            }
+            pipeline.end_and_wait().unwrap();
+        }
+    }
+}
+
+#[test]
+fn outputs_of_every_replica_are_collected() {
+    for executor in executors() {
+        let pipeline = pipeline![
+            config = PipelineConfig::new().with_executor(executor);
+            parallel!(|item: usize| if item.is_multiple_of(5) { None } else { Some(item) }, 3),
+            parallel_sink!(|item: usize| item * 2, 3)
+        ];
+        for item in 0..500 {
+            pipeline.post(item).unwrap();
+        }
+        let mut collected = pipeline.collect().unwrap();
+        collected.sort();
+        let expected: Vec<usize> = (0..500usize).filter(|item| !item.is_multiple_of(5)).map(|item| item * 2).collect();
+        assert_eq!(collected, expected, "{:?}", executor);
+    }
+}
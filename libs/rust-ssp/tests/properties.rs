@@ -0,0 +1,95 @@
+// Property tests: whatever the items, the replicas and the queues, ordered
+// pipelines give back exactly the posted sequence minus the dropped items.
+
+use proptest::prelude::*;
+use rust_spp::*;
+
+fn executor() -> impl Strategy<Value = Executor> {
+    prop_oneof![Just(Executor::Threads), Just(Executor::Tokio { worker_threads: 2 })]
+}
+
+fn queue() -> impl Strategy<Value = QueueMode> {
+    prop_oneof![
+        Just(QueueMode::Unbounded),
+        (1..4usize).prop_map(QueueMode::Bounded),
+        Just(QueueMode::WorkStealing),
+        (1..4usize).prop_map(QueueMode::Ring),
+    ]
+}
+
+//An ordered stage passing items through, in front of the given queue
+fn ordered_stage(replicas: i32, queue: QueueMode) -> InOutStage<u32, u32> {
+    let stage = parallel_ordered!(|item: u32| Some(item), replicas);
+    match queue {
+        QueueMode::Unbounded => stage,
+        QueueMode::Bounded(capacity) => stage.bounded(capacity),
+        QueueMode::WorkStealing => stage.work_stealing(),
+        QueueMode::Ring(capacity) => stage.ring(capacity),
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn ordered_pipeline_keeps_the_posted_sequence(
+        items in prop::collection::vec(any::<u32>(), 0..80),
+        divisor in 1..5u32,
+        replicas in 1..6i32,
+        queue in queue(),
+        executor in executor(),
+    ) {
+        let pipeline = Pipeline::builder()
+            .config(PipelineConfig::new().with_executor(executor))
+            .stage(parallel!(move |item: u32| if item.is_multiple_of(divisor) { None } else { Some(item) }, replicas))
+            .stage(ordered_stage(replicas, queue))
+            .sink_stage(collect_ordered!())
+            .build();
+        for item in &items {
+            pipeline.post(*item).unwrap();
+        }
+        let expected: Vec<u32> = items.iter().copied().filter(|item| !item.is_multiple_of(divisor)).collect();
+        prop_assert_eq!(pipeline.collect().unwrap(), expected);
+    }
+
+    #[test]
+    fn reorder_window_keeps_the_posted_sequence(
+        items in prop::collection::vec(any::<u32>(), 0..80),
+        divisor in 1..5u32,
+        replicas in 1..6i32,
+        window in 1..8usize,
+        executor in executor(),
+    ) {
+        let pipeline = pipeline![
+            config = PipelineConfig::new().with_executor(executor);
+            parallel_ordered!(move |item: u32| if item.is_multiple_of(divisor) { None } else { Some(item) }, replicas, reorder_window(window)),
+            parallel_ordered!(|item: u32| Some(item), replicas),
+            collect_ordered!(reorder_window(window))
+        ];
+        for item in &items {
+            pipeline.post(*item).unwrap();
+        }
+        let expected: Vec<u32> = items.iter().copied().filter(|item| !item.is_multiple_of(divisor)).collect();
+        prop_assert_eq!(pipeline.collect().unwrap(), expected);
+    }
+
+    #[test]
+    fn ordered_sink_after_unordered_stages(
+        items in prop::collection::vec(any::<u32>(), 0..80),
+        divisor in 1..5u32,
+        replicas in 1..6i32,
+        executor in executor(),
+    ) {
+        let pipeline = pipeline![
+            config = PipelineConfig::new().with_executor(executor);
+            parallel!(move |item: u32| if item.is_multiple_of(divisor) { None } else { Some(item) }, replicas),
+            parallel!(|item: u32| Some(item), replicas),
+            sequential_ordered!(|item: u32| item)
+        ];
+        for item in &items {
+            pipeline.post(*item).unwrap();
+        }
+        let expected: Vec<u32> = items.iter().copied().filter(|item| !item.is_multiple_of(divisor)).collect();
+        prop_assert_eq!(pipeline.collect().unwrap(), expected);
+    }
+}
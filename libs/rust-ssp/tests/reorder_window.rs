@@ -0,0 +1,204 @@
+// Reorder windows: ordered stages hold at most a window of items back while
+// an earlier one is late, and the stages in front wait instead.
+
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use rust_spp::*;
+
+mod common;
+use common::executors;
+
+//The first item takes much longer than the others
+fn slow_first(item: u64) -> Option<u64> {
+    if item == 0 {
+        thread::sleep(Duration::from_millis(100));
+    }
+    Some(item)
+}
+
+#[test]
+fn ordered_stage_holds_at_most_the_window() {
+    for executor in executors() {
+        let mut pipeline = pipeline![
+            config = PipelineConfig::new().metrics(true).with_executor(executor);
+            parallel_ordered!(slow_first, 4, reorder_window(8)),
+            collect_ordered!()
+        ];
+        for item in 0..300 {
+            pipeline.post(item).unwrap();
+        }
+        pipeline.end_and_wait().unwrap();
+        let metrics = pipeline.metrics().unwrap();
+        assert!(metrics.stages[0].reorder_high_water <= 8, "{:?}", executor);
+        assert_eq!(pipeline.collect().unwrap(), (0..300).collect::<Vec<_>>(), "{:?}", executor);
+    }
+}
+
+#[test]
+fn without_a_window_the_late_item_holds_everything_back() {
+    let mut pipeline = pipeline![
+        config = PipelineConfig::new().metrics(true);
+        parallel_ordered!(slow_first, 4),
+        collect!()
+    ];
+    for item in 0..300 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    assert!(pipeline.metrics().unwrap().stages[0].reorder_high_water > 8);
+}
+
+#[test]
+fn ordered_sink_holds_at_most_the_window() {
+    for executor in executors() {
+        for window in 1..6 {
+            let mut pipeline = pipeline![
+                config = PipelineConfig::new().metrics(true).with_executor(executor);
+                parallel!(|item: u64| if item % 5 == 3 { None } else { slow_first(item) }, 3),
+                parallel_ordered!(|item: u64| Some(item), 2),
+                collect_ordered!(reorder_window(window))
+            ];
+            for item in 0..100 {
+                pipeline.post(item).unwrap();
+            }
+            pipeline.end_and_wait().unwrap();
+            let metrics = pipeline.metrics().unwrap();
+            assert!(metrics.stages[2].reorder_high_water <= window, "{:?}", executor);
+            let expected: Vec<u64> = (0..100).filter(|item| item % 5 != 3).collect();
+            assert_eq!(pipeline.collect().unwrap(), expected, "{:?}", executor);
+        }
+    }
+}
+
+#[test]
+fn many_ordered_with_window() {
+    for executor in executors() {
+        let pipeline = pipeline![
+            config = PipelineConfig::new().with_executor(executor);
+            parallel_many_ordered!(|item: u64| {
+                slow_first(item);
+                vec![item; (item % 3) as usize]
+            }, 3, reorder_window(2)),
+            collect_ordered!()
+        ];
+        for item in 0..60 {
+            pipeline.post(item).unwrap();
+        }
+        let expected: Vec<u64> = (0..60).flat_map(|item| vec![item; (item % 3) as usize]).collect();
+        assert_eq!(pipeline.collect().unwrap(), expected, "{:?}", executor);
+    }
+}
+
+#[test]
+fn posting_past_the_window() {
+    let open = Arc::new(AtomicBool::new(false));
+    let open_flag = open.clone();
+    let pipeline = pipeline![
+        sequential_ordered!({
+            let open = open_flag.clone();
+            move |item: u64| {
+                while !open.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                item
+            }
+        }, reorder_window(2))
+    ];
+    //The sink takes the first item and waits in it, two more fit
+    pipeline.post(0).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert!(pipeline.try_post(1).is_ok());
+    assert!(pipeline.try_post(2).is_ok());
+    match pipeline.try_post(3) {
+        Err(TryPostError::Full(3)) => {}
+        _ => panic!("the window should be full"),
+    }
+    open.store(true, Ordering::SeqCst);
+    pipeline.post(3).unwrap();
+    assert_eq!(pipeline.collect().unwrap(), vec![0, 1, 2, 3]);
+}
+
+#[test]
+fn producer_waits_for_the_window_to_move() {
+    let set = BlockingOrderedSet::windowed(2);
+    assert!(set.fits(1));
+    assert!(!set.fits(2));
+    let producer = {
+        let set = set.clone();
+        thread::spawn(move || {
+            for order in 0..10 {
+                set.enqueue(TimestampedWorkItem(WorkItem::Value(order), order));
+            }
+        })
+    };
+    //Nothing was removed yet, so the producer waits with the third item
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(set.len(), 2);
+    let mut removed = vec![];
+    for order in 0..10 {
+        match set.wait_and_remove(order) {
+            TimestampedWorkItem(WorkItem::Value(item), _) => removed.push(item),
+            _ => panic!("only values were enqueued"),
+        }
+    }
+    producer.join().unwrap();
+    assert_eq!(removed, (0..10).collect::<Vec<_>>());
+    assert_eq!(set.high_water(), 2);
+}
+
+//On tokio, a replica waiting for room behind a windowed sink holds the
+//reorder lock of its block. Its siblings waiting for that lock used to keep
+//both workers, so the sink never got to make room
+#[test]
+fn siblings_waiting_for_the_reorder_lock_leave_the_workers_free() {
+    for _ in 0..20 {
+        let (done, finished) = mpsc::channel();
+        thread::spawn(move || {
+            let pipeline = pipeline![
+                config = PipelineConfig::new().with_executor(Executor::Tokio { worker_threads: 2 });
+                parallel_ordered!(|item: u32| if item.is_multiple_of(3) { None } else { Some(item) }, 3, reorder_window(6)),
+                parallel_ordered!(|item: u32| Some(item), 3),
+                collect_ordered!(reorder_window(6))
+            ];
+            for item in 0..40 {
+                pipeline.post(item).unwrap();
+            }
+            done.send(pipeline.collect().unwrap()).unwrap();
+        });
+        let expected: Vec<u32> = (0..40u32).filter(|item| !item.is_multiple_of(3)).collect();
+        assert_eq!(finished.recv_timeout(Duration::from_secs(10)).expect("the pipeline is stuck"), expected);
+    }
+}
+
+//Two unordered stages mix the items up by far more than the window. The
+//replica holding the next item must still get it into the buffer, or the
+//siblings waiting for room would never see it move
+#[test]
+fn items_mixed_up_past_the_window_still_go_through() {
+    for executor in executors() {
+        for window in 1..4 {
+            let (done, finished) = mpsc::channel();
+            thread::spawn(move || {
+                let pipeline = pipeline![
+                    config = PipelineConfig::new().with_executor(executor);
+                    parallel!(|item: u64| {
+                        thread::sleep(Duration::from_micros((20 - item % 20) * 200));
+                        Some(item)
+                    }, 4),
+                    parallel!(|item: u64| if item % 7 == 3 { None } else { Some(item) }, 3),
+                    parallel_ordered!(|item: u64| Some(item), 3, reorder_window(window)),
+                    collect_ordered!()
+                ];
+                for item in 0..100 {
+                    pipeline.post(item).unwrap();
+                }
+                done.send(pipeline.collect().unwrap()).unwrap();
+            });
+            let expected: Vec<u64> = (0..100).filter(|item| item % 7 != 3).collect();
+            let collected = finished.recv_timeout(Duration::from_secs(10)).expect("the pipeline is stuck");
+            assert_eq!(collected, expected, "{:?}", executor);
+        }
+    }
+}
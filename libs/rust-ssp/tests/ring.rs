@@ -0,0 +1,218 @@
+// Ring buffer stage queues: items must go through exactly once with dense
+// orders, whether the ring has one producer and consumer or many, and the
+// end of the stream must still reach every replica.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use rust_spp::*;
+
+mod common;
+use common::executors;
+
+const PIPELINES: usize = 300;
+
+fn replicas(i: usize) -> i32 {
+    (i % 6) as i32 + 1
+}
+
+//Dequeues until the Stop, returning the values with their orders
+fn drain(queue: &RingQueue<u64>) -> Vec<(u64, u64)> {
+    let mut items = vec![];
+    loop {
+        match queue.wait_and_dequeue() {
+            TimestampedWorkItem(WorkItem::Value(item), order) => items.push((item, order)),
+            TimestampedWorkItem(WorkItem::Dropped, _) => panic!("nothing was dropped"),
+            TimestampedWorkItem(WorkItem::Stop, _) => return items,
+        }
+    }
+}
+
+#[test]
+fn mpmc_items_go_through_once_with_dense_orders() {
+    for i in 0..20 {
+        let queue = RingQueue::mpmc(i % 7 + 1);
+        let producers: Vec<_> = (0..3u64)
+            .map(|producer| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    for item in 0..500 {
+                        queue.enqueue(WorkItem::Value(producer * 1000 + item));
+                    }
+                })
+            })
+            .collect();
+        let consumers: Vec<_> = (0..3)
+            .map(|_| {
+                let queue = queue.clone();
+                thread::spawn(move || drain(&queue))
+            })
+            .collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        assert_eq!(queue.enqueue(WorkItem::Stop), 1500);
+        let mut items = vec![];
+        let mut orders = vec![];
+        for consumer in consumers {
+            for (item, order) in consumer.join().unwrap() {
+                items.push(item);
+                orders.push(order);
+            }
+        }
+        items.sort();
+        orders.sort();
+        let expected: Vec<u64> = (0..3).flat_map(|producer| (0..500).map(move |item| producer * 1000 + item)).collect();
+        assert_eq!(items, expected);
+        assert_eq!(orders, (0..1500).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn spsc_keeps_the_order_of_the_producer() {
+    for i in 0..20 {
+        let queue = RingQueue::spsc(i % 5 + 1);
+        assert!(queue.is_single_producer());
+        let producer = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                for item in 0..2000 {
+                    queue.enqueue(WorkItem::Value(item));
+                }
+                queue.enqueue(WorkItem::Stop);
+            })
+        };
+        let items = drain(&queue);
+        producer.join().unwrap();
+        assert_eq!(items, (0..2000).map(|item| (item, item)).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn full_ring_hands_the_item_back() {
+    let queue = RingQueue::mpmc(3);
+    //Rounded up to a power of two
+    assert_eq!(queue.capacity(), 4);
+    for item in 0..4 {
+        assert_eq!(queue.try_enqueue(WorkItem::Value(item)).ok(), Some(item));
+    }
+    match queue.try_enqueue(WorkItem::Value(4)) {
+        Err(WorkItem::Value(4)) => {}
+        _ => panic!("the ring should be full"),
+    }
+    assert!(matches!(queue.wait_and_dequeue(), TimestampedWorkItem(WorkItem::Value(0), 0)));
+    assert_eq!(queue.try_enqueue(WorkItem::Value(4)).ok(), Some(4));
+    assert_eq!(queue.len(), 4);
+}
+
+#[test]
+fn stop_comes_after_the_items_and_stays() {
+    let queue = RingQueue::mpmc(8);
+    queue.enqueue(WorkItem::Value(1));
+    queue.enqueue(WorkItem::Dropped);
+    queue.enqueue(WorkItem::Stop);
+    assert!(matches!(queue.wait_and_dequeue(), TimestampedWorkItem(WorkItem::Value(1), 0)));
+    assert!(matches!(queue.wait_and_dequeue(), TimestampedWorkItem(WorkItem::Dropped, 1)));
+    for _ in 0..3 {
+        assert!(matches!(queue.wait_and_dequeue(), TimestampedWorkItem(WorkItem::Stop, 2)));
+    }
+}
+
+#[test]
+fn parked_consumer_wakes_up() {
+    let queue = RingQueue::<u64>::mpmc(2);
+    let consumer = {
+        let queue = queue.clone();
+        thread::spawn(move || drain(&queue))
+    };
+    //Long enough for the consumer to be done spinning
+    thread::sleep(Duration::from_millis(50));
+    queue.enqueue(WorkItem::Value(7));
+    thread::sleep(Duration::from_millis(50));
+    queue.enqueue(WorkItem::Stop);
+    assert_eq!(consumer.join().unwrap(), vec![(7, 0)]);
+}
+
+#[test]
+fn unordered_collect_sees_every_item_once() {
+    for executor in executors() {
+        for i in 0..PIPELINES {
+            let items = i % 41;
+            let pipeline = pipeline![
+                config = PipelineConfig::new().with_executor(executor);
+                parallel!(|item: usize| Some(item), replicas(i), ring(i % 4 + 1)),
+                parallel!(|item: usize| Some(item), replicas(i + 3), ring(8)),
+                parallel_sink!(|item: usize| item, replicas(i + 5), ring(2))
+            ];
+            for item in 0..items {
+                pipeline.post(item).unwrap();
+            }
+            let mut collected = pipeline.collect().unwrap();
+            collected.sort();
+            assert_eq!(collected, (0..items).collect::<Vec<_>>(), "{:?}", executor);
+        }
+    }
+}
+
+#[test]
+fn ordered_collect_with_drops() {
+    for executor in executors() {
+        for i in 0..PIPELINES {
+            let items = i % 29;
+            let pipeline = pipeline![
+                config = PipelineConfig::new().with_executor(executor);
+                parallel!(|item: usize| if item.is_multiple_of(4) { None } else { Some(item) }, replicas(i), ring(4)),
+                parallel_ordered!(|item: usize| Some(item), replicas(i + 1), ring(i % 3 + 1)),
+                collect_ordered!().ring(4)
+            ];
+            for item in 0..items {
+                pipeline.post(item).unwrap();
+            }
+            let collected = pipeline.collect().unwrap();
+            assert_eq!(collected, (0..items).filter(|item| !item.is_multiple_of(4)).collect::<Vec<_>>(), "{:?}", executor);
+        }
+    }
+}
+
+//Single replicas after single replicas share rings with one producer and one
+//consumer
+#[test]
+fn sequential_chain() {
+    for executor in executors() {
+        for i in 0..PIPELINES {
+            let items = i % 37;
+            let pipeline = Pipeline::builder()
+                .config(PipelineConfig::new().with_executor(executor))
+                .stage(parallel!(|item: usize| Some(item * 2), 1, ring(2)))
+                .stage(parallel_ordered!(|item: usize| if item % 3 == 1 { None } else { Some(item + 1) }, 1, ring(2)))
+                .stage(parallel!(|item: usize| Some(item), 1, ring(1)))
+                .sink_stage(collect_ordered!().ring(2))
+                .build();
+            for item in 0..items {
+                pipeline.post(item).unwrap();
+            }
+            let expected: Vec<usize> = (0..items).map(|item| item * 2).filter(|item| item % 3 != 1).map(|item| item + 1).collect();
+            assert_eq!(pipeline.collect().unwrap(), expected, "{:?}", executor);
+        }
+    }
+}
+
+#[test]
+fn posting_into_a_full_ring() {
+    let gate = Arc::new(parking_lot::Mutex::new(()));
+    let gate_lock = gate.clone();
+    let closed = gate.lock();
+    let pipeline = pipeline![
+        parallel!({ let gate = gate_lock.clone(); move |item: u64| { drop(gate.lock()); Some(item) } }, 1, ring(2)),
+        collect_ordered!()
+    ];
+    //One item is taken by the replica, two more fill the ring
+    pipeline.post(0).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert!(pipeline.try_post(1).is_ok());
+    assert!(pipeline.try_post(2).is_ok());
+    assert!(pipeline.try_post(3).is_err());
+    drop(closed);
+    pipeline.post(3).unwrap();
+    assert_eq!(pipeline.collect().unwrap(), vec![0, 1, 2, 3]);
+}
@@ -0,0 +1,89 @@
+// Adaptive stages: replicas are started and leave while items flow, which
+// must not lose items, break ordering or keep the pipeline from ending. The
+// decisions of the scaler are checked on given loads in scaling.rs.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use rust_spp::*;
+
+fn busy_replicas(metrics: &PipelineMetrics, stage: usize) -> usize {
+    metrics.stages[stage].replicas.iter().filter(|replica| replica.items > 0).count()
+}
+
+#[test]
+fn adaptive_stages_see_every_item_once() {
+    for i in 0..300 {
+        let items = i % 37;
+        let pipeline = pipeline![
+            config = PipelineConfig::new().with_thread_budget(i % 6);
+            parallel!(|item: usize| if item.is_multiple_of(3) { None } else { Some(item) }, (i % 5) as i32 + 1, adaptive),
+            parallel_ordered!(|item: usize| Some(item * 2), (i % 7) as i32 + 1, adaptive),
+            collect_ordered!()
+        ];
+        for item in 0..items {
+            pipeline.post(item).unwrap();
+        }
+        let expected: Vec<usize> = (0..items).filter(|item| !item.is_multiple_of(3)).map(|item| item * 2).collect();
+        assert_eq!(pipeline.collect().unwrap(), expected);
+    }
+}
+
+#[test]
+fn adaptive_stages_on_tasks() {
+    for i in 0..100 {
+        let pipeline = pipeline![
+            config = PipelineConfig::new()
+                .with_executor(Executor::Tokio { worker_threads: (i % 3) + 1 })
+                .with_thread_budget(i % 5);
+            parallel!(|item: usize| Some(item + 1), (i % 4) as i32 + 1, adaptive),
+            async_parallel_ordered!(|item: usize| async move { Some(item * 3) }, (i % 6) as i32 + 1, adaptive),
+            collect_ordered!()
+        ];
+        for item in 0..(i % 17) {
+            pipeline.post(item).unwrap();
+        }
+        assert_eq!(pipeline.collect().unwrap(), (0..(i % 17)).map(|item| (item + 1) * 3).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn budget_caps_active_replicas() {
+    // The sink alone uses up the budget: adaptive stages keep one replica
+    let mut pipeline = Pipeline::builder()
+        .config(PipelineConfig::new().metrics(true).with_thread_budget(1))
+        .stage(parallel!(|item: u64| Some(item), 4, adaptive))
+        .stage(parallel!(|item: u64| {
+            thread::sleep(Duration::from_micros(500));
+            Some(item)
+        }, 4, adaptive))
+        .sink(|_item: u64| {})
+        .build();
+    for item in 0..100 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let metrics = pipeline.metrics().unwrap();
+    assert_eq!(busy_replicas(&metrics, 0), 1);
+    assert_eq!(busy_replicas(&metrics, 1), 1);
+}
+
+#[test]
+fn replicas_are_only_created_when_needed() {
+    // With the budget used up, the adaptive stage never gets a second replica
+    for executor in [Executor::Threads, Executor::Tokio { worker_threads: 2 }] {
+        let nodes = Arc::new(AtomicUsize::new(0));
+        let created = nodes.clone();
+        let pipeline = pipeline![
+            config = PipelineConfig::new().with_executor(executor).with_thread_budget(1);
+            parallel!({ created.fetch_add(1, Ordering::SeqCst); |item: u64| Some(item) }, 8, adaptive),
+            collect!()
+        ];
+        for item in 0..100 {
+            pipeline.post(item).unwrap();
+        }
+        assert_eq!(pipeline.collect().unwrap().len(), 100);
+        assert_eq!(nodes.load(Ordering::SeqCst), 1, "{:?}", executor);
+    }
+}
@@ -0,0 +1,137 @@
+// Pipelines fed from an iterator on a source thread of their own.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use rust_spp::*;
+
+#[test]
+fn run_source_collects_every_item() {
+    for executor in [Executor::Threads, Executor::Tokio { worker_threads: 2 }] {
+        for i in 0..50 {
+            let collected = pipeline![
+                config = PipelineConfig::new().with_executor(executor);
+                parallel_ordered!(|item: usize| Some(item * 2), (i % 4) as i32 + 1),
+                collect_ordered!()
+            ].run_source(0..i).unwrap();
+            assert_eq!(collected, (0..i).map(|item| item * 2).collect::<Vec<_>>(), "{:?}", executor);
+        }
+    }
+}
+
+#[test]
+fn bounded_first_stage_throttles_the_source() {
+    let produced = Arc::new(AtomicUsize::new(0));
+    let consumed = Arc::new(AtomicUsize::new(0));
+    let most_in_flight = Arc::new(AtomicUsize::new(0));
+
+    let source = {
+        let (produced, consumed, most_in_flight) = (produced.clone(), consumed.clone(), most_in_flight.clone());
+        (0..100u64).inspect(move |_| {
+            let in_flight = produced.fetch_add(1, Ordering::SeqCst) - consumed.load(Ordering::SeqCst);
+            most_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+        })
+    };
+    let collected = Pipeline::builder()
+        .stage(parallel!({
+            let consumed = consumed.clone();
+            move |item: u64| {
+                thread::sleep(Duration::from_micros(200));
+                consumed.fetch_add(1, Ordering::SeqCst);
+                Some(item)
+            }
+        }, 1, bounded(2)))
+        .collect()
+        .run_source(source)
+        .unwrap();
+
+    assert_eq!(collected.len(), 100);
+    // Two queued, one being processed and one waiting to be posted
+    assert!(most_in_flight.load(Ordering::SeqCst) <= 4);
+}
+
+#[test]
+fn source_stops_once_a_stage_failed() {
+    let error = Pipeline::builder()
+        .stage(try_parallel!(|item: u64| if item == 10 { Err("bad item") } else { Ok(Some(item)) }, 2, bounded(4)))
+        .collect()
+        .run_source(0..)
+        .unwrap_err();
+    assert_eq!(error.order, 10);
+}
+
+#[test]
+fn post_source_returns_while_the_source_runs() {
+    // The source only ends once the caller closes the channel
+    let (items, source) = mpsc::channel();
+    let mut pipeline = pipeline![
+        parallel_ordered!(|item: u64| Some(item), 2),
+        collect_ordered!()
+    ];
+    pipeline.post_source(source).unwrap();
+    for item in 0..100 {
+        items.send(item).unwrap();
+    }
+    drop(items);
+    pipeline.wait_source().unwrap();
+    assert_eq!(pipeline.collect().unwrap(), (0..100).collect::<Vec<_>>());
+}
+
+#[test]
+fn sources_post_one_after_the_other() {
+    let mut pipeline = pipeline![
+        parallel_ordered!(|item: u64| Some(item), 3),
+        collect_ordered!()
+    ];
+    pipeline.post_source((0..50).inspect(|_| thread::sleep(Duration::from_micros(100)))).unwrap();
+    pipeline.post_source(50..100).unwrap();
+    assert_eq!(pipeline.collect().unwrap(), (0..100).collect::<Vec<_>>());
+}
+
+#[test]
+fn source_reports_an_early_stop() {
+    let mut pipeline = Pipeline::builder()
+        .stage(try_parallel!(|item: u64| if item == 10 { Err("bad item") } else { Ok(Some(item)) }, 2, bounded(4)))
+        .collect()
+        .build();
+    pipeline.post_source(0..).unwrap();
+    assert!(matches!(pipeline.wait_source(), Err(ItemPostError::Failed)));
+    assert_eq!(pipeline.collect().unwrap_err().order, 10);
+
+    let mut pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 2, bounded(4)),
+        collect!()
+    ];
+    pipeline.post_source(0..).unwrap();
+    pipeline.cancel();
+    assert!(matches!(pipeline.wait_source(), Err(ItemPostError::Cancelled)));
+}
+
+#[test]
+fn source_shows_up_in_metrics() {
+    let mut pipeline = pipeline![
+        config = PipelineConfig::new().metrics(true);
+        parallel!(|item: u64| Some(item), 2),
+        collect!()
+    ];
+    pipeline.post_source(0..100).unwrap();
+    pipeline.post(100).unwrap();
+    pipeline.end_and_wait().unwrap();
+    let metrics = pipeline.metrics().unwrap();
+    assert_eq!(metrics.source.unwrap().items, 100);
+    assert_eq!(metrics.latency.unwrap().items, 101);
+    assert_eq!(pipeline.collect().unwrap().len(), 101);
+}
+
+#[test]
+fn no_source_after_the_end() {
+    let mut pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 2),
+        collect!()
+    ];
+    pipeline.end_and_wait().unwrap();
+    assert!(matches!(pipeline.post_source(0..10), Err(ItemPostError::StreamEnded)));
+    assert!(pipeline.metrics().is_none());
+}
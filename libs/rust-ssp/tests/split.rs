@@ -0,0 +1,205 @@
+// Split and merge: items go through several branches and come back
+// together, in the order they reached the split.
+
+use std::thread;
+use std::time::Duration;
+use rust_spp::*;
+
+mod common;
+use common::executors;
+
+#[derive(Debug, PartialEq)]
+enum Output {
+    Doubled(u64),
+    Text(String),
+}
+
+#[test]
+fn broadcast_rejoins_by_order() {
+    for executor in executors() {
+        for i in 0..50 {
+            let pipeline = pipeline![
+                config = PipelineConfig::new().with_executor(executor);
+                parallel!(|item: u64| Some(item), (i % 3) + 1),
+                split!(broadcast;
+                    [parallel!(|item: u64| {
+                        thread::sleep(Duration::from_micros(item % 5 * 50));
+                        Some(Output::Doubled(item * 2))
+                    }, (i % 4) + 1)],
+                    [parallel_ordered!(|item: u64| Some(item + 1), 2),
+                     parallel!(|item: u64| Some(Output::Text(item.to_string())), 1)]),
+                merge!(),
+                collect_ordered!()
+            ];
+            for item in 0..(i as u64 % 17) {
+                pipeline.post(item).unwrap();
+            }
+            let mut collected = pipeline.collect().unwrap();
+            collected.sort_by_key(|outputs| match &outputs[0] {
+                Some(Output::Doubled(item)) => *item,
+                _ => panic!("every branch gave an output")
+            });
+            let expected: Vec<Vec<Option<Output>>> = (0..(i as u64 % 17))
+                .map(|item| vec![Some(Output::Doubled(item * 2)), Some(Output::Text((item + 1).to_string()))])
+                .collect();
+            assert_eq!(collected, expected, "{:?}", executor);
+        }
+    }
+}
+
+#[test]
+fn merge_keeps_post_order_behind_ordered_stages() {
+    for i in 0..50 {
+        let pipeline = pipeline![
+            parallel_ordered!(|item: u64| Some(item), (i % 3) + 1),
+            split!(broadcast;
+                [parallel!(|item: u64| {
+                    thread::sleep(Duration::from_micros((10 - item % 10) * 30));
+                    Some(item)
+                }, 4)],
+                [parallel!(|item: u64| Some(item * 100), 3)]),
+            merge!(),
+            parallel_ordered!(|outputs: Vec<Option<u64>>| Some(outputs.into_iter().flatten().sum::<u64>()), 2),
+            collect_ordered!()
+        ];
+        for item in 0..(i as u64) {
+            pipeline.post(item).unwrap();
+        }
+        assert_eq!(pipeline.collect().unwrap(), (0..(i as u64)).map(|item| item * 101).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn by_key_sends_each_item_to_one_branch() {
+    for i in 0..50 {
+        let pipeline = pipeline![
+            split!(by_key(|item: &u64| item % 4);
+                [parallel!(|item: u64| Some(item), 2)],
+                [parallel!(|item: u64| Some(item), (i % 3) + 1)]),
+            merge!(),
+            collect_ordered!()
+        ];
+        for item in 0..(i as u64) {
+            pipeline.post(item).unwrap();
+        }
+        let collected = pipeline.collect().unwrap();
+        let items: Vec<u64> = collected.iter().map(|outputs| {
+            assert_eq!(outputs.iter().flatten().count(), 1);
+            outputs.iter().flatten().copied().next().unwrap()
+        }).collect();
+        assert_eq!(items, (0..(i as u64)).collect::<Vec<_>>());
+        for (item, outputs) in collected.iter().enumerate() {
+            let branch = outputs.iter().position(Option::is_some);
+            assert_eq!(branch, collected[item % 4].iter().position(Option::is_some), "same key, same branch");
+        }
+    }
+}
+
+#[test]
+fn stages_after_the_branches_may_drop_outputs() {
+    for executor in executors() {
+        for i in 0..20 {
+            let pipeline = pipeline![
+                config = PipelineConfig::new().with_executor(executor);
+                split!(broadcast;
+                    [parallel!(|item: u64| Some(item), 2)],
+                    [parallel!(|item: u64| Some(item * 10), (i % 3) + 1)]),
+                parallel!(|branched: Branched<u64>| {
+                    if branched.branch == 1 && branched.order.is_multiple_of(3) { None } else { Some(branched) }
+                }, (i % 4) + 1),
+                merge!(),
+                collect_ordered!()
+            ];
+            for item in 0..(i as u64 * 3) {
+                pipeline.post(item).unwrap();
+            }
+            let expected: Vec<Vec<Option<u64>>> = (0..(i as u64 * 3))
+                .map(|item| vec![Some(item), Some(item * 10).filter(|_| item % 3 != 0)])
+                .collect();
+            assert_eq!(pipeline.collect().unwrap(), expected, "{:?}", executor);
+        }
+    }
+}
+
+#[test]
+fn merge_drops_items_filtered_on_every_branch() {
+    let pipeline = pipeline![
+        split!(by_key(|item: &u64| *item);
+            [parallel!(|item: u64| Some(item), 2)],
+            [parallel!(|item: u64| Some(item), 2)]),
+        parallel!(|branched: Branched<u64>| branched.item.filter(|item| item % 2 == 0).map(|_| branched), 3),
+        merge!(),
+        parallel_ordered!(|outputs: Vec<Option<u64>>| outputs.into_iter().flatten().next(), 2),
+        collect_ordered!()
+    ];
+    for item in 0..40 {
+        pipeline.post(item).unwrap();
+    }
+    assert_eq!(pipeline.collect().unwrap(), (0..40).filter(|item| item % 2 == 0).collect::<Vec<u64>>());
+}
+
+#[test]
+fn dropped_items_leave_empty_slots() {
+    let pipeline = pipeline![
+        parallel!(|item: u64| if item.is_multiple_of(5) { None } else { Some(item) }, 2),
+        split!(broadcast;
+            [parallel!(|item: u64| if item.is_multiple_of(2) { Some(item) } else { None }, 2)],
+            [parallel!(|item: u64| if item.is_multiple_of(3) { Some(item) } else { None }, 2)]),
+        merge!(),
+        collect_ordered!()
+    ];
+    for item in 0..30 {
+        pipeline.post(item).unwrap();
+    }
+    let collected = pipeline.collect().unwrap();
+    let expected: Vec<Vec<Option<u64>>> = (0..30)
+        .filter(|item| item % 5 != 0 && (item % 2 == 0 || item % 3 == 0))
+        .map(|item| vec![Some(item).filter(|item| item % 2 == 0), Some(item).filter(|item| item % 3 == 0)])
+        .collect();
+    assert_eq!(collected, expected);
+}
+
+#[test]
+fn branch_stages_are_numbered_after_the_split() {
+    let pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 1),
+        split!(broadcast;
+            [parallel!(|item: u64| Some(item), 1), parallel!(|item: u64| Some(item), 1)],
+            [try_parallel!(|item: u64| if item == 7 { Err("seven") } else { Ok(Some(item)) }, 2).label("check")]),
+        merge!(),
+        collect!()
+    ];
+    for item in 0..20 {
+        pipeline.post(item).unwrap();
+    }
+    let error = pipeline.collect().unwrap_err();
+    assert_eq!(error.stage, 3);
+    assert_eq!(error.label.as_deref(), Some("check"));
+}
+
+#[test]
+fn builder_split_with_metrics() {
+    let mut pipeline = Pipeline::builder()
+        .config(PipelineConfig::new().metrics(true))
+        .stage(split!(broadcast;
+            [parallel!(label = "left"; |item: u64| Some(item), 2)],
+            [parallel!(|item: u64| Some(item + 1), 1)]))
+        .stage(merge!(label = "merge"))
+        .sink(|outputs: Vec<Option<u64>>| outputs.len())
+        .build();
+    for item in 0..25 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let metrics = pipeline.metrics().unwrap();
+    let labels: Vec<(usize, Option<String>, u64)> = metrics.stages.iter()
+        .map(|stage| (stage.stage, stage.label.clone(), stage.items))
+        .collect();
+    assert_eq!(labels, vec![
+        (0, Some("left".to_string()), 25),
+        (1, None, 25),
+        (2, Some("merge".to_string()), 50),
+        (3, None, 25),
+    ]);
+    assert_eq!(metrics.latency.unwrap().items, 25);
+}
@@ -0,0 +1,129 @@
+// Outputs of the last stage streamed as they are produced, instead of
+// collected at the end.
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use rust_spp::*;
+
+mod common;
+use common::executors;
+
+#[test]
+fn ordered_stream_keeps_post_order() {
+    for executor in executors() {
+        for i in 0..50 {
+            let pipeline = pipeline![
+                config = PipelineConfig::new().with_executor(executor);
+                parallel_ordered!(|item: usize| Some(item + 1), (i % 4) as i32 + 1),
+                collect_ordered!()
+            ];
+            for item in 0..i {
+                pipeline.post(item).unwrap();
+            }
+            let streamed: Vec<usize> = pipeline.into_stream().map(Result::unwrap).collect();
+            assert_eq!(streamed, (1..=i).collect::<Vec<_>>(), "{:?}", executor);
+        }
+    }
+}
+
+#[test]
+fn unordered_stream_yields_every_output() {
+    for executor in executors() {
+        for i in 0..50 {
+            let pipeline = pipeline![
+                config = PipelineConfig::new().with_executor(executor);
+                parallel!(|item: usize| Some(item), 3),
+                parallel_sink!(|item: usize| item * 2, (i % 3) as i32 + 1)
+            ];
+            for item in 0..i {
+                pipeline.post(item).unwrap();
+            }
+            let mut streamed: Vec<usize> = pipeline.into_stream().map(Result::unwrap).collect();
+            streamed.sort();
+            assert_eq!(streamed, (0..i).map(|item| item * 2).collect::<Vec<_>>(), "{:?}", executor);
+        }
+    }
+}
+
+#[test]
+fn outputs_arrive_before_the_end() {
+    for executor in executors() {
+        let mut pipeline = pipeline![
+            config = PipelineConfig::new().with_executor(executor);
+            parallel_ordered!(|item: u64| Some(item), 2),
+            collect_ordered!()
+        ];
+        let (done, received) = mpsc::channel();
+        let outputs = pipeline.stream();
+        let consumer = thread::spawn(move || {
+            for output in outputs {
+                done.send(output).unwrap();
+            }
+        });
+        for item in 0..10 {
+            pipeline.post(item).unwrap();
+            // Each output is seen while the pipeline is still open
+            assert_eq!(received.recv_timeout(Duration::from_secs(10)).unwrap(), item, "{:?}", executor);
+        }
+        pipeline.end_and_wait().unwrap();
+        consumer.join().unwrap();
+        assert!(received.try_recv().is_err());
+        assert!(pipeline.collect().unwrap().is_empty());
+    }
+}
+
+#[test]
+fn outputs_collected_before_streaming_come_first() {
+    let mut pipeline = pipeline![
+        parallel_ordered!(|item: u64| Some(item), 1),
+        collect_ordered!()
+    ];
+    for item in 0..20 {
+        pipeline.post(item).unwrap();
+    }
+    thread::sleep(Duration::from_millis(50));
+    let outputs = pipeline.stream();
+    for item in 20..40 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    assert_eq!(outputs.collect::<Vec<_>>(), (0..40).collect::<Vec<_>>());
+}
+
+#[test]
+#[should_panic(expected = "can only be streamed once")]
+fn outputs_are_streamed_once() {
+    let pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 2),
+        collect!()
+    ];
+    let _outputs = pipeline.stream();
+    let _again = pipeline.stream();
+}
+
+#[test]
+fn stream_ends_with_the_stage_error() {
+    for executor in executors() {
+        let pipeline = pipeline![
+            config = PipelineConfig::new().with_executor(executor);
+            try_parallel_ordered!(|item: u64| if item == 5 { Err("bad item") } else { Ok(Some(item)) }, 2),
+            collect_ordered!()
+        ];
+        for item in 0..10 {
+            pipeline.post(item).unwrap();
+        }
+        let mut stream = pipeline.into_stream();
+        let mut outputs = vec![];
+        let error = loop {
+            match stream.next() {
+                Some(Ok(output)) => outputs.push(output),
+                Some(Err(error)) => break error,
+                None => panic!("the stream ended without the error")
+            }
+        };
+        assert_eq!(error.order, 5, "{:?}", executor);
+        assert!(outputs.iter().all(|output| *output != 5));
+        assert!(stream.next().is_none());
+    }
+}
@@ -0,0 +1,93 @@
+// Stress tests for end-of-stream propagation: many tiny pipelines with
+// varying replica counts, started and ended as fast as possible.
+
+use rust_spp::*;
+
+const PIPELINES: usize = 1000;
+
+fn replicas(i: usize) -> i32 {
+    (i % 8) as i32 + 1
+}
+
+#[test]
+fn end_and_wait_terminates_tiny_pipelines() {
+    for i in 0..PIPELINES {
+        let mut pipeline = pipeline![
+            parallel!(|item: usize| Some(item + 1), replicas(i)),
+            parallel!(|item: usize| Some(item * 2), replicas(i + 3)),
+            sequential!(|_item: usize| {})
+        ];
+        for item in 0..(i % 5) {
+            pipeline.post(item).unwrap();
+        }
+        pipeline.end_and_wait().unwrap();
+    }
+}
+
+#[test]
+fn empty_streams_terminate() {
+    for i in 0..PIPELINES {
+        let mut pipeline = pipeline![
+            parallel!(|item: usize| Some(item), replicas(i)),
+            parallel_sink!(|item: usize| item, replicas(i + 1))
+        ];
+        pipeline.end_and_wait().unwrap();
+    }
+}
+
+#[test]
+fn dropping_without_end_terminates() {
+    for i in 0..PIPELINES {
+        let pipeline = pipeline![
+            parallel!(|item: usize| Some(item), replicas(i)),
+            parallel!(|item: usize| Some(item), replicas(i + 5)),
+            collect!()
+        ];
+        pipeline.post(i).unwrap();
+    }
+}
+
+#[test]
+fn unordered_collect_sees_every_item_once() {
+    for i in 0..PIPELINES {
+        let items = i % 17;
+        let pipeline = pipeline![
+            parallel!(|item: usize| Some(item), replicas(i)),
+            parallel!(|item: usize| Some(item), replicas(i + 1)),
+            parallel_sink!(|item: usize| item, replicas(i + 2))
+        ];
+        for item in 0..items {
+            pipeline.post(item).unwrap();
+        }
+        let mut collected = pipeline.collect().unwrap();
+        collected.sort();
+        assert_eq!(collected, (0..items).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn ordered_collect_with_drops_and_bounded_queues() {
+    for i in 0..PIPELINES {
+        let items = i % 23;
+        let pipeline = pipeline![
+            parallel!(|item: usize| if item.is_multiple_of(3) { None } else { Some(item) }, replicas(i), bounded(2)),
+            parallel_ordered!(|item: usize| Some(item), replicas(i + 4), bounded(1)),
+            collect_ordered!()
+        ];
+        for item in 0..items {
+            pipeline.post(item).unwrap();
+        }
+        let collected = pipeline.collect().unwrap();
+        assert_eq!(collected, (0..items).filter(|item| !item.is_multiple_of(3)).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn post_after_end_is_rejected() {
+    let mut pipeline = pipeline![
+        parallel!(|item: usize| Some(item), 4),
+        collect!()
+    ];
+    pipeline.end_and_wait().unwrap();
+    assert!(matches!(pipeline.post(1), Err(ItemPostError::StreamEnded)));
+}
@@ -0,0 +1,144 @@
+// Opt-in tracing: every item leaves an enqueue, dequeue, start and finish
+// event at each stage, exported as Chrome Trace Event JSON.
+
+use std::collections::HashMap;
+use rust_spp::*;
+
+mod common;
+use common::executors;
+
+//Events of one stage, by order, in the order they were recorded
+fn by_order(trace: &PipelineTrace, stage: usize) -> HashMap<u64, Vec<&TraceEvent>> {
+    let mut events: HashMap<u64, Vec<&TraceEvent>> = HashMap::new();
+    for event in trace.events.iter().filter(|event| event.stage == stage) {
+        events.entry(event.order).or_default().push(event);
+    }
+    events
+}
+
+#[test]
+fn tracing_is_off_by_default() {
+    let mut pipeline = pipeline![
+        parallel!(|item: u64| Some(item), 2),
+        sequential!(|_item: u64| {})
+    ];
+    pipeline.post(1).unwrap();
+    pipeline.end_and_wait().unwrap();
+    assert!(pipeline.trace().is_none());
+}
+
+#[test]
+fn every_item_is_traced_at_every_stage() {
+    for executor in executors() {
+        let mut pipeline = pipeline![
+            config = PipelineConfig::new().tracing(true).with_executor(executor);
+            parallel!(|item: u64| if item.is_multiple_of(3) { None } else { Some(item) }, 4),
+            parallel_ordered!(|item: u64| Some(item), 2),
+            sequential_ordered!(|_item: u64| {})
+        ];
+        for item in 0..60 {
+            pipeline.post(item).unwrap();
+        }
+        pipeline.end_and_wait().unwrap();
+        let trace = pipeline.trace().unwrap();
+
+        //The first stage runs every item
+        let first = by_order(&trace, 0);
+        assert_eq!(first.len(), 60, "{:?}", executor);
+        for events in first.values() {
+            let kinds: Vec<_> = events.iter().map(|event| event.kind).collect();
+            assert_eq!(kinds, vec![TraceEventKind::Enqueued, TraceEventKind::Dequeued,
+                TraceEventKind::Started, TraceEventKind::Finished], "{:?}", executor);
+            assert!(events[0].replica.is_none());
+            assert!(events[1..].iter().all(|event| event.replica == events[1].replica && event.replica.unwrap() < 4));
+            assert!(events.windows(2).all(|pair| pair[0].at <= pair[1].at));
+        }
+
+        //Later stages still see the dropped items go through their queue,
+        //but only run the others
+        for stage in 1..3 {
+            let events = by_order(&trace, stage);
+            assert_eq!(events.len(), 60, "{:?}", executor);
+            let started = trace.events.iter()
+                .filter(|event| event.stage == stage && event.kind == TraceEventKind::Started)
+                .count();
+            assert_eq!(started, 40, "{:?}", executor);
+        }
+    }
+}
+
+#[test]
+fn tracks_are_named_after_the_threads() {
+    let mut pipeline = pipeline![
+        config = PipelineConfig::new().tracing(true);
+        parallel!(label = "decode"; |item: u64| Some(item), 2),
+        sequential!(|_item: u64| {})
+    ];
+    for item in 0..10 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let trace = pipeline.trace().unwrap();
+    assert_eq!(trace.stages.get(&0).map(String::as_str), Some("decode"));
+    assert_eq!(trace.stages.get(&1).map(String::as_str), Some("stage-1"));
+    assert_eq!(trace.replicas.len(), 3);
+    assert!(trace.replicas.get(&(0, 1)).unwrap().starts_with("decode"));
+}
+
+#[test]
+fn chrome_json_has_a_slice_per_item() {
+    let mut pipeline = pipeline![
+        config = PipelineConfig::new().tracing(true);
+        parallel!(|item: u64| Some(item), 3),
+        collect!()
+    ];
+    for item in 0..20 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let json: serde_json::Value = serde_json::from_str(&pipeline.trace().unwrap().to_chrome_json()).unwrap();
+    let events = json["traceEvents"].as_array().unwrap();
+    let phases = |phase: &str| events.iter().filter(|event| event["ph"] == phase).count();
+    assert_eq!(phases("B"), 40);
+    assert_eq!(phases("E"), 40);
+    assert_eq!(phases("i"), 80);
+    assert!(phases("M") > 0);
+    assert!(events.iter().all(|event| event["pid"] == 1));
+}
+
+//Items reach an unordered sink in the order the replicas finish them, but
+//keep the orders they had at the stage before, so the events of the sink
+//are about the same items
+#[test]
+fn unordered_sink_traces_the_orders_of_the_stage_before() {
+    for executor in executors() {
+        let mut pipeline = pipeline![
+            config = PipelineConfig::new().tracing(true).with_executor(executor);
+            parallel!(|item: u64| {
+                std::thread::sleep(std::time::Duration::from_micros((30 - item) * 200));
+                if item.is_multiple_of(4) { None } else { Some(item) }
+            }, 4),
+            collect!()
+        ];
+        for item in 0..30 {
+            pipeline.post(item).unwrap();
+        }
+        pipeline.end_and_wait().unwrap();
+        let trace = pipeline.trace().unwrap();
+        let parallel = by_order(&trace, 0);
+        let sink = by_order(&trace, 1);
+        assert_eq!(sink.len(), 30, "{:?}", executor);
+        for (order, events) in &sink {
+            let kinds: Vec<_> = events.iter().map(|event| event.kind).collect();
+            let expected = if order.is_multiple_of(4) {
+                vec![TraceEventKind::Enqueued, TraceEventKind::Dequeued]
+            } else {
+                vec![TraceEventKind::Enqueued, TraceEventKind::Dequeued,
+                    TraceEventKind::Started, TraceEventKind::Finished]
+            };
+            assert_eq!(kinds, expected, "order {} {:?}", order, executor);
+            let finished = parallel[order].iter().find(|event| event.kind == TraceEventKind::Finished).unwrap();
+            assert!(finished.at <= events[0].at, "order {} {:?}", order, executor);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+// Work-stealing stage queues: every item must be processed exactly once and
+// the end of the stream must still reach every replica.
+
+use rust_spp::*;
+
+const PIPELINES: usize = 500;
+
+fn replicas(i: usize) -> i32 {
+    (i % 8) as i32 + 1
+}
+
+#[test]
+fn unordered_collect_sees_every_item_once() {
+    for i in 0..PIPELINES {
+        let items = i % 41;
+        let pipeline = pipeline![
+            parallel!(|item: usize| Some(item), replicas(i), work_stealing),
+            parallel!(|item: usize| Some(item), replicas(i + 3), work_stealing),
+            parallel_sink!(|item: usize| item, replicas(i + 5), work_stealing)
+        ];
+        for item in 0..items {
+            pipeline.post(item).unwrap();
+        }
+        let mut collected = pipeline.collect().unwrap();
+        collected.sort();
+        assert_eq!(collected, (0..items).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn ordered_collect_with_drops() {
+    for i in 0..PIPELINES {
+        let items = i % 29;
+        let pipeline = pipeline![
+            parallel!(|item: usize| if item.is_multiple_of(4) { None } else { Some(item) }, replicas(i), work_stealing),
+            parallel_ordered!(|item: usize| Some(item), replicas(i + 1), work_stealing),
+            collect_ordered!()
+        ];
+        for item in 0..items {
+            pipeline.post(item).unwrap();
+        }
+        let collected = pipeline.collect().unwrap();
+        assert_eq!(collected, (0..items).filter(|item| !item.is_multiple_of(4)).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn uneven_work_is_shared_between_replicas() {
+    let mut pipeline = Pipeline::builder()
+        .config(PipelineConfig::new().metrics(true))
+        .stage(parallel!(|item: u64| {
+            std::thread::sleep(std::time::Duration::from_micros(200 * (item % 3)));
+            Some(item)
+        }, 4, work_stealing))
+        .sink(|_item: u64| {})
+        .build();
+    for item in 0..400 {
+        pipeline.post(item).unwrap();
+    }
+    pipeline.end_and_wait().unwrap();
+    let metrics = pipeline.metrics().unwrap();
+    assert_eq!(metrics.stages[0].items, 400);
+    assert!(metrics.stages[0].replicas.iter().all(|replica| replica.items > 0));
+}
+
+#[test]
+fn work_stealing_on_tasks() {
+    for i in 0..100 {
+        let pipeline = pipeline![
+            config = PipelineConfig::new().with_executor(Executor::Tokio { worker_threads: (i % 3) + 1 });
+            parallel!(|item: usize| Some(item * 2), replicas(i), work_stealing),
+            parallel_ordered!(|item: usize| Some(item + 1), replicas(i + 2), work_stealing),
+            collect_ordered!()
+        ];
+        for item in 0..(i % 13) {
+            pipeline.post(item).unwrap();
+        }
+        assert_eq!(pipeline.collect().unwrap(), (0..(i % 13)).map(|item| item * 2 + 1).collect::<Vec<_>>());
+    }
+}
@@ -20,15 +20,26 @@ fn main() {
     let threads = args[3].parse::<usize>().unwrap();
     let iter_size1 = args[4].parse::<i32>().unwrap();
     let iter_size2 = args[5].parse::<i32>().unwrap();
+    // Only the rust-ssp* runtimes take options, see rust_ssp::Options
+    let options = match rust_ssp::Options::parse(&args[6..]) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    };
 
     match runtime.as_str() {
         "sequential" => sequential::sequential(size, iter_size1, iter_size2),
-        "rust-ssp" => rust_ssp::rust_ssp_pipeline(size, threads, iter_size1, iter_size2),
+        "rust-ssp" => rust_ssp::rust_ssp_pipeline(size, threads, iter_size1, iter_size2, options),
+        "rust-ssp-ws" => rust_ssp::rust_ssp_ws_pipeline(size, threads, iter_size1, iter_size2, options),
+        "rust-ssp-adaptive" => rust_ssp::rust_ssp_adaptive_pipeline(size, threads, iter_size1, iter_size2, options),
+        "rust-ssp-ring" => rust_ssp::rust_ssp_ring_pipeline(size, threads, iter_size1, iter_size2, options),
         "std-threads" => std_threads::std_threads_pipeline(size, threads, iter_size1, iter_size2),
         "tokio" => tokio::tokio_pipeline(size, threads, iter_size1, iter_size2),
         "rayon" => rayon::rayon_pipeline(size, threads, iter_size1, iter_size2),
         "pipeliner" => pipeliner::pipeliner_pipeline(size, threads, iter_size1, iter_size2),
         "dagrs" => dagrs::dagrs_pipeline(size, threads, iter_size1, iter_size2),
-        _ => println!("Invalid run_mode, use: sequential | rust-ssp | std-threads | tokio | rayon | pipeliner"),
+        _ => println!("Invalid run_mode, use: sequential | rust-ssp | rust-ssp-ws | rust-ssp-adaptive | rust-ssp-ring | std-threads | tokio | rayon | pipeliner"),
     }
 }
@@ -1,6 +1,6 @@
 use std::fs::File;
 use std::io::Write;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use rust_spp::*;
 
@@ -13,11 +13,83 @@ struct Tcontent {
     k_buffer: Vec<i32>,
 }
 
-pub fn rust_ssp_pipeline(size: usize, threads: usize, iter_size1: i32, iter_size2: i32) {
+type Tune = fn(InOutStage<Tcontent, Tcontent>) -> InOutStage<Tcontent, Tcontent>;
+
+// Options of the rust-ssp* runtimes, after the other arguments. Without
+// them, the runtimes print nothing but the execution time
+#[derive(Default)]
+pub struct Options {
+    // --affinity compact|scatter|<core list>: pins the replicas and prints
+    // where they run
+    affinity: Option<Affinity>,
+    // --time-limit <seconds>: cancels longer runs, keeping the lines computed
+    time_limit: Option<Duration>,
+}
+
+impl Options {
+    // Fails on an affinity policy with no cores this process may run on,
+    // before any pipeline is built
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args.next().unwrap_or_else(|| panic!("{} needs a value", flag));
+            match flag.as_str() {
+                "--affinity" => {
+                    let affinity: Affinity = value.parse()?;
+                    affinity.check(&Topology::current())?;
+                    options.affinity = Some(affinity);
+                }
+                "--time-limit" => options.time_limit = Some(Duration::from_secs_f64(value.parse().unwrap())),
+                _ => panic!("Unknown option: {}", flag),
+            }
+        }
+        Ok(options)
+    }
+}
+
+pub fn rust_ssp_pipeline(size: usize, threads: usize, iter_size1: i32, iter_size2: i32, options: Options) {
+    run_pipeline(size, threads, iter_size1, iter_size2, options, PipelineConfig::new(), |stage| stage)
+}
+
+// Same pipeline, but replicas take lines from their own work-stealing deques
+pub fn rust_ssp_ws_pipeline(size: usize, threads: usize, iter_size1: i32, iter_size2: i32, options: Options) {
+    run_pipeline(size, threads, iter_size1, iter_size2, options, PipelineConfig::new(), InOutStage::work_stealing)
+}
+
+// Same pipeline and thread count, but the replicas are moved to whichever
+// stage the lines pile up in
+pub fn rust_ssp_adaptive_pipeline(size: usize, threads: usize, iter_size1: i32, iter_size2: i32, options: Options) {
+    let config = PipelineConfig::new().with_thread_budget(2 * threads + 1);
+    run_pipeline(size, threads, iter_size1, iter_size2, options, config, InOutStage::adaptive)
+}
+
+// Same pipeline, but the lines wait in lock-free ring buffers, so the cost of
+// the queues themselves can be told apart from the work
+const RING_CAPACITY: usize = 1024;
+
+pub fn rust_ssp_ring_pipeline(size: usize, threads: usize, iter_size1: i32, iter_size2: i32, options: Options) {
+    run_pipeline(size, threads, iter_size1, iter_size2, options, PipelineConfig::new(), |stage| stage.ring(RING_CAPACITY))
+}
+
+fn run_pipeline(
+    size: usize,
+    threads: usize,
+    iter_size1: i32,
+    iter_size2: i32,
+    options: Options,
+    config: PipelineConfig,
+    tune: Tune,
+) {
+    let config = match &options.affinity {
+        Some(affinity) => config.with_affinity(affinity.clone()),
+        None => config,
+    };
     let start = SystemTime::now();
 
     let pipeline = pipeline![
-        parallel!(
+        config = config;
+        tune(parallel!(
             move |mut content: Tcontent| {
                 let init_a = -2.125 as f64;
                 let init_b = -1.5 as f64;
@@ -50,8 +122,8 @@ pub fn rust_ssp_pipeline(size: usize, threads: usize, iter_size1: i32, iter_size
                 Some(content)
             },
             threads as i32
-        ),
-        parallel!(
+        )),
+        tune(parallel!(
             move |mut content: Tcontent| {
                 let init_a = -2.125 as f64;
                 let init_b = -1.5 as f64;
@@ -83,23 +155,33 @@ pub fn rust_ssp_pipeline(size: usize, threads: usize, iter_size1: i32, iter_size
                 Some(content)
             },
             threads as i32
-        ),
+        )),
         collect_ordered!()
     ];
 
-    for i in 0..size {
-        pipeline
-            .post(Tcontent {
-                size,
-                line: i as i64,
-                line_buffer: vec![0; size],
-                a_buffer: vec![0.0; size],
-                b_buffer: vec![0.0; size],
-                k_buffer: vec![0; size],
-            })
-            .unwrap();
+    let lines = (0..size).map(move |i| Tcontent {
+        size,
+        line: i as i64,
+        line_buffer: vec![0; size],
+        a_buffer: vec![0.0; size],
+        b_buffer: vec![0.0; size],
+        k_buffer: vec![0; size],
+    });
+    if options.affinity.is_some() {
+        let placement = pipeline.placement();
+        let cores: Vec<String> = placement.replicas.iter()
+            .map(|replica| replica.core.map_or("-".to_string(), |core| core.to_string()))
+            .collect();
+        println!("Affinity: {} (cores: {})", placement.affinity, cores.join(" "));
+    }
+    let cancel = pipeline.cancel_handle();
+    if let Some(limit) = options.time_limit {
+        cancel.cancel_after(limit);
+    }
+    let collection = pipeline.run_source(lines).unwrap();
+    if cancel.is_cancelled() {
+        println!("Time limit reached after {} of {} lines", collection.len(), size);
     }
-    let collection = pipeline.collect();
 
     let system_duration = start.elapsed().expect("Failed to get render time?");
     let in_sec = system_duration.as_secs() as f64 + system_duration.subsec_nanos() as f64 * 1e-9;